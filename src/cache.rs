@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::processor::ProcessedImage;
+
+/// How often the background task sweeps expired entries.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    value: ProcessedImage,
+    inserted: Instant,
+    /// Logical clock of the last hit, for LRU ordering.
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<[u8; 32], Entry>,
+    total_bytes: usize,
+    clock: u64,
+}
+
+/// Finished conversions keyed by `processor::content_key`, bounded by total
+/// output bytes rather than entry count since outputs range from a few hundred
+/// bytes to several megabytes.
+pub struct ResponseCache {
+    max_bytes: usize,
+    ttl: Option<Duration>,
+    inner: Mutex<Inner>,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize, ttl: Option<Duration>) -> Self {
+        Self {
+            max_bytes,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> Option<ProcessedImage> {
+        let mut inner = self.inner.lock().unwrap();
+        if self.is_expired(inner.entries.get(key)?) {
+            let entry = inner.entries.remove(key)?;
+            inner.total_bytes -= entry.value.bytes.len();
+            return None;
+        }
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(key)?;
+        entry.last_used = clock;
+        Some(entry.value.clone())
+    }
+
+    /// Stores a result and evicts least-recently-used entries until the cache
+    /// is back within its byte budget. Outputs larger than the whole budget are
+    /// not cached.
+    pub fn insert(&self, key: [u8; 32], value: ProcessedImage) {
+        let size = value.bytes.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let entry = Entry {
+            value,
+            inserted: Instant::now(),
+            last_used: inner.clock,
+        };
+        if let Some(old) = inner.entries.insert(key, entry) {
+            inner.total_bytes -= old.value.bytes.len();
+        }
+        inner.total_bytes += size;
+        self.evict_locked(&mut inner);
+    }
+
+    /// Drops expired entries, then trims to the byte budget. Runs on every
+    /// insert and periodically from the background sweep.
+    pub fn evict(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.evict_locked(&mut inner);
+    }
+
+    /// Bytes of output currently held.
+    pub fn total_bytes(&self) -> usize {
+        self.inner.lock().unwrap().total_bytes
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        self.ttl.is_some_and(|ttl| entry.inserted.elapsed() >= ttl)
+    }
+
+    fn evict_locked(&self, inner: &mut Inner) {
+        if self.ttl.is_some() {
+            let before = inner.entries.len();
+            let mut freed = 0;
+            inner.entries.retain(|_, entry| {
+                let keep = !self.is_expired(entry);
+                if !keep {
+                    freed += entry.value.bytes.len();
+                }
+                keep
+            });
+            inner.total_bytes -= freed;
+            if inner.entries.len() < before {
+                tracing::debug!(
+                    expired = before - inner.entries.len(),
+                    "Cache entries expired"
+                );
+            }
+        }
+
+        // Linear scan for the oldest entry; the cache holds at most a few
+        // thousand outputs, so this is cheap next to an encode.
+        while inner.total_bytes > self.max_bytes {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some(entry) = inner.entries.remove(&oldest) {
+                inner.total_bytes -= entry.value.bytes.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::OutputFormat;
+    use bytes::Bytes;
+
+    fn output(len: usize) -> ProcessedImage {
+        ProcessedImage {
+            bytes: Bytes::from(vec![0u8; len]),
+            format: OutputFormat::WebP,
+            width: 1,
+            height: 1,
+            render_size: None,
+            warnings: Vec::new(),
+            quality_score: None,
+            encode_duration: Duration::ZERO,
+            avif_speed: None,
+            searched_quality: None,
+        }
+    }
+
+    #[test]
+    fn test_insert_past_budget_evicts_oldest() {
+        let cache = ResponseCache::new(250, None);
+        cache.insert([1; 32], output(100));
+        cache.insert([2; 32], output(100));
+        assert_eq!(cache.total_bytes(), 200);
+
+        cache.insert([3; 32], output(100));
+        assert_eq!(cache.total_bytes(), 200);
+        assert!(cache.get(&[1; 32]).is_none());
+        assert!(cache.get(&[2; 32]).is_some());
+        assert!(cache.get(&[3; 32]).is_some());
+    }
+
+    #[test]
+    fn test_get_refreshes_recency() {
+        let cache = ResponseCache::new(250, None);
+        cache.insert([1; 32], output(100));
+        cache.insert([2; 32], output(100));
+        assert!(cache.get(&[1; 32]).is_some());
+
+        cache.insert([3; 32], output(100));
+        assert!(cache.get(&[1; 32]).is_some());
+        assert!(cache.get(&[2; 32]).is_none());
+    }
+
+    #[test]
+    fn test_oversized_output_not_cached() {
+        let cache = ResponseCache::new(250, None);
+        cache.insert([1; 32], output(100));
+        cache.insert([2; 32], output(300));
+        assert!(cache.get(&[2; 32]).is_none());
+        assert!(cache.get(&[1; 32]).is_some());
+    }
+
+    #[test]
+    fn test_replacing_entry_keeps_accounting() {
+        let cache = ResponseCache::new(1000, None);
+        cache.insert([1; 32], output(100));
+        cache.insert([1; 32], output(40));
+        assert_eq!(cache.total_bytes(), 40);
+    }
+
+    #[test]
+    fn test_sweep_drops_expired_entries() {
+        let cache = ResponseCache::new(1000, Some(Duration::ZERO));
+        cache.insert([1; 32], output(100));
+        cache.evict();
+        assert_eq!(cache.total_bytes(), 0);
+        assert!(cache.get(&[1; 32]).is_none());
+    }
+}
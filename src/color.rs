@@ -0,0 +1,95 @@
+/// Named colors accepted wherever a color field is parsed.
+const NAMED: &[(&str, [u8; 3])] = &[
+    ("black", [0, 0, 0]),
+    ("white", [255, 255, 255]),
+    ("red", [255, 0, 0]),
+    ("green", [0, 128, 0]),
+    ("blue", [0, 0, 255]),
+    ("yellow", [255, 255, 0]),
+    ("gray", [128, 128, 128]),
+    ("grey", [128, 128, 128]),
+    ("silver", [192, 192, 192]),
+    ("navy", [0, 0, 128]),
+    ("orange", [255, 165, 0]),
+];
+
+/// Parses `#RRGGBB`, `#RGB` or a CSS-style color name (case-insensitive).
+pub fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix('#') {
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        return match hex.len() {
+            6 => Some([
+                u8::from_str_radix(&hex[0..2], 16).ok()?,
+                u8::from_str_radix(&hex[2..4], 16).ok()?,
+                u8::from_str_radix(&hex[4..6], 16).ok()?,
+            ]),
+            3 => {
+                let mut rgb = [0u8; 3];
+                for (i, c) in hex.chars().enumerate() {
+                    let v = c.to_digit(16)? as u8;
+                    rgb[i] = v * 17;
+                }
+                Some(rgb)
+            }
+            _ => None,
+        };
+    }
+
+    let lower = value.to_lowercase();
+    NAMED
+        .iter()
+        .find(|(name, _)| *name == lower)
+        .map(|(_, rgb)| *rgb)
+}
+
+/// Like `parse_color`, plus `#RRGGBBAA` and `transparent`. Colors without
+/// an alpha part are opaque.
+pub fn parse_color_alpha(value: &str) -> Option<[u8; 4]> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("transparent") {
+        return Some([0, 0, 0, 0]);
+    }
+    if let Some(hex) = value.strip_prefix('#').filter(|hex| hex.len() == 8) {
+        let [r, g, b] = parse_color(&format!("#{}", &hex[..6]))?;
+        let a = u8::from_str_radix(&hex[6..], 16).ok()?;
+        return Some([r, g, b, a]);
+    }
+    parse_color(value).map(|[r, g, b]| [r, g, b, 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_color("#ff8800"), Some([255, 136, 0]));
+        assert_eq!(parse_color("#FFF"), Some([255, 255, 255]));
+    }
+
+    #[test]
+    fn test_parse_named() {
+        assert_eq!(parse_color("White"), Some([255, 255, 255]));
+        assert_eq!(parse_color("grey"), Some([128, 128, 128]));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert_eq!(parse_color("#12345"), None);
+        assert_eq!(parse_color("#gggggg"), None);
+        assert_eq!(parse_color("ff0000"), None);
+        assert_eq!(parse_color("chartreuse-ish"), None);
+    }
+
+    #[test]
+    fn test_parse_with_alpha() {
+        assert_eq!(parse_color_alpha("#ff880080"), Some([255, 136, 0, 128]));
+        assert_eq!(parse_color_alpha("Transparent"), Some([0, 0, 0, 0]));
+        assert_eq!(parse_color_alpha("white"), Some([255, 255, 255, 255]));
+        assert_eq!(parse_color_alpha("#ff8800+1"), None);
+        assert_eq!(parse_color_alpha("#ff8800g0"), None);
+    }
+}
@@ -0,0 +1,409 @@
+use std::env;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::handlers::form::FieldLimits;
+use crate::middleware::client_ip::{parse_cidrs, Cidr};
+use crate::processor::{
+    input_mime, DefaultQuality, FormatCaps, Limits, OutputFormat, PngCompression, SizeCap,
+    DEFAULT_WEBP_LOSSLESS_EFFORT, MAX_DIMENSION_CEILING, MAX_PIXELS_CEILING,
+};
+
+/// Upper bound for either work pool; more would only contend for CPU.
+const MAX_POOL_SIZE: usize = 256;
+
+/// Runtime settings read from the environment once at router construction.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// zlib effort for PNG output. PNG is lossless, so `quality` is ignored
+    /// and this is the only knob that affects its size.
+    pub png_compression: PngCompression,
+    /// libwebp effort, 0–100, for lossless and near-lossless WebP output,
+    /// which likewise ignores `quality`.
+    pub webp_lossless_effort: u8,
+    /// Largest accepted request body, in megabytes.
+    pub max_upload_mb: u64,
+    /// Quality per output format for requests that don't send `quality`.
+    pub default_quality: DefaultQuality,
+    /// Output formats clients may request; others are rejected with 400.
+    pub allowed_formats: Vec<OutputFormat>,
+    /// Output format for `/convert` requests that don't choose one.
+    pub default_format: OutputFormat,
+    /// Input MIME types accepted, whatever the encoders could handle; others
+    /// are rejected with 415. `None` accepts every supported input.
+    pub allowed_inputs: Option<Vec<&'static str>>,
+    /// Multipart field names the image may arrive under besides `file`.
+    pub file_field_names: Vec<String>,
+    /// Budget for decoding alone, reported as a 408 distinct from encode timeouts.
+    pub decode_timeout: Duration,
+    /// Share one encode between concurrent requests with identical input and
+    /// options instead of running it once per request.
+    pub coalesce_identical: bool,
+    /// Reject `/convert` fields it doesn't know with 400 instead of ignoring
+    /// them, so a typo such as `quailty` doesn't go unnoticed.
+    pub strict_fields: bool,
+    /// Caps on requested and decoded image size.
+    pub limits: Limits,
+    /// Budget for a whole conversion (decode, transform, encode).
+    pub encoding_timeout: Duration,
+    /// Longest conversion budget a request may ask for with `timeout_ms`.
+    /// Never below `encoding_timeout`.
+    pub max_encoding_timeout: Duration,
+    /// Budget for a whole `/convert` or `/srcset` request, including reading
+    /// the upload.
+    pub request_timeout: Duration,
+    /// Caps on the number of form fields and the time spent waiting for them.
+    pub field_limits: FieldLimits,
+    /// Total output bytes the response cache may hold. `0` disables caching.
+    pub cache_max_bytes: usize,
+    /// Age after which cached outputs are dropped. `None` keeps them until
+    /// evicted by the byte budget.
+    pub cache_ttl: Option<Duration>,
+    /// Background jobs (`POST /convert?async=true`) held at once, pending or
+    /// finished. `0` disables async mode.
+    pub max_jobs: usize,
+    /// Total body bytes the finished jobs' results may hold.
+    pub max_job_bytes: usize,
+    /// How long a finished job's result stays fetchable.
+    pub job_ttl: Duration,
+    /// Directory for the on-disk output cache. `None` disables it.
+    pub cache_dir: Option<PathBuf>,
+    /// Total bytes the on-disk cache may hold.
+    pub cache_dir_max_bytes: u64,
+    /// Concurrent conversions allowed on the light and heavy pools.
+    pub light_pool_size: usize,
+    pub heavy_pool_size: usize,
+    /// Uploads at least this large go to the heavy pool.
+    pub heavy_input_bytes: usize,
+    /// Requests asking for a side at least this long go to the heavy pool.
+    pub heavy_dimension: u32,
+    /// Distinct-color threshold applied when a request sets `require_photo`.
+    pub photo_min_colors: u32,
+    /// How long shutdown waits for in-flight conversions before exiting anyway.
+    pub shutdown_timeout: Duration,
+    /// Skip bearer-token checks entirely. For deployments that authenticate
+    /// callers in front of the service.
+    pub auth_disabled: bool,
+    /// Peers whose `X-Forwarded-For`/`X-Real-IP` headers are believed when
+    /// resolving the client address. Empty trusts no one.
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let encoding_timeout = env_secs("ENCODING_TIMEOUT_SECS", 30);
+        Self {
+            png_compression: env_or("PNG_COMPRESSION", PngCompression::Default),
+            webp_lossless_effort: match env_or("WEBP_LOSSLESS_EFFORT", DEFAULT_WEBP_LOSSLESS_EFFORT)
+            {
+                effort if effort <= 100 => effort,
+                effort => {
+                    tracing::warn!(
+                        value = effort,
+                        "WEBP_LOSSLESS_EFFORT above 100, using default"
+                    );
+                    DEFAULT_WEBP_LOSSLESS_EFFORT
+                }
+            },
+            max_upload_mb: env_or("MAX_UPLOAD_MB", 10),
+            default_quality: DefaultQuality {
+                webp: env_bounded(
+                    "DEFAULT_QUALITY_WEBP",
+                    DefaultQuality::default().webp,
+                    100.0,
+                ),
+                avif: env_bounded(
+                    "DEFAULT_QUALITY_AVIF",
+                    DefaultQuality::default().avif,
+                    100.0,
+                ),
+                jpeg: env_bounded(
+                    "DEFAULT_QUALITY_JPEG",
+                    DefaultQuality::default().jpeg,
+                    100.0,
+                ),
+            },
+            allowed_formats: env::var("ALLOWED_FORMATS")
+                .map(|raw| parse_formats(&raw))
+                .unwrap_or_else(|_| OutputFormat::ALL.to_vec()),
+            // main refuses to start on a malformed value before getting here
+            default_format: env_or("DEFAULT_FORMAT", OutputFormat::WebP),
+            allowed_inputs: env::var("ALLOWED_INPUT_FORMATS")
+                .ok()
+                .and_then(|raw| parse_input_formats(&raw)),
+            file_field_names: parse_field_names(
+                &env::var("FILE_FIELD_NAMES").unwrap_or_else(|_| DEFAULT_FILE_FIELDS.to_string()),
+            ),
+            decode_timeout: env_secs("DECODE_TIMEOUT_SECS", 10),
+            coalesce_identical: env_or("COALESCE_IDENTICAL", false),
+            strict_fields: env_or("STRICT_FIELDS", false),
+            limits: Limits {
+                min_dimension: env_bounded(
+                    "MIN_DIMENSION",
+                    Limits::default().min_dimension,
+                    MAX_DIMENSION_CEILING,
+                ),
+                max_dimension: env_bounded(
+                    "MAX_DIMENSION",
+                    Limits::default().max_dimension,
+                    MAX_DIMENSION_CEILING,
+                ),
+                max_long_edge: match env_or("MAX_DIMENSION_LONG_EDGE", 0) {
+                    0 => None,
+                    edge => Some(edge.min(MAX_DIMENSION_CEILING)),
+                },
+                max_pixels: env_bounded(
+                    "MAX_PIXELS",
+                    Limits::default().max_pixels,
+                    MAX_PIXELS_CEILING,
+                ),
+                max_animation_duration: match env_or("MAX_ANIMATION_DURATION_MS", 0) {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms)),
+                },
+                max_frames: env_or("MAX_ANIMATION_FRAMES", Limits::default().max_frames),
+                max_animation_pixels: env_or(
+                    "MAX_ANIMATION_PIXELS",
+                    Limits::default().max_animation_pixels,
+                ),
+                max_output_bytes: env_bounded(
+                    "MAX_OUTPUT_BYTES",
+                    Limits::default().max_output_bytes,
+                    usize::MAX,
+                ),
+                format_caps: FormatCaps {
+                    webp: env_size_cap(OutputFormat::WebP),
+                    avif: env_size_cap(OutputFormat::Avif),
+                    png: env_size_cap(OutputFormat::Png),
+                    jpeg: env_size_cap(OutputFormat::Jpeg),
+                },
+            },
+            encoding_timeout,
+            max_encoding_timeout: env_secs("MAX_ENCODING_TIMEOUT_SECS", encoding_timeout.as_secs())
+                .max(encoding_timeout),
+            request_timeout: env_secs("REQUEST_TIMEOUT_SECS", 60),
+            field_limits: FieldLimits {
+                max_fields: env_or("MAX_FORM_FIELDS", FieldLimits::default().max_fields),
+                parse_time: env_secs(
+                    "FORM_PARSE_TIMEOUT_SECS",
+                    FieldLimits::default().parse_time.as_secs(),
+                ),
+            },
+            cache_max_bytes: env_or("CACHE_MAX_BYTES", 0),
+            cache_ttl: match env_or("CACHE_TTL_SECS", 0) {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            max_jobs: env_or("MAX_JOBS", 100),
+            max_job_bytes: env_or("MAX_JOB_BYTES", 256 * 1024 * 1024),
+            job_ttl: env_secs("JOB_TTL_SECS", 300),
+            cache_dir: env::var_os("CACHE_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+            cache_dir_max_bytes: env_or("CACHE_DIR_MAX_BYTES", env_or("CACHE_MAX_BYTES", 0)),
+            light_pool_size: env_bounded("LIGHT_POOL_SIZE", cpus, MAX_POOL_SIZE),
+            heavy_pool_size: env_bounded("HEAVY_POOL_SIZE", (cpus / 2).max(1), MAX_POOL_SIZE),
+            heavy_input_bytes: env_or("HEAVY_INPUT_BYTES", 2 * 1024 * 1024),
+            heavy_dimension: env_or("HEAVY_DIMENSION", 2048),
+            photo_min_colors: env_or("PHOTO_MIN_COLORS", 256),
+            shutdown_timeout: env_secs("SHUTDOWN_TIMEOUT_SECS", 30),
+            auth_disabled: env_or("AUTH_DISABLED", false),
+            trusted_proxies: env::var("TRUSTED_PROXIES")
+                .map(|raw| parse_cidrs(&raw))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Conversion budget for a request that asked for `requested`, clamped
+    /// to `max_encoding_timeout`. `None` uses `encoding_timeout`.
+    pub fn encoding_timeout_for(&self, requested: Option<Duration>) -> Duration {
+        requested.map_or(self.encoding_timeout, |requested| {
+            requested.min(self.max_encoding_timeout)
+        })
+    }
+}
+
+/// Reads and parses `name`, falling back to `default` (with a warning) when the
+/// value is missing or malformed.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(raw) => raw.parse().unwrap_or_else(|_| {
+            tracing::warn!(var = name, value = %raw, "Invalid value, using default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Parses a comma-separated format list, skipping unknown names. A list with
+/// no usable entry allows every format rather than none.
+fn parse_formats(raw: &str) -> Vec<OutputFormat> {
+    let mut formats = Vec::new();
+    for name in raw.split(',').filter(|name| !name.trim().is_empty()) {
+        match name.parse::<OutputFormat>() {
+            Ok(format) if !formats.contains(&format) => formats.push(format),
+            Ok(_) => {}
+            Err(_) => tracing::warn!(value = %name.trim(), "Unknown format in ALLOWED_FORMATS"),
+        }
+    }
+    if formats.is_empty() {
+        tracing::warn!("ALLOWED_FORMATS lists no known format, allowing all");
+        return OutputFormat::ALL.to_vec();
+    }
+    formats
+}
+
+/// Parses a comma-separated input format list into MIME types, skipping
+/// unknown names. A list with no usable entry accepts every input rather
+/// than none.
+fn parse_input_formats(raw: &str) -> Option<Vec<&'static str>> {
+    let mut mimes = Vec::new();
+    for name in raw.split(',').filter(|name| !name.trim().is_empty()) {
+        match input_mime(name) {
+            Some(mime) if !mimes.contains(&mime) => mimes.push(mime),
+            Some(_) => {}
+            None => {
+                tracing::warn!(value = %name.trim(), "Unknown format in ALLOWED_INPUT_FORMATS")
+            }
+        }
+    }
+    if mimes.is_empty() {
+        tracing::warn!("ALLOWED_INPUT_FORMATS lists no known format, accepting all");
+        return None;
+    }
+    Some(mimes)
+}
+
+/// Field names `FILE_FIELD_NAMES` defaults to, covering what common form
+/// libraries and upload widgets call the image part.
+const DEFAULT_FILE_FIELDS: &str = "file,image,upload";
+
+/// Parses a comma-separated list of field names. An empty list falls back to
+/// the defaults; `file` is accepted either way.
+fn parse_field_names(raw: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+        }
+    }
+    if names.is_empty() {
+        tracing::warn!("FILE_FIELD_NAMES lists no field name, using the defaults");
+        return parse_field_names(DEFAULT_FILE_FIELDS);
+    }
+    names
+}
+
+/// Reads a positive number of seconds; zero or malformed values use `default`.
+fn env_secs(name: &str, default: u64) -> Duration {
+    match env_or(name, default) {
+        0 => {
+            tracing::warn!(var = name, "Timeout must be positive, using default");
+            Duration::from_secs(default)
+        }
+        secs => Duration::from_secs(secs),
+    }
+}
+
+/// Reads `MAX_DIMENSION_<FORMAT>` and `MAX_PIXELS_<FORMAT>` for `format`.
+fn env_size_cap(format: OutputFormat) -> SizeCap {
+    let suffix = format.name().to_uppercase();
+    SizeCap {
+        max_dimension: env_cap(&format!("MAX_DIMENSION_{}", suffix), MAX_DIMENSION_CEILING),
+        max_pixels: env_cap(&format!("MAX_PIXELS_{}", suffix), MAX_PIXELS_CEILING),
+    }
+}
+
+/// Reads an optional cap in `1..=ceiling`; unset, malformed or out-of-range
+/// values leave it off.
+fn env_cap<T>(name: &str, ceiling: T) -> Option<T>
+where
+    T: FromStr + PartialOrd + Copy + From<u8>,
+{
+    let raw = env::var(name).ok()?;
+    match raw.parse::<T>() {
+        Ok(value) if value >= T::from(1) && value <= ceiling => Some(value),
+        _ => {
+            tracing::warn!(var = name, value = %raw, "Invalid cap, ignoring");
+            None
+        }
+    }
+}
+
+/// Reads a value in `1..=ceiling`; anything outside it uses `default`.
+fn env_bounded<T>(name: &str, default: T, ceiling: T) -> T
+where
+    T: FromStr + PartialOrd + Copy + Display + From<u8>,
+{
+    let value = env_or(name, default);
+    if value < T::from(1) || value > ceiling {
+        tracing::warn!(var = name, %value, %ceiling, "Value out of range, using default");
+        return default;
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_formats() {
+        assert_eq!(
+            parse_formats("webp, PNG,jpg"),
+            vec![OutputFormat::WebP, OutputFormat::Png, OutputFormat::Jpeg]
+        );
+        assert_eq!(parse_formats("webp,jxl,webp"), vec![OutputFormat::WebP]);
+        assert_eq!(parse_formats(""), OutputFormat::ALL.to_vec());
+        assert_eq!(parse_formats("jxl"), OutputFormat::ALL.to_vec());
+    }
+
+    #[test]
+    fn test_parse_input_formats() {
+        assert_eq!(
+            parse_input_formats("png, JPG,jpeg,heic"),
+            Some(vec!["image/png", "image/jpeg", "image/heic"])
+        );
+        assert_eq!(parse_input_formats(""), None);
+        assert_eq!(parse_input_formats("docx"), None);
+    }
+
+    #[test]
+    fn test_parse_field_names() {
+        assert_eq!(
+            parse_field_names(" photo,image ,photo"),
+            vec!["photo", "image"]
+        );
+        assert_eq!(parse_field_names(" , "), vec!["file", "image", "upload"]);
+    }
+
+    #[test]
+    fn test_encoding_timeout_for() {
+        let config = Config {
+            encoding_timeout: Duration::from_secs(30),
+            max_encoding_timeout: Duration::from_secs(120),
+            ..Config::from_env()
+        };
+        assert_eq!(config.encoding_timeout_for(None), Duration::from_secs(30));
+        // Below the cap, longer and shorter budgets are both honoured
+        assert_eq!(
+            config.encoding_timeout_for(Some(Duration::from_secs(90))),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            config.encoding_timeout_for(Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            config.encoding_timeout_for(Some(Duration::from_secs(600))),
+            Duration::from_secs(120)
+        );
+    }
+}
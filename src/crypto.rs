@@ -0,0 +1,107 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key};
+
+/// AES-256 key length in bytes (64 hex characters on the wire).
+pub const KEY_LEN: usize = 32;
+/// AES-GCM nonce length in bytes.
+pub const NONCE_LEN: usize = 12;
+
+pub struct Encrypted {
+    pub ciphertext: Vec<u8>,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+/// Parses a hex-encoded AES-256 key supplied by the client.
+pub fn parse_key(value: &str) -> anyhow::Result<[u8; KEY_LEN]> {
+    let bytes = hex::decode(value.trim())
+        .map_err(|_| anyhow::anyhow!("encryption_key must be hex-encoded"))?;
+    bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("encryption_key must be {} bytes", KEY_LEN))
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a fresh random nonce.
+/// The authentication tag is appended to the ciphertext.
+pub fn encrypt(key: &[u8; KEY_LEN], plaintext: &[u8]) -> anyhow::Result<Encrypted> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| anyhow::anyhow!("AES-GCM encryption failed"))?;
+
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    nonce_bytes.copy_from_slice(&nonce);
+    Ok(Encrypted {
+        ciphertext,
+        nonce: nonce_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{process_image, OutputFormat, ProcessOptions};
+    use aes_gcm::Nonce;
+    use image::{ImageBuffer, ImageReader, Rgba};
+    use std::io::Cursor;
+
+    fn webp_output() -> Vec<u8> {
+        let img: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(16, 16, Rgba([0, 128, 255, 255]));
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let options = ProcessOptions {
+            format: OutputFormat::WebP,
+            ..Default::default()
+        };
+        process_image(&png, options).unwrap().bytes.to_vec()
+    }
+
+    #[test]
+    fn test_parse_key_rejects_wrong_length() {
+        assert!(parse_key("00ff").is_err());
+        assert!(parse_key("zz").is_err());
+        assert!(parse_key(&"ab".repeat(KEY_LEN)).is_ok());
+    }
+
+    #[test]
+    fn test_encrypted_output_decrypts_to_image() {
+        let key = parse_key(&"1f".repeat(KEY_LEN)).unwrap();
+        let output = webp_output();
+
+        let encrypted = encrypt(&key, &output).unwrap();
+        assert_ne!(encrypted.ciphertext, output);
+
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+        let decrypted = cipher
+            .decrypt(
+                Nonce::from_slice(&encrypted.nonce),
+                encrypted.ciphertext.as_ref(),
+            )
+            .unwrap();
+        assert_eq!(decrypted, output);
+
+        let decoded = ImageReader::new(Cursor::new(decrypted))
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!(decoded.width(), 16);
+    }
+
+    #[test]
+    fn test_wrong_key_fails_to_decrypt() {
+        let key = parse_key(&"1f".repeat(KEY_LEN)).unwrap();
+        let encrypted = encrypt(&key, &webp_output()).unwrap();
+
+        let wrong = [0u8; KEY_LEN];
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&wrong));
+        assert!(cipher
+            .decrypt(
+                Nonce::from_slice(&encrypted.nonce),
+                encrypted.ciphertext.as_ref()
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::processor::{OutputFormat, ProcessedImage, Warning};
+
+struct Entry {
+    size: u64,
+    written: SystemTime,
+    /// Logical clock of the last hit, for LRU ordering.
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<[u8; 32], Entry>,
+    total_bytes: u64,
+    clock: u64,
+}
+
+/// Everything in a `ProcessedImage` except the bytes, stored ahead of them.
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    format: OutputFormat,
+    width: u32,
+    height: u32,
+    render_size: Option<(u32, u32)>,
+    warnings: Vec<Warning>,
+    quality_score: Option<f64>,
+    encode_ms: u64,
+    avif_speed: Option<u8>,
+    searched_quality: Option<u8>,
+}
+
+/// Finished conversions persisted under `CACHE_DIR`, one file per
+/// `processor::content_key`, so they survive restarts and can outgrow memory.
+/// The index of sizes and recency lives in memory and is rebuilt from the
+/// directory at startup, oldest files first. Files are written to a temporary
+/// name and renamed into place, so concurrent writers of one key never
+/// expose a partial file.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    ttl: Option<Duration>,
+    index: Mutex<Index>,
+}
+
+impl DiskCache {
+    /// Creates `dir` if needed and indexes the entries already in it. Leftover
+    /// temporary files from an interrupted write are removed.
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        max_bytes: u64,
+        ttl: Option<Duration>,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut found = Vec::new();
+        for item in std::fs::read_dir(&dir)? {
+            let item = item?;
+            let name = item.file_name();
+            let Some(key) = name.to_str().and_then(parse_key) else {
+                if name.to_str().is_some_and(|n| n.ends_with(".tmp")) {
+                    let _ = std::fs::remove_file(item.path());
+                }
+                continue;
+            };
+            let meta = item.metadata()?;
+            let written = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.push((key, meta.len(), written));
+        }
+        found.sort_by_key(|&(_, _, written)| written);
+
+        let mut index = Index::default();
+        for (key, size, written) in found {
+            index.clock += 1;
+            index.total_bytes += size;
+            let last_used = index.clock;
+            index.entries.insert(
+                key,
+                Entry {
+                    size,
+                    written,
+                    last_used,
+                },
+            );
+        }
+        tracing::info!(
+            dir = %dir.display(),
+            entries = index.entries.len(),
+            bytes = index.total_bytes,
+            "Opened disk cache"
+        );
+
+        let cache = Self {
+            dir,
+            max_bytes,
+            ttl,
+            index: Mutex::new(index),
+        };
+        let victims = cache.evict_locked(&mut cache.index.lock().unwrap());
+        for path in victims {
+            let _ = std::fs::remove_file(path);
+        }
+        Ok(cache)
+    }
+
+    pub async fn get(&self, key: &[u8; 32]) -> Option<ProcessedImage> {
+        let written = self.index.lock().unwrap().entries.get(key)?.written;
+        if self.ttl.is_some_and(|ttl| is_older(written, ttl)) {
+            self.remove(key).await;
+            return None;
+        }
+
+        let path = self.path(key);
+        let parsed = tokio::fs::read(&path)
+            .await
+            .ok()
+            .and_then(|blob| decode(&blob));
+        let Some(value) = parsed else {
+            // Deleted or corrupted behind our back; forget it
+            tracing::warn!(path = %path.display(), "Dropping unreadable disk cache entry");
+            self.remove(key).await;
+            return None;
+        };
+
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let clock = index.clock;
+        if let Some(entry) = index.entries.get_mut(key) {
+            entry.last_used = clock;
+        }
+        Some(value)
+    }
+
+    /// Writes a result and evicts least-recently-used files until the
+    /// directory is back within its byte budget. Outputs larger than the
+    /// whole budget are not cached. Failures are logged and otherwise
+    /// ignored: the cache is an optimization.
+    pub async fn insert(&self, key: [u8; 32], value: &ProcessedImage) {
+        let blob = encode(value);
+        let size = blob.len() as u64;
+        if size > self.max_bytes {
+            return;
+        }
+
+        let path = self.path(&key);
+        let tmp = self
+            .dir
+            .join(format!("{}.{}.tmp", hex(&key), uuid::Uuid::new_v4()));
+        let written = async {
+            tokio::fs::write(&tmp, &blob).await?;
+            tokio::fs::rename(&tmp, &path).await
+        };
+        if let Err(e) = written.await {
+            tracing::warn!(path = %path.display(), error = %e, "Disk cache write failed");
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return;
+        }
+
+        let victims = {
+            let mut index = self.index.lock().unwrap();
+            index.clock += 1;
+            let entry = Entry {
+                size,
+                written: SystemTime::now(),
+                last_used: index.clock,
+            };
+            if let Some(old) = index.entries.insert(key, entry) {
+                index.total_bytes -= old.size;
+            }
+            index.total_bytes += size;
+            self.evict_locked(&mut index)
+        };
+        for victim in victims {
+            let _ = tokio::fs::remove_file(victim).await;
+        }
+    }
+
+    /// Writes and removes a probe file, failing if the directory has become
+    /// unwritable (full disk, lost mount, changed permissions). Uses the
+    /// temporary suffix so a probe left behind by a crash is swept on open.
+    pub async fn check_writable(&self) -> io::Result<()> {
+        let probe = self.dir.join(format!("ready.{}.tmp", uuid::Uuid::new_v4()));
+        tokio::fs::write(&probe, b"ok").await?;
+        tokio::fs::remove_file(&probe).await
+    }
+
+    /// Bytes of cache files currently indexed.
+    pub fn total_bytes(&self) -> u64 {
+        self.index.lock().unwrap().total_bytes
+    }
+
+    async fn remove(&self, key: &[u8; 32]) {
+        let removed = {
+            let mut index = self.index.lock().unwrap();
+            let removed = index.entries.remove(key);
+            if let Some(entry) = &removed {
+                index.total_bytes -= entry.size;
+            }
+            removed
+        };
+        if removed.is_some() {
+            let _ = tokio::fs::remove_file(self.path(key)).await;
+        }
+    }
+
+    /// Drops expired entries, then the least recently used until within
+    /// budget. Returns the files to delete once the lock is released.
+    fn evict_locked(&self, index: &mut Index) -> Vec<PathBuf> {
+        let mut victims = Vec::new();
+        if let Some(ttl) = self.ttl {
+            let expired: Vec<[u8; 32]> = index
+                .entries
+                .iter()
+                .filter(|(_, entry)| is_older(entry.written, ttl))
+                .map(|(key, _)| *key)
+                .collect();
+            for key in expired {
+                if let Some(entry) = index.entries.remove(&key) {
+                    index.total_bytes -= entry.size;
+                    victims.push(self.path(&key));
+                }
+            }
+        }
+
+        while index.total_bytes > self.max_bytes {
+            let Some(oldest) = index
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some(entry) = index.entries.remove(&oldest) {
+                index.total_bytes -= entry.size;
+                victims.push(self.path(&oldest));
+            }
+        }
+        victims
+    }
+
+    fn path(&self, key: &[u8; 32]) -> PathBuf {
+        self.dir.join(hex(key))
+    }
+}
+
+fn is_older(written: SystemTime, ttl: Duration) -> bool {
+    written.elapsed().is_ok_and(|age| age >= ttl)
+}
+
+fn hex(key: &[u8; 32]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Inverse of `hex`; `None` for anything that isn't a cache file name.
+fn parse_key(name: &str) -> Option<[u8; 32]> {
+    if name.len() != 64 || !name.is_ascii() {
+        return None;
+    }
+    let mut key = [0u8; 32];
+    for (byte, pair) in key.iter_mut().zip(name.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(key)
+}
+
+/// File layout: metadata length (u32 LE), metadata JSON, encoded image.
+fn encode(value: &ProcessedImage) -> Vec<u8> {
+    let meta = Meta {
+        format: value.format,
+        width: value.width,
+        height: value.height,
+        render_size: value.render_size,
+        warnings: value.warnings.clone(),
+        quality_score: value.quality_score,
+        encode_ms: value.encode_duration.as_millis() as u64,
+        avif_speed: value.avif_speed,
+        searched_quality: value.searched_quality,
+    };
+    let meta = serde_json::to_vec(&meta).expect("cache metadata always serializes");
+    let mut blob = Vec::with_capacity(4 + meta.len() + value.bytes.len());
+    blob.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    blob.extend_from_slice(&meta);
+    blob.extend_from_slice(&value.bytes);
+    blob
+}
+
+fn decode(blob: &[u8]) -> Option<ProcessedImage> {
+    let len = u32::from_le_bytes(blob.get(..4)?.try_into().ok()?) as usize;
+    let meta: Meta = serde_json::from_slice(blob.get(4..4 + len)?).ok()?;
+    Some(ProcessedImage {
+        bytes: Bytes::copy_from_slice(&blob[4 + len..]),
+        format: meta.format,
+        width: meta.width,
+        height: meta.height,
+        render_size: meta.render_size,
+        warnings: meta.warnings,
+        quality_score: meta.quality_score,
+        encode_duration: Duration::from_millis(meta.encode_ms),
+        avif_speed: meta.avif_speed,
+        searched_quality: meta.searched_quality,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(len: usize) -> ProcessedImage {
+        ProcessedImage {
+            bytes: Bytes::from(vec![7u8; len]),
+            format: OutputFormat::Avif,
+            width: 3,
+            height: 2,
+            render_size: None,
+            warnings: vec![Warning::Upscaled],
+            quality_score: Some(0.5),
+            encode_duration: Duration::from_millis(12),
+            avif_speed: Some(6),
+            searched_quality: None,
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("imgopt-cache-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_check_writable() {
+        let dir = temp_dir();
+        let cache = DiskCache::open(&dir, 10_000, None).unwrap();
+        cache.check_writable().await.unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(cache.check_writable().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_survives_reopen() {
+        let dir = temp_dir();
+        let cache = DiskCache::open(&dir, 10_000, None).unwrap();
+        cache.insert([1; 32], &output(100)).await;
+
+        let hit = cache.get(&[1; 32]).await.unwrap();
+        assert_eq!(hit.bytes.len(), 100);
+        assert_eq!(hit.format, OutputFormat::Avif);
+        assert_eq!((hit.width, hit.height), (3, 2));
+        assert_eq!(hit.warnings, vec![Warning::Upscaled]);
+        assert!(cache.get(&[2; 32]).await.is_none());
+
+        let reopened = DiskCache::open(&dir, 10_000, None).unwrap();
+        assert_eq!(reopened.total_bytes(), cache.total_bytes());
+        assert!(reopened.get(&[1; 32]).await.is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_insert_past_budget_evicts_least_recently_used() {
+        let dir = temp_dir();
+        let size = encode(&output(100)).len() as u64;
+        let cache = DiskCache::open(&dir, size * 2, None).unwrap();
+        cache.insert([1; 32], &output(100)).await;
+        cache.insert([2; 32], &output(100)).await;
+        assert!(cache.get(&[1; 32]).await.is_some());
+
+        cache.insert([3; 32], &output(100)).await;
+        assert_eq!(cache.total_bytes(), size * 2);
+        assert!(cache.get(&[2; 32]).await.is_none());
+        assert!(!dir.join(hex(&[2; 32])).exists());
+        assert!(cache.get(&[1; 32]).await.is_some());
+        assert!(cache.get(&[3; 32]).await.is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,126 +1,1607 @@
 use axum::{
-    body::Bytes,
-    extract::Multipart,
+    body::{Body, Bytes},
+    extract::{Extension, Path, Request, State},
     http::{HeaderMap, StatusCode},
-    response::{IntoResponse, Response},
+    response::{IntoResponse, Json, Response},
 };
+use base64::Engine;
+use futures_util::stream;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::sync::Arc;
 use std::time::Duration;
-use uuid::Uuid;
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
+use utoipa::ToSchema;
 
-use crate::processor::{process_image, OutputFormat, ProcessOptions, MAX_DIMENSION};
+use crate::color::{parse_color, parse_color_alpha};
+#[cfg(feature = "encrypt")]
+use crate::crypto;
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::handlers::form::{field_name, parse_bool, FieldError, FormFields, FormRejection};
+use crate::handlers::jobs::spawn_job;
+use crate::middleware::auth::ClientLabel;
+use crate::middleware::body_limit::too_large_message;
+use crate::middleware::client_ip::ClientIp;
+use crate::pool::Lane;
+use crate::processor::{
+    content_key, decode_once, process_image, sniff_mime, AvifTiling, BitDepth, ChromaSubsampling,
+    CropFocus, Fit, Flip, Gravity, Mask, OutputFormat, Preset, ProcessError, ProcessOptions,
+    ProcessedImage, Sharpen, TextMetadata, Warning, Watermark, AVIF_SPEED_RANGE,
+    DEFAULT_TRIM_TOLERANCE, DPR_RANGE, GAMMA_RANGE, MAX_BLUR_SIGMA, MAX_METADATA_TEXT, MAX_SCALE,
+    MAX_SHARPEN_AMOUNT, TONE_RANGE,
+};
+use crate::request_id::{self, RequestId};
+use crate::state::AppState;
+
+/// Result of a timed blocking conversion, shared between coalesced requests.
+pub type ConvertOutcome = Arc<Result<Result<anyhow::Result<ProcessedImage>, JoinError>, Elapsed>>;
+
+/// Encoded size of one format in a multi-format response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FormatSize {
+    pub format: &'static str,
+    pub bytes: usize,
+    pub smallest: bool,
+}
+
+/// JSON part sent alongside multi-format results so clients can pick the
+/// smallest encode without measuring each one.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SizeSummary {
+    /// Ascending by size; ties keep request order.
+    pub formats: Vec<FormatSize>,
+    pub smallest: Option<&'static str>,
+}
+
+/// One format in a `formats` response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FormatVariant {
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+    /// Encoded size in bytes.
+    pub bytes: usize,
+    pub base64: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<&'static str>,
+}
+
+/// `formats` response: every requested encode in request order, plus the
+/// size summary.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct VariantsResult {
+    pub variants: Vec<FormatVariant>,
+    #[serde(flatten)]
+    pub summary: SizeSummary,
+}
+
+/// `dry_run` response: what the conversion would have returned, minus the
+/// image itself.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DryRunResult {
+    pub output_bytes: usize,
+    pub width: u32,
+    pub height: u32,
+    pub format: &'static str,
+}
 
-// SEC-003: maximum time allowed for a single encoding operation
-const ENCODING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Builds the size summary from `(format, encoded length)` pairs.
+pub fn size_summary(results: &[(OutputFormat, usize)]) -> SizeSummary {
+    let mut sorted = results.to_vec();
+    sorted.sort_by_key(|&(_, len)| len);
+    let smallest = sorted.first().map(|(format, _)| format.name());
+    let formats = sorted
+        .iter()
+        .enumerate()
+        .map(|(i, &(format, bytes))| FormatSize {
+            format: format.name(),
+            bytes,
+            smallest: i == 0,
+        })
+        .collect();
+    SizeSummary { formats, smallest }
+}
 
-pub async fn convert_image(mut multipart: Multipart) -> Response {
-    let request_id = Uuid::new_v4();
+/// Whether a part's declared `Content-Type` agrees with the sniffed MIME
+/// type. Generic types make no claim and always match; parameters, case and
+/// common aliases are ignored.
+fn declared_type_matches(declared: &str, actual: &str) -> bool {
+    let declared = declared
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let canonical = match declared.as_str() {
+        "" | "application/octet-stream" | "binary/octet-stream" => return true,
+        "image/jpg" | "image/pjpeg" => "image/jpeg",
+        "image/x-png" => "image/png",
+        "image/x-ms-bmp" => "image/bmp",
+        "image/heif" => "image/heic",
+        other => other,
+    };
+    canonical == actual
+}
+
+/// Output format named by the extension of a `/convert/{name}.{ext}` path,
+/// e.g. `photo.avif`.
+pub fn format_from_path(name: &str) -> Result<OutputFormat, String> {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) if !ext.is_empty() => ext,
+        _ => {
+            return Err(format!(
+                "path {:?} has no extension; use e.g. /convert/photo.webp",
+                name
+            ))
+        }
+    };
+    ext.parse().map_err(|_| {
+        format!(
+            "unknown output extension {:?}: must be webp, avif, png, jpeg or jpg",
+            ext
+        )
+    })
+}
+
+/// Name for the converted file: the upload's base name with its extension
+/// swapped for the output format's. Path components, control characters
+/// and quotes are dropped so the result is safe inside a quoted header value.
+/// `None` when nothing usable is left.
+pub fn output_filename(original: &str, format: OutputFormat) -> Option<String> {
+    let base = original.rsplit(['/', '\\']).next().unwrap_or(original);
+    let clean: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .collect();
+    let stem = match clean.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => clean.as_str(),
+    }
+    .trim();
+    if stem.is_empty() || stem.chars().all(|c| c == '.') {
+        return None;
+    }
+    Some(format!("{}.{}", stem, format.name()))
+}
+
+/// `Content-Disposition` value. Non-ASCII names get an ASCII `filename`
+/// fallback plus the RFC 5987 `filename*` form.
+fn content_disposition(download: bool, filename: Option<&str>) -> String {
+    let kind = if download { "attachment" } else { "inline" };
+    let Some(name) = filename else {
+        return kind.to_string();
+    };
+    if name.is_ascii() {
+        return format!("{}; filename=\"{}\"", kind, name);
+    }
+    let fallback: String = name
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let encoded: String = name
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        kind, fallback, encoded
+    )
+}
+
+#[utoipa::path(
+    post,
+    path = "/convert",
+    request_body(content = crate::handlers::openapi::ConvertForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "The converted image, or JSON for `dry_run` and `formats`", content(
+            (Vec<u8> = "image/webp"),
+            (Vec<u8> = "image/avif"),
+            (Vec<u8> = "image/png"),
+            (Vec<u8> = "image/jpeg"),
+            (DryRunResult = "application/json"),
+        )),
+        (status = 202, description = "Queued as a background job (`async=true`); poll `Location`", body = crate::handlers::jobs::JobPending),
+        (status = 400, description = "Invalid field or form", body = crate::handlers::error::ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 408, description = "Upload, decode or encode timed out", body = crate::handlers::error::ErrorBody),
+        (status = 413, description = "Upload exceeds MAX_UPLOAD_MB", body = crate::handlers::error::ErrorBody),
+        (status = 415, description = "Input format not accepted", body = crate::handlers::error::ErrorBody),
+        (status = 422, description = "Input can't be converted", body = crate::handlers::error::ErrorBody),
+        (status = 503, description = "Too many background jobs in progress", body = crate::handlers::error::ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn convert_image(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientLabel>>,
+    request: Request,
+) -> Response {
+    convert(state, client, None, request).await
+}
+
+/// `POST /convert/{name}.{ext}`: the same conversion, with the output format
+/// taken from the path's extension. It wins over a `format` field, which
+/// lets CDNs and static-asset tooling address outputs by file name.
+pub async fn convert_image_as(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientLabel>>,
+    Path(name): Path<String>,
+    request: Request,
+) -> Response {
+    convert(state, client, Some(name), request).await
+}
+
+async fn convert(
+    state: AppState,
+    client: Option<Extension<ClientLabel>>,
+    path_name: Option<String>,
+    request: Request,
+) -> Response {
+    let request_id = RequestId::from_request(&request);
+    // Logged next to the token label so conversions can be traced to a caller
+    let client_ip = request
+        .extensions()
+        .get::<ClientIp>()
+        .map_or_else(|| "unknown".to_string(), |ClientIp(ip)| ip.to_string());
+    let path_format = match path_name.as_deref().map(format_from_path) {
+        Some(Ok(format)) => Some(format),
+        Some(Err(message)) => {
+            return ApiError::new(ErrorCode::InvalidParameter, message, request_id).into_response()
+        }
+        None => None,
+    };
+    // `async` comes from the query so multipart uploads can set it without
+    // adding a field; raw uploads see it again as a field, which agrees
+    let async_query = request
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("async="));
+    let mut async_job = match async_query.map(parse_bool) {
+        None => false,
+        Some(Some(b)) => b,
+        Some(None) => {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                "async must be true, false, 1, 0, yes or no",
+                request_id,
+            )
+            .into_response()
+        }
+    };
+    let mut form = match FormFields::from_request(
+        request,
+        &state,
+        state.config.field_limits,
+        &state.config.file_field_names,
+    )
+    .await
+    {
+        Ok(fields) => fields,
+        Err(FormRejection::TooLarge) => {
+            tracing::warn!(%request_id, "Raw request body exceeds upload limit");
+            return ApiError::new(
+                ErrorCode::PayloadTooLarge,
+                too_large_message(state.config.max_upload_mb),
+                request_id,
+            )
+            .into_response();
+        }
+        Err(FormRejection::Invalid(reason)) => {
+            tracing::warn!(%request_id, %reason, "Unreadable request body");
+            return ApiError::new(ErrorCode::InvalidMultipart, reason, request_id).into_response();
+        }
+    };
+    let client = client.map_or_else(|| "unknown".into(), |Extension(label)| label.0);
+    let max_dimension = state.config.limits.max_side();
 
     let mut file_bytes: Option<Bytes> = None;
+    let mut file_name: Option<String> = None;
+    let mut file_content_type: Option<String> = None;
+    let mut strict_content_type = false;
+    let mut download = false;
+    let mut dry_run = false;
+    let mut transcode = false;
     let mut quality = 80.0f32;
+    let mut quality_supplied = false;
     let mut width: Option<u32> = None;
     let mut height: Option<u32> = None;
-    let mut format = OutputFormat::WebP;
+    let mut fit = Fit::Fill;
+    let mut gravity: Option<CropFocus> = None;
+    let mut focus_x: Option<f32> = None;
+    let mut focus_y: Option<f32> = None;
+    let mut format = state.config.default_format;
+    let mut format_supplied = false;
+    let mut formats: Option<Vec<OutputFormat>> = None;
+    let mut auto_format = false;
+    let mut chroma_subsampling = None;
+    let mut progressive = false;
+    let mut dpr = 1.0f32;
+    let mut scale: Option<f32> = None;
+    let mut frame: Option<usize> = None;
+    // `animated=frame` (true) or `animated=reject` (false), when sent
+    let mut take_frame: Option<bool> = None;
+    let mut loop_count = 0u16;
+    let mut anim_background = [0u8; 4];
+    let mut force_8bit = false;
+    let mut bit_depth = BitDepth::Auto;
+    let mut background: Option<[u8; 3]> = None;
+    let mut avif_speed = None;
+    let mut preset: Option<Preset> = None;
+    let mut deadline = None;
+    let mut timeout_ms: Option<u64> = None;
+    let mut avif_tiling = AvifTiling::Auto;
+    let mut lossless = false;
+    let mut lossless_supplied = false;
+    let mut embed_thumbnail = false;
+    let mut text_metadata = TextMetadata::default();
+    let mut near_lossless = None;
+    let mut alpha_quality = None;
+    let mut dpi: Option<u16> = None;
+    let mut require_photo = false;
+    let mut score = false;
+    let mut min_ssim: Option<f64> = None;
+    let mut target_ratio: Option<f32> = None;
+    let mut rotate = 0u16;
+    let mut brightness = None;
+    let mut contrast = None;
+    let mut gamma = None;
+    let mut saturation = None;
+    let mut grayscale = false;
+    let mut tint = None;
+    let mut blur = None;
+    let mut sharpen = None;
+    let mut flip = Flip::None;
+    let mut mask_name: Option<String> = None;
+    let mut radius: Option<u32> = None;
+    let mut trim = false;
+    let mut trim_tolerance = DEFAULT_TRIM_TOLERANCE;
+    let mut watermark_bytes: Option<Bytes> = None;
+    let mut watermark_gravity = Gravity::default();
+    let mut watermark_opacity = 1.0f32;
+    #[cfg(feature = "encrypt")]
+    let mut encryption_key: Option<[u8; crypto::KEY_LEN]> = None;
 
     loop {
-        let field = match multipart.next_field().await {
+        let field = match form.next_field().await {
             Ok(Some(f)) => f,
             Ok(None) => break,
+            Err(FieldError::Multipart(e)) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                tracing::warn!(%request_id, "Multipart body exceeds upload limit");
+                return ApiError::new(
+                    ErrorCode::PayloadTooLarge,
+                    too_large_message(state.config.max_upload_mb),
+                    request_id,
+                )
+                .into_response();
+            }
             Err(e) => {
                 tracing::warn!(%request_id, error = %e, "Multipart parsing error");
-                return (StatusCode::BAD_REQUEST, "Invalid multipart request").into_response();
+                return e.api_error(request_id).into_response();
+            }
+        };
+
+        let name = field_name(field.name(), &state.config.file_field_names);
+
+        match name.as_str() {
+            "file" => {
+                file_name = field.file_name().map(str::to_string);
+                file_content_type = field.content_type().map(str::to_string);
+                match field.bytes().await {
+                    Ok(bytes) => file_bytes = Some(bytes),
+                    Err(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                        tracing::warn!(%request_id, "Uploaded file exceeds upload limit");
+                        return ApiError::new(
+                            ErrorCode::PayloadTooLarge,
+                            too_large_message(state.config.max_upload_mb),
+                            request_id,
+                        )
+                        .into_response();
+                    }
+                    Err(e) => {
+                        tracing::warn!(%request_id, error = %e, "Failed to read file field");
+                        return ApiError::new(
+                            ErrorCode::InvalidMultipart,
+                            "Failed to read uploaded file",
+                            request_id,
+                        )
+                        .into_response();
+                    }
+                }
+            }
+            "options" => {
+                if let Ok(val) = field.text().await {
+                    if let Err(message) = form.queue_options(&val) {
+                        return ApiError::new(ErrorCode::InvalidParameter, message, request_id)
+                            .into_response();
+                    }
+                }
+            }
+            "quality" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f32>() {
+                        Ok(q) if (1.0..=100.0).contains(&q) => {
+                            quality = q;
+                            quality_supplied = true;
+                        }
+                        Ok(_) => {
+                            return ApiError::new(
+                                ErrorCode::QualityOutOfRange,
+                                "quality must be between 1 and 100",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                        Err(_) => {
+                            return ApiError::new(
+                                ErrorCode::QualityOutOfRange,
+                                "quality must be a number",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "width" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<u32>() {
+                        Ok(w) if w > 0 && w <= max_dimension => width = Some(w),
+                        Ok(0) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "width must be greater than 0",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                        Ok(_) => {
+                            return ApiError::new(
+                                ErrorCode::TooLarge,
+                                format!("width must not exceed {}", max_dimension),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                        Err(_) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "width must be a positive integer",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "height" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<u32>() {
+                        Ok(h) if h > 0 && h <= max_dimension => height = Some(h),
+                        Ok(0) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "height must be greater than 0",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                        Ok(_) => {
+                            return ApiError::new(
+                                ErrorCode::TooLarge,
+                                format!("height must not exceed {}", max_dimension),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                        Err(_) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "height must be a positive integer",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "fit" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().to_lowercase().parse::<Fit>() {
+                        Ok(f) => fit = f,
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "gravity" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<CropFocus>() {
+                        Ok(g) => gravity = Some(g),
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "focus_x" | "focus_y" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<f32>() {
+                        Ok(f) if (0.0..=1.0).contains(&f) => {
+                            if name == "focus_x" {
+                                focus_x = Some(f);
+                            } else {
+                                focus_y = Some(f);
+                            }
+                        }
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                format!("{} must be a number between 0 and 1", name),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "chroma" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<ChromaSubsampling>() {
+                        Ok(c) => chroma_subsampling = Some(c),
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "preset" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<Preset>() {
+                        Ok(p) => preset = Some(p),
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "download" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => download = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "download must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "transcode" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => transcode = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "transcode must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "strict_content_type" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => strict_content_type = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "strict_content_type must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "async" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => async_job = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "async must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "dry_run" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => dry_run = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "dry_run must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "progressive" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => progressive = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "progressive must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "dpr" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f32>() {
+                        Ok(d) if DPR_RANGE.contains(&d) => dpr = d,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "dpr must be between 1 and 4",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "scale" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<f32>() {
+                        Ok(s) if s > 0.0 && s <= MAX_SCALE => scale = Some(s),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                format!("scale must be above 0 and at most {}", MAX_SCALE),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "format" => {
+                if let Ok(val) = field.text().await {
+                    format_supplied = true;
+                    match val.to_lowercase().as_str() {
+                        "webp" => format = OutputFormat::WebP,
+                        "avif" => format = OutputFormat::Avif,
+                        "png" => format = OutputFormat::Png,
+                        "jpeg" | "jpg" => format = OutputFormat::Jpeg,
+                        "auto" => auto_format = true,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "format must be 'webp', 'avif', 'png', 'jpeg' or 'auto'",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "formats" => {
+                if let Ok(val) = field.text().await {
+                    let mut list = Vec::new();
+                    for name in val.split(',') {
+                        match name.trim().parse::<OutputFormat>() {
+                            Ok(f) if !list.contains(&f) => list.push(f),
+                            Ok(_) => {}
+                            Err(_) => {
+                                return ApiError::new(
+                                    ErrorCode::InvalidParameter,
+                                    "formats must be a comma-separated list of 'webp', 'avif', 'png' or 'jpeg'",
+                                    request_id,
+                                )
+                                .into_response()
+                            }
+                        }
+                    }
+                    formats = Some(list);
+                }
+            }
+            "grayscale" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => grayscale = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "grayscale must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "trim" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => trim = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "trim must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "trim_tolerance" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<u8>() {
+                        Ok(t) => trim_tolerance = t,
+                        Err(_) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "trim_tolerance must be between 0 and 255",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "brightness" | "contrast" | "saturation" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<f32>() {
+                        Ok(v) if TONE_RANGE.contains(&v) => match name.as_str() {
+                            "brightness" => brightness = Some(v),
+                            "contrast" => contrast = Some(v),
+                            _ => saturation = Some(v),
+                        },
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                format!(
+                                    "{} must be between {} and {}",
+                                    name,
+                                    TONE_RANGE.start(),
+                                    TONE_RANGE.end()
+                                ),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "gamma" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<f32>() {
+                        Ok(g) if GAMMA_RANGE.contains(&g) => gamma = Some(g),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                format!(
+                                    "gamma must be between {} and {}",
+                                    GAMMA_RANGE.start(),
+                                    GAMMA_RANGE.end()
+                                ),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "tint" => {
+                if let Ok(val) = field.text().await {
+                    match parse_color(&val) {
+                        Some(color) => tint = Some(color),
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "tint must be #RRGGBB or a named color",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "blur" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f32>() {
+                        Ok(sigma) if sigma > 0.0 && sigma <= MAX_BLUR_SIGMA => blur = Some(sigma),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                format!(
+                                    "blur must be greater than 0 and at most {}",
+                                    MAX_BLUR_SIGMA
+                                ),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "sharpen" => {
+                if let Ok(val) = field.text().await {
+                    // "amount" or "amount,threshold"
+                    let (amount, threshold) = match val.split_once(',') {
+                        Some((a, t)) => (a.trim().parse::<f32>(), t.trim().parse::<u8>()),
+                        None => (val.trim().parse::<f32>(), Ok(0)),
+                    };
+                    match (amount, threshold) {
+                        (Ok(amount), Ok(threshold))
+                            if amount > 0.0 && amount <= MAX_SHARPEN_AMOUNT =>
+                        {
+                            sharpen = Some(Sharpen { amount, threshold })
+                        }
+                        _ => {
+                            return ApiError::new(ErrorCode::InvalidParameter, format!(
+                                    "sharpen must be an amount up to {}, optionally followed by ,threshold (0-255)",
+                                    MAX_SHARPEN_AMOUNT
+                                ), request_id).into_response()
+                        }
+                    }
+                }
+            }
+            "rotate" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<i32>() {
+                        Ok(deg) if deg % 90 == 0 => rotate = deg.rem_euclid(360) as u16,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "rotate must be a multiple of 90",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "watermark" => match field.bytes().await {
+                Ok(bytes) => watermark_bytes = Some(bytes),
+                Err(e) => {
+                    tracing::warn!(%request_id, error = %e, "Failed to read watermark field");
+                    return ApiError::new(
+                        ErrorCode::InvalidMultipart,
+                        "Failed to read uploaded watermark",
+                        request_id,
+                    )
+                    .into_response();
+                }
+            },
+            "watermark_gravity" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<Gravity>() {
+                        Ok(g) => watermark_gravity = g,
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "watermark_opacity" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f32>() {
+                        Ok(o) if (0.0..=1.0).contains(&o) => watermark_opacity = o,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "watermark_opacity must be between 0 and 1",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "mask" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().to_lowercase().as_str() {
+                        name @ ("circle" | "rounded") => mask_name = Some(name.to_string()),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "mask must be 'circle' or 'rounded'",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "radius" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<u32>() {
+                        Ok(r) if r > 0 => radius = Some(r),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "radius must be a positive integer",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "flip" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<Flip>() {
+                        Ok(f) => flip = f,
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "frame" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<usize>() {
+                        Ok(f) => frame = Some(f),
+                        Err(_) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "frame must be a non-negative integer",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "animated" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().to_lowercase().as_str() {
+                        "reject" => take_frame = Some(false),
+                        "frame" => take_frame = Some(true),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "animated must be reject or frame",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "loop_count" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<u16>() {
+                        Ok(n) => loop_count = n,
+                        Err(_) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "loop_count must be between 0 and 65535",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "anim_background" => {
+                if let Ok(val) = field.text().await {
+                    match parse_color_alpha(&val) {
+                        Some(color) => anim_background = color,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "anim_background must be #RRGGBB, #RRGGBBAA, a named color or transparent",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "force_8bit" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => force_8bit = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "force_8bit must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "bit_depth" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().to_lowercase().parse::<BitDepth>() {
+                        Ok(depth) => bit_depth = depth,
+                        Err(e) => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                e.to_string(),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "background" => {
+                if let Ok(val) = field.text().await {
+                    match parse_color(&val) {
+                        Some(color) => background = Some(color),
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "background must be #RRGGBB or a named color",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "lossless" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => {
+                            lossless = b;
+                            lossless_supplied = true;
+                        }
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "lossless must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
             }
-        };
-
-        let name = field.name().unwrap_or("").to_string();
-
-        match name.as_str() {
-            "file" => match field.bytes().await {
-                Ok(bytes) => file_bytes = Some(bytes),
-                Err(e) => {
-                    tracing::warn!(%request_id, error = %e, "Failed to read file field");
-                    return (StatusCode::BAD_REQUEST, "Failed to read uploaded file")
+            "copyright" | "author" | "description" => {
+                if let Ok(val) = field.text().await {
+                    let text = val.trim();
+                    if text.len() > MAX_METADATA_TEXT || text.contains('\0') {
+                        return ApiError::new(
+                            ErrorCode::InvalidParameter,
+                            format!(
+                                "{} must be at most {} bytes of text",
+                                name, MAX_METADATA_TEXT
+                            ),
+                            request_id,
+                        )
                         .into_response();
+                    }
+                    let slot = match name.as_str() {
+                        "copyright" => &mut text_metadata.copyright,
+                        "author" => &mut text_metadata.author,
+                        _ => &mut text_metadata.description,
+                    };
+                    *slot = (!text.is_empty()).then(|| text.to_string());
                 }
-            },
-            "quality" => {
+            }
+            "embed_thumbnail" => {
                 if let Ok(val) = field.text().await {
-                    match val.parse::<f32>() {
-                        Ok(q) if (1.0..=100.0).contains(&q) => quality = q,
-                        Ok(_) => {
-                            return (StatusCode::BAD_REQUEST, "quality must be between 1 and 100")
-                                .into_response()
+                    match parse_bool(&val) {
+                        Some(b) => embed_thumbnail = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "embed_thumbnail must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
                         }
-                        Err(_) => {
-                            return (StatusCode::BAD_REQUEST, "quality must be a number")
-                                .into_response()
+                    }
+                }
+            }
+            "near_lossless" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<u8>() {
+                        Ok(n) if n <= 100 => near_lossless = Some(n),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "near_lossless must be between 0 and 100",
+                                request_id,
+                            )
+                            .into_response()
                         }
                     }
                 }
             }
-            "width" => {
+            "alpha_quality" => {
                 if let Ok(val) = field.text().await {
-                    match val.parse::<u32>() {
-                        Ok(w) if w > 0 && w <= MAX_DIMENSION => width = Some(w),
-                        Ok(0) => {
-                            return (StatusCode::BAD_REQUEST, "width must be greater than 0")
-                                .into_response()
+                    match val.parse::<u8>() {
+                        Ok(q) if q <= 100 => alpha_quality = Some(q),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "alpha_quality must be between 0 and 100",
+                                request_id,
+                            )
+                            .into_response()
                         }
-                        Ok(_) => {
-                            return (
-                                StatusCode::BAD_REQUEST,
-                                format!("width must not exceed {}", MAX_DIMENSION),
+                    }
+                }
+            }
+            "dpi" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().parse::<u16>() {
+                        Ok(d) if d > 0 => dpi = Some(d),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "dpi must be between 1 and 65535",
+                                request_id,
                             )
-                                .into_response()
+                            .into_response()
                         }
-                        Err(_) => {
-                            return (StatusCode::BAD_REQUEST, "width must be a positive integer")
-                                .into_response()
+                    }
+                }
+            }
+            "require_photo" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => require_photo = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "require_photo must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
                         }
                     }
                 }
             }
-            "height" => {
+            "min_ssim" => {
                 if let Ok(val) = field.text().await {
-                    match val.parse::<u32>() {
-                        Ok(h) if h > 0 && h <= MAX_DIMENSION => height = Some(h),
-                        Ok(0) => {
-                            return (StatusCode::BAD_REQUEST, "height must be greater than 0")
-                                .into_response()
+                    match val.parse::<f64>() {
+                        Ok(s) if s > 0.0 && s <= 1.0 => min_ssim = Some(s),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "min_ssim must be a number above 0 and at most 1",
+                                request_id,
+                            )
+                            .into_response()
                         }
-                        Ok(_) => {
-                            return (
-                                StatusCode::BAD_REQUEST,
-                                format!("height must not exceed {}", MAX_DIMENSION),
+                    }
+                }
+            }
+            "target_ratio" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f32>() {
+                        Ok(r) if r > 0.0 && r <= 1.0 => target_ratio = Some(r),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "target_ratio must be a number above 0 and at most 1",
+                                request_id,
                             )
-                                .into_response()
+                            .into_response()
                         }
-                        Err(_) => {
-                            return (StatusCode::BAD_REQUEST, "height must be a positive integer")
-                                .into_response()
+                    }
+                }
+            }
+            "score" => {
+                if let Ok(val) = field.text().await {
+                    match parse_bool(&val) {
+                        Some(b) => score = b,
+                        None => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "score must be true, false, 1, 0, yes or no",
+                                request_id,
+                            )
+                            .into_response()
                         }
                     }
                 }
             }
-            "format" => {
+            "speed" => {
                 if let Ok(val) = field.text().await {
-                    match val.to_lowercase().as_str() {
-                        "webp" => format = OutputFormat::WebP,
-                        "avif" => format = OutputFormat::Avif,
+                    match val.parse::<u8>() {
+                        Ok(s) if AVIF_SPEED_RANGE.contains(&s) => avif_speed = Some(s),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "speed must be between 1 and 10",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "tiling" => {
+                if let Ok(val) = field.text().await {
+                    match val.trim().to_lowercase().as_str() {
+                        "auto" => avif_tiling = AvifTiling::Auto,
+                        "off" => avif_tiling = AvifTiling::Off,
                         _ => {
-                            return (StatusCode::BAD_REQUEST, "format must be 'webp' or 'avif'")
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "tiling must be auto or off",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "deadline_ms" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<u64>() {
+                        Ok(ms) if ms > 0 => deadline = Some(Duration::from_millis(ms)),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "deadline_ms must be a positive integer",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "timeout_ms" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<u64>() {
+                        Ok(ms) if ms > 0 => timeout_ms = Some(ms),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "timeout_ms must be a positive integer",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "encryption_key" => {
+                if let Ok(val) = field.text().await {
+                    #[cfg(feature = "encrypt")]
+                    {
+                        match crypto::parse_key(&val) {
+                            Ok(key) => encryption_key = Some(key),
+                            Err(e) => {
+                                return ApiError::new(
+                                    ErrorCode::InvalidParameter,
+                                    e.to_string(),
+                                    request_id,
+                                )
                                 .into_response()
+                            }
                         }
                     }
+                    #[cfg(not(feature = "encrypt"))]
+                    {
+                        let _ = val;
+                        return ApiError::new(
+                            ErrorCode::InvalidParameter,
+                            "encryption is not supported in this build",
+                            request_id,
+                        )
+                        .into_response();
+                    }
                 }
             }
+            _ if state.config.strict_fields => {
+                tracing::warn!(%request_id, field = %name, "Rejected unknown field");
+                return ApiError::new(
+                    ErrorCode::InvalidParameter,
+                    format!("unknown field: {}", name),
+                    request_id,
+                )
+                .into_response();
+            }
             _ => {}
         }
     }
 
+    // The path's extension overrides the `format` field, `auto` included
+    if let Some(path_format) = path_format {
+        format = path_format;
+        auto_format = false;
+    }
+
     let Some(bytes) = file_bytes else {
         tracing::warn!(%request_id, "Request missing required file field");
-        return (StatusCode::BAD_REQUEST, "Missing file field").into_response();
+        return ApiError::new(ErrorCode::MissingFile, "Missing file field", request_id)
+            .into_response();
+    };
+
+    if bytes.is_empty() {
+        tracing::warn!(%request_id, "Request carried an empty file");
+        return ApiError::new(ErrorCode::EmptyFile, "uploaded file is empty", request_id)
+            .into_response();
+    }
+
+    if let (Some(declared), Some(actual)) = (file_content_type.as_deref(), sniff_mime(&bytes)) {
+        if !declared_type_matches(declared, actual) {
+            if strict_content_type {
+                tracing::warn!(%request_id, declared, actual, "Rejecting mislabeled upload");
+                return ApiError::new(
+                    ErrorCode::ContentTypeMismatch,
+                    format!("file is declared as {} but contains {}", declared, actual),
+                    request_id,
+                )
+                .into_response();
+            }
+            tracing::warn!(%request_id, declared, actual, "Upload content type doesn't match its bytes");
+        }
+    }
+
+    // One response can't be both a single image and a set of variants
+    if formats.is_some() {
+        let conflicts: Vec<&str> = [
+            ("format", format_supplied),
+            ("a path extension", path_format.is_some()),
+            ("dry_run", dry_run),
+            #[cfg(feature = "encrypt")]
+            ("encryption key", encryption_key.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if !conflicts.is_empty() {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                format!("formats can't be combined with {}", conflicts.join(", ")),
+                request_id,
+            )
+            .into_response();
+        }
+    }
+
+    // auto may pick either WebP or AVIF, so it needs both
+    let requested = if let Some(list) = &formats {
+        &list[..]
+    } else if auto_format {
+        &[OutputFormat::WebP, OutputFormat::Avif][..]
+    } else {
+        std::slice::from_ref(&format)
+    };
+    if let Some(disabled) = requested
+        .iter()
+        .find(|f| !state.config.allowed_formats.contains(f))
+    {
+        tracing::warn!(%request_id, format = disabled.name(), "Requested format not enabled");
+        return ApiError::new(
+            ErrorCode::FormatNotEnabled,
+            format!("format not enabled: {}", disabled.name()),
+            request_id,
+        )
+        .into_response();
+    }
+
+    let crop_focus = match (focus_x, focus_y, gravity) {
+        (None, None, gravity) => gravity.unwrap_or_default(),
+        (Some(x), Some(y), None) => CropFocus::Point { x, y },
+        (Some(_), Some(_), Some(_)) => {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                "focus_x/focus_y can't be combined with gravity",
+                request_id,
+            )
+            .into_response()
+        }
+        _ => {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                "focus_x and focus_y must be given together",
+                request_id,
+            )
+            .into_response()
+        }
+    };
+
+    // A transcode only changes the encoding, so anything touching geometry or
+    // pixels is a contradiction rather than something to silently drop
+    if transcode {
+        let conflicts: Vec<&str> = [
+            ("width", width.is_some()),
+            ("height", height.is_some()),
+            ("dpr", dpr != 1.0),
+            ("scale", scale.is_some()),
+            ("fit", fit != Fit::Fill),
+            ("trim", trim),
+            ("rotate", rotate != 0),
+            ("flip", flip != Flip::None),
+            ("mask", mask_name.is_some()),
+            ("blur", blur.is_some()),
+            ("sharpen", sharpen.is_some()),
+            ("brightness", brightness.is_some()),
+            ("contrast", contrast.is_some()),
+            ("gamma", gamma.is_some()),
+            ("saturation", saturation.is_some()),
+            ("grayscale", grayscale),
+            ("tint", tint.is_some()),
+            ("watermark", watermark_bytes.is_some()),
+            ("background", background.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if !conflicts.is_empty() {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                format!("transcode can't be combined with {}", conflicts.join(", ")),
+                request_id,
+            )
+            .into_response();
+        }
+    }
+
+    let mask = match (mask_name.as_deref(), radius) {
+        (Some("circle"), _) => Some(Mask::Circle),
+        (Some(_), Some(radius)) => Some(Mask::Rounded { radius }),
+        (Some(_), None) => {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                "mask=rounded requires radius",
+                request_id,
+            )
+            .into_response()
+        }
+        (None, _) => None,
     };
+    // JPEG would silently turn the masked corners white, in a `formats`
+    // variant as much as in the only output
+    if mask.is_some() && requested.contains(&OutputFormat::Jpeg) && background.is_none() {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "mask needs an output format with transparency; use webp, avif or png, or set background",
+            request_id,
+        )
+        .into_response();
+    }
+
+    if min_ssim.is_some() && format == OutputFormat::Avif && !auto_format {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "min_ssim is not available for avif, whose output can't be decoded for comparison",
+            request_id,
+        )
+        .into_response();
+    }
+
+    if min_ssim.is_some() && target_ratio.is_some() {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "min_ssim and target_ratio are mutually exclusive",
+            request_id,
+        )
+        .into_response();
+    }
+
+    if scale.is_some() && (width.is_some() || height.is_some()) {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "scale can't be combined with width or height",
+            request_id,
+        )
+        .into_response();
+    }
+
+    // `animated=frame` without an index takes the first frame
+    match (take_frame, frame) {
+        (Some(true), None) => frame = Some(0),
+        (Some(false), Some(_)) => {
+            return ApiError::new(
+                ErrorCode::InvalidParameter,
+                "frame can't be combined with animated=reject",
+                request_id,
+            )
+            .into_response();
+        }
+        _ => {}
+    }
+
+    if blur.is_some() && sharpen.is_some() {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "blur and sharpen are mutually exclusive",
+            request_id,
+        )
+        .into_response();
+    }
+
+    // Presets only fill in what wasn't sent explicitly. Speed and chroma
+    // depend on the format encoded, so the processor resolves those per
+    // format; losslessness only differs for JPEG, which ignores it.
+    if let Some(preset) = preset {
+        let settings = preset.settings(if auto_format {
+            OutputFormat::Avif
+        } else {
+            format
+        });
+        if !lossless_supplied && !quality_supplied {
+            lossless = settings.lossless;
+        }
+    }
+
+    if (lossless || format.is_lossless()) && quality_supplied {
+        tracing::debug!(%request_id, format = ?format, "Ignoring quality for lossless output");
+    }
+    if target_ratio.is_some() && (lossless || (format.is_lossless() && !auto_format)) {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "target_ratio needs lossy output; it can't be combined with lossless or png",
+            request_id,
+        )
+        .into_response();
+    }
 
     tracing::info!(
         %request_id,
@@ -128,53 +1609,723 @@ pub async fn convert_image(mut multipart: Multipart) -> Response {
         ?width,
         ?height,
         quality,
+        transcode,
         file_size = bytes.len(),
         "Processing image"
     );
 
+    let encoding_timeout = state
+        .config
+        .encoding_timeout_for(timeout_ms.map(Duration::from_millis));
+    if let Some(ms) = timeout_ms {
+        tracing::info!(
+            %request_id,
+            requested_ms = ms,
+            effective_ms = encoding_timeout.as_millis() as u64,
+            clamped = u128::from(ms) > encoding_timeout.as_millis(),
+            "Using requested encoding timeout"
+        );
+    }
+
     let options = ProcessOptions {
         quality,
+        default_quality: (!quality_supplied)
+            .then(|| preset.map_or(state.config.default_quality, Preset::default_quality)),
         width,
         height,
+        fit,
+        crop_focus,
+        format,
+        auto_format,
+        chroma_subsampling,
+        progressive,
+        transcode,
+        dpr,
+        scale,
+        frame,
+        reject_animated: take_frame == Some(false),
+        loop_count,
+        anim_background,
+        png_compression: state.config.png_compression,
+        webp_lossless_effort: state.config.webp_lossless_effort,
+        force_8bit,
+        bit_depth,
+        background,
+        decode_timeout: Some(state.config.decode_timeout),
+        avif_speed,
+        preset,
+        deadline,
+        avif_tiling,
+        lossless,
+        near_lossless,
+        alpha_quality,
+        limits: state.config.limits,
+        allowed_inputs: state.config.allowed_inputs.clone(),
+        require_photo: require_photo.then_some(state.config.photo_min_colors),
+        brightness,
+        contrast,
+        gamma,
+        saturation,
+        grayscale,
+        tint,
+        blur,
+        sharpen,
+        trim: trim.then_some(trim_tolerance),
+        watermark: watermark_bytes.map(|bytes| Watermark {
+            bytes,
+            gravity: watermark_gravity,
+            opacity: watermark_opacity,
+        }),
+        rotate,
+        flip,
+        mask,
+        score,
+        min_ssim,
+        target_ratio,
+        // Leave room for the final fallback encode within the timeout
+        search_budget: (min_ssim.is_some() || target_ratio.is_some())
+            .then(|| encoding_timeout * 3 / 4),
+        embed_thumbnail,
+        metadata: text_metadata,
+        dpi,
+    };
+    // Only named in the AVIF timeout message
+    let avif_speed = options.resolved_avif_speed();
+    let conversion = Conversion {
+        state,
+        client,
+        client_ip,
+        request_id,
+        bytes,
+        options,
+        formats,
         format,
+        width,
+        height,
+        dpr,
+        encoding_timeout,
+        dry_run,
+        auto_format,
+        download,
+        file_name,
+        target_ratio,
+        avif_speed,
+        #[cfg(feature = "encrypt")]
+        encryption_key,
     };
+    // Anything wrong with the request itself has been answered by now; with
+    // `async=true` the rest runs as a background job
+    if async_job {
+        let state = conversion.state.clone();
+        let owner = conversion.client.clone();
+        let key = conversion.job_key();
+        return spawn_job(&state, request_id, owner, key, run_conversion(conversion));
+    }
+    run_conversion(conversion).await
+}
+
+/// A validated `/convert` request: the upload, its `ProcessOptions` and what
+/// shapes the response.
+struct Conversion {
+    state: AppState,
+    client: Arc<str>,
+    client_ip: String,
+    request_id: RequestId,
+    bytes: Bytes,
+    options: ProcessOptions,
+    formats: Option<Vec<OutputFormat>>,
+    format: OutputFormat,
+    width: Option<u32>,
+    height: Option<u32>,
+    dpr: f32,
+    encoding_timeout: Duration,
+    dry_run: bool,
+    auto_format: bool,
+    download: bool,
+    file_name: Option<String>,
+    target_ratio: Option<f32>,
+    avif_speed: u8,
+    #[cfg(feature = "encrypt")]
+    encryption_key: Option<[u8; crypto::KEY_LEN]>,
+}
+
+impl Conversion {
+    /// Identity of the whole response: the `content_key` plus every field
+    /// that shapes the response around the image. Identical async
+    /// submissions share a job through it.
+    fn job_key(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(content_key(&self.bytes, &self.options));
+        match &self.formats {
+            Some(formats) => {
+                hasher.update([1]);
+                for format in formats {
+                    hasher.update(format.name().as_bytes());
+                    hasher.update([0]);
+                }
+            }
+            None => hasher.update([0]),
+        }
+        hasher.update([
+            self.dry_run as u8,
+            self.auto_format as u8,
+            self.download as u8,
+        ]);
+        match &self.file_name {
+            Some(name) => {
+                hasher.update([1]);
+                hasher.update((name.len() as u64).to_le_bytes());
+                hasher.update(name.as_bytes());
+            }
+            None => hasher.update([0]),
+        }
+        match self.target_ratio {
+            Some(ratio) => {
+                hasher.update([1]);
+                hasher.update(ratio.to_le_bytes());
+            }
+            None => hasher.update([0]),
+        }
+        #[cfg(feature = "encrypt")]
+        match &self.encryption_key {
+            Some(key) => {
+                hasher.update([1]);
+                hasher.update(key);
+            }
+            None => hasher.update([0]),
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&hasher.finalize());
+        key
+    }
+}
+
+/// Runs a validated conversion and builds its response, whether for the
+/// request itself or for a background job.
+async fn run_conversion(conversion: Conversion) -> Response {
+    let Conversion {
+        state,
+        client,
+        client_ip,
+        request_id,
+        bytes,
+        options,
+        formats,
+        format,
+        width,
+        height,
+        dpr,
+        encoding_timeout,
+        dry_run,
+        auto_format,
+        download,
+        file_name,
+        target_ratio,
+        avif_speed,
+        #[cfg(feature = "encrypt")]
+        encryption_key,
+    } = conversion;
     let format_copy = format;
+    let input_len = bytes.len();
+    // Large uploads and outputs run on their own pool so they can't starve small ones
+    let output_side = |side: Option<u32>| side.map(|s| (s as f32 * dpr) as u32);
+    let lane = state
+        .pools
+        .lane_for(bytes.len(), output_side(width), output_side(height));
+
+    if let Some(formats) = formats {
+        tracing::info!(
+            %request_id,
+            %client,
+            %client_ip,
+            formats = ?formats,
+            "Converting to several formats"
+        );
+        return convert_to_formats(
+            &state,
+            request_id,
+            bytes,
+            options,
+            formats,
+            lane,
+            encoding_timeout,
+        )
+        .await;
+    }
 
     // SEC-003: wrap spawn_blocking with a timeout to prevent CPU starvation
-    let processing = tokio::task::spawn_blocking(move || process_image(&bytes, options));
+    let key =
+        (state.config.coalesce_identical || state.cache.is_some() || state.disk_cache.is_some())
+            .then(|| content_key(&bytes, &options));
+    let mut cached = key.and_then(|key| state.cache.as_ref()?.get(&key));
+    if let (None, Some(key), Some(disk)) = (&cached, key, &state.disk_cache) {
+        cached = disk.get(&key).await;
+        // Promote so the next hit doesn't touch the disk
+        if let (Some(hit), Some(cache)) = (&cached, &state.cache) {
+            cache.insert(key, hit.clone());
+        }
+    }
+    let from_cache = cached.is_some();
+    tracing::debug!(%request_id, ?lane, "Dispatching conversion");
+    let pools = state.pools.clone();
+    // Blocking threads don't inherit the current span; carry it over so the
+    // stage spans nest under this request
+    let span = tracing::Span::current();
+    let convert = move || async move {
+        let processing = pools.spawn(lane, move || {
+            span.in_scope(|| process_image(&bytes, options))
+        });
+        Arc::new(tokio::time::timeout(encoding_timeout, processing).await)
+    };
+    let outcome = match (cached, key) {
+        (Some(hit), _) => {
+            tracing::debug!(%request_id, "Serving conversion from cache");
+            Arc::new(Ok(Ok(Ok(hit))))
+        }
+        (None, Some(key)) if state.config.coalesce_identical => {
+            let (outcome, leader) = state.inflight.run(key, convert).await;
+            if !leader {
+                tracing::info!(%request_id, "Reused result of identical in-flight conversion");
+            }
+            outcome
+        }
+        _ => convert().await,
+    };
+    // A dry run is a probe; its result isn't worth the cache space
+    if let (false, false, Some(key), Ok(Ok(Ok(processed)))) =
+        (from_cache, dry_run, key, outcome.as_ref())
+    {
+        if let Some(cache) = &state.cache {
+            cache.insert(key, processed.clone());
+        }
+        if let Some(disk) = state.disk_cache.clone() {
+            // Written in the background so the response isn't held up by disk I/O
+            let processed = processed.clone();
+            tokio::spawn(async move { disk.insert(key, &processed).await });
+        }
+    }
 
-    match tokio::time::timeout(ENCODING_TIMEOUT, processing).await {
-        Ok(Ok(Ok(converted_bytes))) => {
+    match outcome.as_ref() {
+        Ok(Ok(Ok(processed))) => {
+            let converted_bytes = processed.bytes.clone();
             tracing::info!(
                 %request_id,
+                %client,
+                %client_ip,
                 output_size = converted_bytes.len(),
                 "Image conversion successful"
             );
-            let content_type = match format_copy {
-                OutputFormat::WebP => "image/webp",
-                OutputFormat::Avif => "image/avif",
-            };
+            if dry_run {
+                let result = DryRunResult {
+                    output_bytes: converted_bytes.len(),
+                    width: processed.width,
+                    height: processed.height,
+                    format: processed.format.name(),
+                };
+                let mut response = Json(result).into_response();
+                response
+                    .headers_mut()
+                    .insert("X-Request-Id", request_id.header_value());
+                return response;
+            }
+            let content_type = processed.format.mime();
             let mut headers = HeaderMap::new();
             headers.insert("Content-Type", content_type.parse().unwrap());
             // OBS-001: propagate request_id to client for traceability
-            headers.insert("X-Request-Id", request_id.to_string().parse().unwrap());
-            (StatusCode::OK, headers, converted_bytes).into_response()
+            headers.insert("X-Request-Id", request_id.header_value());
+            if auto_format {
+                headers.insert("X-Chosen-Format", processed.format.name().parse().unwrap());
+            }
+            let filename = file_name
+                .as_deref()
+                .and_then(|name| output_filename(name, processed.format));
+            if filename.is_some() || download {
+                let disposition = content_disposition(download, filename.as_deref());
+                headers.insert("Content-Disposition", disposition.parse().unwrap());
+            }
+            if processed.warnings.contains(&Warning::TrimSkipped) {
+                tracing::warn!(%request_id, "Trim would remove the whole image; kept original bounds");
+            }
+            if !processed.warnings.is_empty() {
+                let codes: Vec<&str> = processed.warnings.iter().map(|w| w.code()).collect();
+                headers.insert("X-Warnings", codes.join(",").parse().unwrap());
+            }
+            headers.insert("X-Image-Width", processed.width.into());
+            headers.insert("X-Image-Height", processed.height.into());
+            headers.insert("X-Image-Bytes", converted_bytes.len().into());
+            headers.insert(
+                "X-Encode-Ms",
+                (processed.encode_duration.as_millis() as u64).into(),
+            );
+            if let Some(speed) = processed.avif_speed {
+                headers.insert("X-Avif-Speed", speed.into());
+            }
+            if let Some((w, h)) = processed.render_size {
+                headers.insert("X-Render-Size", format!("{}x{}", w, h).parse().unwrap());
+            }
+            if let Some(quality) = processed.searched_quality {
+                headers.insert("X-Quality", quality.into());
+            }
+            if let Some(score) = processed.quality_score {
+                headers.insert("X-Quality-Score", format!("{:.4}", score).parse().unwrap());
+            }
+            if target_ratio.is_some() {
+                let ratio = converted_bytes.len() as f64 / input_len as f64;
+                headers.insert(
+                    "X-Compression-Ratio",
+                    format!("{:.4}", ratio).parse().unwrap(),
+                );
+            }
+            #[cfg(feature = "encrypt")]
+            {
+                if let Some(key) = encryption_key {
+                    return encrypted_response(request_id, &key, &converted_bytes, headers);
+                }
+            }
+            streamed_response(headers, converted_bytes)
         }
         Ok(Ok(Err(e))) => {
-            tracing::error!(%request_id, error = %e, "Image processing failed");
-            (StatusCode::UNPROCESSABLE_ENTITY, "Image processing failed").into_response()
+            let Some(err) = e.downcast_ref::<ProcessError>() else {
+                tracing::error!(
+                    %request_id,
+                    %client,
+                    %client_ip,
+                    error = %e,
+                    "Image processing failed"
+                );
+                return ApiError::new(
+                    ErrorCode::DecodeFailed,
+                    "Image processing failed",
+                    request_id,
+                )
+                .into_response();
+            };
+            match err {
+                ProcessError::DecodeTimeout { .. } => {
+                    tracing::error!(%request_id, error = %err, "Image decoding timed out");
+                }
+                ProcessError::OutputTooLarge { .. } => {
+                    tracing::warn!(
+                        %request_id,
+                        %client,
+                        %client_ip,
+                        error = %err,
+                        "Discarded oversized output"
+                    );
+                }
+                ProcessError::MultiFrame
+                | ProcessError::FrameOutOfRange { .. }
+                | ProcessError::HeicUnsupported
+                | ProcessError::UnsupportedFormat
+                | ProcessError::AnimationTooLong { .. }
+                | ProcessError::TooManyFrames { .. }
+                | ProcessError::AnimationTooLarge { .. } => {
+                    tracing::warn!(%request_id, error = %err, "Rejected input");
+                }
+                _ => {
+                    tracing::info!(%request_id, error = %err, "Rejected input");
+                }
+            }
+            let message = match err {
+                ProcessError::DecodeTimeout { .. } => "Decoding timed out".to_string(),
+                _ => err.to_string(),
+            };
+            ApiError::new(ErrorCode::from(err), message, request_id).into_response()
         }
         Ok(Err(e)) => {
             tracing::error!(%request_id, error = %e, "Task join error");
-            (StatusCode::INTERNAL_SERVER_ERROR, "Internal error").into_response()
+            ApiError::new(ErrorCode::Internal, "Internal error", request_id).into_response()
         }
         Err(_) => {
             tracing::error!(
                 %request_id,
-                timeout_secs = ENCODING_TIMEOUT.as_secs(),
+                timeout_secs = encoding_timeout.as_secs(),
                 "Image encoding timed out"
             );
-            (StatusCode::REQUEST_TIMEOUT, "Processing timed out").into_response()
+            if format_copy == OutputFormat::Avif {
+                // Low speeds are the usual cause; tell the client which knob to turn
+                let message = format!(
+                    "Processing timed out after {}s at AVIF speed {}; higher speed values encode faster",
+                    encoding_timeout.as_secs(),
+                    avif_speed
+                );
+                return ApiError::new(ErrorCode::Timeout, message, request_id).into_response();
+            }
+            ApiError::new(ErrorCode::Timeout, "Processing timed out", request_id).into_response()
+        }
+    }
+}
+
+/// `formats` path: decodes and transforms once, then encodes each format as
+/// its own pool job so they run concurrently within the usual pool limits.
+/// The whole conversion shares one encoding timeout. Results aren't cached.
+async fn convert_to_formats(
+    state: &AppState,
+    request_id: RequestId,
+    bytes: Bytes,
+    options: ProcessOptions,
+    formats: Vec<OutputFormat>,
+    lane: Lane,
+    encoding_timeout: Duration,
+) -> Response {
+    let pools = state.pools.clone();
+    let span = tracing::Span::current();
+    let work = async move {
+        let decoded = pools
+            .spawn(lane, {
+                let span = span.clone();
+                move || span.in_scope(|| decode_once(&bytes, options))
+            })
+            .await?;
+        let decoded = match decoded {
+            Ok(decoded) => Arc::new(decoded),
+            Err(e) => return Ok(Err(e)),
+        };
+        let mut tasks = JoinSet::new();
+        for (i, &format) in formats.iter().enumerate() {
+            let (pools, decoded, span) = (pools.clone(), decoded.clone(), span.clone());
+            tasks.spawn(async move {
+                let job = pools.spawn(lane, move || span.in_scope(|| decoded.encode(format)));
+                (i, job.await)
+            });
+        }
+        // Returning early drops the set, which aborts the remaining encodes
+        let mut slots: Vec<Option<ProcessedImage>> = formats.iter().map(|_| None).collect();
+        while let Some(joined) = tasks.join_next().await {
+            let (i, encoded) = joined?;
+            match encoded? {
+                Ok(processed) => slots[i] = Some(processed),
+                Err(e) => return Ok(Err(e)),
+            }
+        }
+        Ok::<_, JoinError>(Ok(slots.into_iter().flatten().collect::<Vec<_>>()))
+    };
+
+    let processed = match timeout(encoding_timeout, work).await {
+        Ok(Ok(Ok(processed))) => processed,
+        Ok(Ok(Err(e))) => {
+            tracing::warn!(%request_id, error = %e, "Multi-format conversion failed");
+            return match e.downcast_ref::<ProcessError>() {
+                Some(err) => ApiError::new(ErrorCode::from(err), err.to_string(), request_id),
+                None => ApiError::new(
+                    ErrorCode::DecodeFailed,
+                    "Image processing failed",
+                    request_id,
+                ),
+            }
+            .into_response();
+        }
+        Ok(Err(e)) => {
+            tracing::error!(%request_id, error = %e, "Task join error");
+            return ApiError::new(ErrorCode::Internal, "Internal error", request_id)
+                .into_response();
+        }
+        Err(_) => {
+            tracing::error!(%request_id, "Multi-format encoding timed out");
+            return ApiError::new(ErrorCode::Timeout, "Processing timed out", request_id)
+                .into_response();
+        }
+    };
+
+    let sizes: Vec<(OutputFormat, usize)> = processed
+        .iter()
+        .map(|p| (p.format, p.bytes.len()))
+        .collect();
+    let variants = processed
+        .iter()
+        .map(|p| FormatVariant {
+            format: p.format.name(),
+            width: p.width,
+            height: p.height,
+            bytes: p.bytes.len(),
+            base64: base64::engine::general_purpose::STANDARD.encode(&p.bytes),
+            warnings: p.warnings.iter().map(|w| w.code()).collect(),
+        })
+        .collect();
+    let result = VariantsResult {
+        variants,
+        summary: size_summary(&sizes),
+    };
+    (
+        [(request_id::HEADER, request_id.header_value())],
+        Json(result),
+    )
+        .into_response()
+}
+
+/// Encrypts the converted bytes and swaps the content type for an opaque one.
+/// The original image type is kept in `X-Original-Content-Type` and the nonce
+/// needed for decryption is returned hex-encoded in `X-Encryption-Nonce`.
+#[cfg(feature = "encrypt")]
+fn encrypted_response(
+    request_id: RequestId,
+    key: &[u8; crypto::KEY_LEN],
+    plaintext: &[u8],
+    mut headers: HeaderMap,
+) -> Response {
+    let encrypted = match crypto::encrypt(key, plaintext) {
+        Ok(e) => e,
+        Err(e) => {
+            tracing::error!(%request_id, error = %e, "Output encryption failed");
+            return ApiError::new(ErrorCode::Internal, "Internal error", request_id)
+                .into_response();
+        }
+    };
+
+    if let Some(original) = headers.remove("Content-Type") {
+        headers.insert("X-Original-Content-Type", original);
+    }
+    headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
+    headers.insert(
+        "X-Encryption-Nonce",
+        hex::encode(encrypted.nonce).parse().unwrap(),
+    );
+
+    streamed_response(headers, Bytes::from(encrypted.ciphertext))
+}
+
+/// Chunk size for streamed response bodies.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Sends `bytes` as a chunked stream so the client can start reading before
+/// the whole body has been written. Chunks are slices of the same buffer,
+/// which the cache may share, so nothing is copied. The length is known up
+/// front and set explicitly; a streamed body carries no size hint.
+fn streamed_response(mut headers: HeaderMap, bytes: Bytes) -> Response {
+    headers.insert("Content-Length", bytes.len().into());
+    let chunks = (0..bytes.len())
+        .step_by(STREAM_CHUNK_SIZE)
+        .map(move |start| {
+            let end = (start + STREAM_CHUNK_SIZE).min(bytes.len());
+            Ok::<_, Infallible>(bytes.slice(start..end))
+        });
+    let body = Body::from_stream(stream::iter(chunks));
+    (StatusCode::OK, headers, body).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_size_summary_marks_smallest_format() {
+        // Noisy photo-like content: lossy WebP beats lossless PNG by a wide margin
+        let mut source = RgbaImage::new(64, 64);
+        for (x, y, pixel) in source.enumerate_pixels_mut() {
+            let v = (x * 7919 + y * 104_729) % 251;
+            *pixel = Rgba([v as u8, (v * 3 % 256) as u8, (v * 7 % 256) as u8, 255]);
         }
+        let mut input = Vec::new();
+        source
+            .write_to(&mut Cursor::new(&mut input), image::ImageFormat::Png)
+            .unwrap();
+
+        let results: Vec<(OutputFormat, usize)> = [OutputFormat::Png, OutputFormat::WebP]
+            .into_iter()
+            .map(|format| {
+                let options = ProcessOptions {
+                    format,
+                    ..Default::default()
+                };
+                (format, process_image(&input, options).unwrap().bytes.len())
+            })
+            .collect();
+
+        let summary = size_summary(&results);
+        assert_eq!(summary.smallest, Some("webp"));
+        assert_eq!(summary.formats[0].format, "webp");
+        assert!(summary.formats[0].smallest);
+        assert!(!summary.formats[1].smallest);
+        assert!(summary.formats[0].bytes < summary.formats[1].bytes);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["formats"][1]["format"], "png");
+    }
+
+    #[test]
+    fn test_size_summary_empty() {
+        let summary = size_summary(&[]);
+        assert!(summary.formats.is_empty());
+        assert_eq!(summary.smallest, None);
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(format_from_path("photo.avif"), Ok(OutputFormat::Avif));
+        assert_eq!(format_from_path("a.b.JPG"), Ok(OutputFormat::Jpeg));
+        assert_eq!(format_from_path("x.webp"), Ok(OutputFormat::WebP));
+        assert!(format_from_path("photo.gif")
+            .unwrap_err()
+            .contains("\"gif\""));
+        assert!(format_from_path("photo").is_err());
+        assert!(format_from_path("photo.").is_err());
+    }
+
+    #[test]
+    fn test_output_filename_swaps_extension_and_sanitizes() {
+        assert_eq!(
+            output_filename("photo.png", OutputFormat::WebP).as_deref(),
+            Some("photo.webp")
+        );
+        assert_eq!(
+            output_filename("../../etc/my.photo.JPG", OutputFormat::Avif).as_deref(),
+            Some("my.photo.avif")
+        );
+        assert_eq!(
+            output_filename("C:\\Users\\a\\scan", OutputFormat::Png).as_deref(),
+            Some("scan.png")
+        );
+        assert_eq!(
+            output_filename("bad\"\r\nname.gif", OutputFormat::WebP).as_deref(),
+            Some("badname.webp")
+        );
+        assert_eq!(output_filename("dir/", OutputFormat::WebP), None);
+        assert_eq!(output_filename("..", OutputFormat::WebP), None);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(content_disposition(true, None), "attachment");
+        assert_eq!(
+            content_disposition(false, Some("photo.webp")),
+            "inline; filename=\"photo.webp\""
+        );
+        assert_eq!(
+            content_disposition(true, Some("café.webp")),
+            "attachment; filename=\"caf_.webp\"; filename*=UTF-8''caf%C3%A9.webp"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response_sends_whole_body() {
+        let payload: Vec<u8> = (0..STREAM_CHUNK_SIZE * 2 + 123).map(|i| i as u8).collect();
+        let response = streamed_response(HeaderMap::new(), Bytes::from(payload.clone()));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("Content-Length").unwrap(),
+            &payload.len().to_string()
+        );
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body.as_ref(), payload.as_slice());
+    }
+
+    #[test]
+    fn test_declared_type_matches() {
+        assert!(declared_type_matches("image/png", "image/png"));
+        assert!(declared_type_matches(
+            "IMAGE/JPG; charset=binary",
+            "image/jpeg"
+        ));
+        assert!(declared_type_matches(
+            "application/octet-stream",
+            "image/png"
+        ));
+        assert!(!declared_type_matches("image/png", "image/jpeg"));
+        assert!(!declared_type_matches("text/plain", "image/gif"));
     }
 }
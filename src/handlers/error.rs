@@ -0,0 +1,208 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::processor::ProcessError;
+use crate::request_id::{self, RequestId};
+
+/// Machine-readable error codes. The strings are part of the API: clients
+/// branch on them, so existing codes must never change meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    InvalidMultipart,
+    MissingFile,
+    EmptyFile,
+    InvalidParameter,
+    QualityOutOfRange,
+    TooLarge,
+    FrameOutOfRange,
+    FormatNotEnabled,
+    PayloadTooLarge,
+    Timeout,
+    UnsupportedFormat,
+    ContentTypeMismatch,
+    InputFormatNotAccepted,
+    FormatLimitExceeded,
+    AnimatedInput,
+    AnimationTooLong,
+    AnimationTooLarge,
+    OutputTooLarge,
+    NotPhoto,
+    TooSmall,
+    Truncated,
+    HeicUnsupported,
+    DecodeFailed,
+    JobNotFound,
+    TooManyJobs,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorCode::InvalidMultipart => "invalid_multipart",
+            ErrorCode::MissingFile => "missing_file",
+            ErrorCode::EmptyFile => "empty_file",
+            ErrorCode::InvalidParameter => "invalid_parameter",
+            ErrorCode::QualityOutOfRange => "quality_out_of_range",
+            ErrorCode::TooLarge => "too_large",
+            ErrorCode::FrameOutOfRange => "frame_out_of_range",
+            ErrorCode::FormatNotEnabled => "format_not_enabled",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::Timeout => "timeout",
+            ErrorCode::UnsupportedFormat => "unsupported_format",
+            ErrorCode::ContentTypeMismatch => "content_type_mismatch",
+            ErrorCode::InputFormatNotAccepted => "input_format_not_accepted",
+            ErrorCode::FormatLimitExceeded => "format_limit_exceeded",
+            ErrorCode::AnimatedInput => "animated_input",
+            ErrorCode::AnimationTooLong => "animation_too_long",
+            ErrorCode::AnimationTooLarge => "animation_too_large",
+            ErrorCode::OutputTooLarge => "output_too_large",
+            ErrorCode::NotPhoto => "not_photo",
+            ErrorCode::TooSmall => "too_small",
+            ErrorCode::Truncated => "truncated_image",
+            ErrorCode::HeicUnsupported => "heic_unsupported",
+            ErrorCode::DecodeFailed => "decode_failed",
+            ErrorCode::JobNotFound => "job_not_found",
+            ErrorCode::TooManyJobs => "too_many_jobs",
+            ErrorCode::Internal => "internal_error",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidMultipart
+            | ErrorCode::MissingFile
+            | ErrorCode::EmptyFile
+            | ErrorCode::InvalidParameter
+            | ErrorCode::QualityOutOfRange
+            | ErrorCode::TooLarge
+            | ErrorCode::FrameOutOfRange
+            | ErrorCode::FormatNotEnabled => StatusCode::BAD_REQUEST,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::Timeout => StatusCode::REQUEST_TIMEOUT,
+            ErrorCode::ContentTypeMismatch | ErrorCode::InputFormatNotAccepted => {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE
+            }
+            ErrorCode::UnsupportedFormat
+            | ErrorCode::FormatLimitExceeded
+            | ErrorCode::AnimatedInput
+            | ErrorCode::AnimationTooLong
+            | ErrorCode::AnimationTooLarge
+            | ErrorCode::OutputTooLarge
+            | ErrorCode::NotPhoto
+            | ErrorCode::TooSmall
+            | ErrorCode::Truncated
+            | ErrorCode::HeicUnsupported
+            | ErrorCode::DecodeFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::JobNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::TooManyJobs => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<&ProcessError> for ErrorCode {
+    fn from(err: &ProcessError) -> Self {
+        match err {
+            ProcessError::MultiFrame => ErrorCode::AnimatedInput,
+            ProcessError::FrameOutOfRange { .. } => ErrorCode::FrameOutOfRange,
+            ProcessError::HeicUnsupported => ErrorCode::HeicUnsupported,
+            ProcessError::DecodeTimeout { .. } => ErrorCode::Timeout,
+            ProcessError::UnsupportedFormat => ErrorCode::UnsupportedFormat,
+            ProcessError::AnimationTooLong { .. } => ErrorCode::AnimationTooLong,
+            ProcessError::TooManyFrames { .. } | ProcessError::AnimationTooLarge { .. } => {
+                ErrorCode::AnimationTooLarge
+            }
+            ProcessError::NotPhoto { .. } => ErrorCode::NotPhoto,
+            ProcessError::TooSmall { .. } => ErrorCode::TooSmall,
+            ProcessError::Truncated => ErrorCode::Truncated,
+            ProcessError::OutputTooLarge { .. } => ErrorCode::OutputTooLarge,
+            ProcessError::InputNotAccepted { .. } => ErrorCode::InputFormatNotAccepted,
+            ProcessError::FormatLimit { .. } => ErrorCode::FormatLimitExceeded,
+        }
+    }
+}
+
+/// Error response body: `{"error": {"code", "message", "request_id"}}`,
+/// always sent as `application/json` with the code's status and the id
+/// echoed in `X-Request-Id`.
+#[derive(Debug)]
+pub struct ApiError {
+    code: ErrorCode,
+    message: String,
+    request_id: RequestId,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>, request_id: RequestId) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            request_id,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetail<'a> {
+    /// Stable, machine-readable error code.
+    code: &'static str,
+    message: &'a str,
+    request_id: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: ErrorDetail {
+                code: self.code.code(),
+                message: &self.message,
+                request_id: self.request_id.to_string(),
+            },
+        };
+        (
+            self.code.status(),
+            [(request_id::HEADER, self.request_id.header_value())],
+            Json(body),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_error_body_shape() {
+        let request_id = RequestId::generate();
+        let response =
+            ApiError::new(ErrorCode::MissingFile, "Missing file field", request_id).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(
+            response.headers().get("x-request-id").unwrap(),
+            request_id.as_str()
+        );
+
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["error"]["code"], "missing_file");
+        assert_eq!(json["error"]["message"], "Missing file field");
+        assert_eq!(json["error"]["request_id"], request_id.to_string());
+    }
+}
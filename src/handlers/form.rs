@@ -0,0 +1,441 @@
+use axum::{
+    body::Bytes,
+    extract::{
+        multipart::{Field, MultipartError},
+        FromRequest, Multipart, Query, Request,
+    },
+    http::header,
+};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::request_id::RequestId;
+
+/// Caps on the fields a handler reads, so a client can't tie up a worker
+/// with thousands of tiny parts or parts trickled in one by one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FieldLimits {
+    /// Most fields read from the body or query string, counting each entry
+    /// unpacked from an `options` object as a field of its own.
+    pub max_fields: usize,
+    /// Total time spent waiting for the next field to start. Reading a
+    /// field's contents, the upload included, isn't counted; the request
+    /// timeout covers that.
+    pub parse_time: Duration,
+}
+
+impl Default for FieldLimits {
+    fn default() -> Self {
+        Self {
+            max_fields: 20,
+            parse_time: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Why the next field couldn't be read.
+#[derive(Debug)]
+pub enum FieldError {
+    Multipart(MultipartError),
+    /// More than `FieldLimits::max_fields` fields were sent.
+    TooMany {
+        limit: usize,
+    },
+    /// Waiting for fields took longer than `FieldLimits::parse_time`.
+    TooSlow {
+        limit: Duration,
+    },
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldError::Multipart(e) => e.fmt(f),
+            FieldError::TooMany { limit } => write!(f, "more than {} form fields", limit),
+            FieldError::TooSlow { limit } => write!(
+                f,
+                "form fields took longer than {}s to arrive",
+                limit.as_secs_f32()
+            ),
+        }
+    }
+}
+
+impl FieldError {
+    /// Response for a field that couldn't be read: `400` for malformed
+    /// multipart or too many fields, `408` for fields arriving too slowly.
+    pub fn api_error(&self, request_id: RequestId) -> ApiError {
+        match self {
+            FieldError::Multipart(_) => ApiError::new(
+                ErrorCode::InvalidMultipart,
+                "Invalid multipart request",
+                request_id,
+            ),
+            FieldError::TooMany { .. } => {
+                ApiError::new(ErrorCode::InvalidMultipart, self.to_string(), request_id)
+            }
+            FieldError::TooSlow { .. } => {
+                ApiError::new(ErrorCode::Timeout, self.to_string(), request_id)
+            }
+        }
+    }
+}
+
+/// Enforces `FieldLimits` over one request's field loop. Handlers reading
+/// `Multipart` directly wrap each `next_field` call in `next`.
+pub struct FieldBudget {
+    limits: FieldLimits,
+    read: usize,
+    waited: Duration,
+}
+
+impl FieldBudget {
+    pub fn new(limits: FieldLimits) -> Self {
+        Self {
+            limits,
+            read: 0,
+            waited: Duration::ZERO,
+        }
+    }
+
+    /// Awaits `next_field` within what's left of the time budget and counts
+    /// the field it yields.
+    pub async fn next<T>(
+        &mut self,
+        next_field: impl Future<Output = Result<Option<T>, MultipartError>>,
+    ) -> Result<Option<T>, FieldError> {
+        let too_slow = FieldError::TooSlow {
+            limit: self.limits.parse_time,
+        };
+        // `timeout` polls once before checking its deadline, so a spent
+        // budget has to be refused up front
+        let remaining = self.limits.parse_time.saturating_sub(self.waited);
+        if remaining.is_zero() {
+            return Err(too_slow);
+        }
+        let started = Instant::now();
+        let result = tokio::time::timeout(remaining, next_field).await;
+        self.waited += started.elapsed();
+        let field = result
+            .map_err(|_| too_slow)?
+            .map_err(FieldError::Multipart)?;
+        if field.is_some() {
+            self.count()?;
+        }
+        Ok(field)
+    }
+
+    /// Counts one field that didn't come from `next_field`, such as an
+    /// entry of an `options` object.
+    pub fn count(&mut self) -> Result<(), FieldError> {
+        self.read += 1;
+        if self.read > self.limits.max_fields {
+            return Err(FieldError::TooMany {
+                limit: self.limits.max_fields,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Request parameters for a conversion, read either from a multipart form or
+/// from a raw image body plus query string. Both yield the same field names,
+/// so handlers parse them with one loop.
+pub struct FormFields {
+    source: Source,
+    /// Entries unpacked from an `options` JSON object, handed out before the
+    /// next part is read.
+    queued: VecDeque<FormField<'static>>,
+    /// Names of fields sent on their own. These win over `options` entries.
+    seen: HashSet<String>,
+    /// Extra names the image part may come under (see `field_name`). Like
+    /// `file`, they can't be passed in the query or in `options`.
+    file_names: Vec<String>,
+    budget: FieldBudget,
+}
+
+enum Source {
+    Multipart(Multipart),
+    Raw(std::vec::IntoIter<FormField<'static>>),
+}
+
+/// One named value: a multipart part, or a query parameter / the raw body.
+pub enum FormField<'a> {
+    Multipart(Field<'a>),
+    Text(String, String),
+    File(String, Bytes),
+}
+
+/// Why the request's fields couldn't be read.
+pub enum FormRejection {
+    /// Raw body exceeded the upload limit.
+    TooLarge,
+    /// Anything else: malformed multipart, unreadable body.
+    Invalid(String),
+}
+
+/// Whether the body is a bare image rather than a form.
+fn is_raw_image(request: &Request) -> bool {
+    request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.trim_start().to_ascii_lowercase().starts_with("image/"))
+}
+
+impl FormFields {
+    /// Picks multipart or raw mode from `Content-Type`. In raw mode the body
+    /// becomes the `file` field and query parameters supply the rest; file
+    /// fields, including any of `file_names`, can't be passed in the query.
+    pub async fn from_request<S: Send + Sync>(
+        request: Request,
+        state: &S,
+        limits: FieldLimits,
+        file_names: &[String],
+    ) -> Result<Self, FormRejection> {
+        let file_names = file_names.to_vec();
+        if !is_raw_image(&request) {
+            return Multipart::from_request(request, state)
+                .await
+                .map(|multipart| FormFields::new(Source::Multipart(multipart), limits, file_names))
+                .map_err(|rejection| FormRejection::Invalid(rejection.body_text()));
+        }
+
+        let params = Query::<Vec<(String, String)>>::try_from_uri(request.uri())
+            .map_err(|rejection| FormRejection::Invalid(rejection.body_text()))?
+            .0;
+        let body = Bytes::from_request(request, state)
+            .await
+            .map_err(|rejection| {
+                if rejection.status() == axum::http::StatusCode::PAYLOAD_TOO_LARGE {
+                    FormRejection::TooLarge
+                } else {
+                    FormRejection::Invalid(rejection.body_text())
+                }
+            })?;
+
+        let mut fields = vec![FormField::File("file".to_string(), body)];
+        fields.extend(
+            params
+                .into_iter()
+                .filter(|(name, _)| !is_file_field(name, &file_names))
+                .map(|(name, value)| FormField::Text(name, value)),
+        );
+        Ok(FormFields::new(
+            Source::Raw(fields.into_iter()),
+            limits,
+            file_names,
+        ))
+    }
+
+    fn new(source: Source, limits: FieldLimits, file_names: Vec<String>) -> Self {
+        Self {
+            source,
+            queued: VecDeque::new(),
+            seen: HashSet::new(),
+            file_names,
+            budget: FieldBudget::new(limits),
+        }
+    }
+
+    pub async fn next_field(&mut self) -> Result<Option<FormField<'_>>, FieldError> {
+        if let Some(queued) = self.queued.pop_front() {
+            self.budget.count()?;
+            return Ok(Some(queued));
+        }
+        let field = match &mut self.source {
+            Source::Multipart(multipart) => self
+                .budget
+                .next(multipart.next_field())
+                .await?
+                .map(FormField::Multipart),
+            Source::Raw(fields) => {
+                let next = fields.next();
+                self.budget
+                    .next(async { Ok::<_, MultipartError>(next) })
+                    .await?
+            }
+        };
+        if let Some(name) = field.as_ref().and_then(FormField::name) {
+            self.seen.insert(name.to_string());
+        }
+        Ok(field)
+    }
+
+    /// Unpacks an `options` JSON object into text fields, returned by the
+    /// following `next_field` calls so each value goes through the same
+    /// validation as a separately sent field. Keys already sent as their own
+    /// field are skipped, as are nulls; a field sent later still overrides.
+    pub fn queue_options(&mut self, json: &str) -> Result<(), String> {
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)
+            .map_err(|e| format!("options must be a JSON object: {}", e))?;
+        for (key, value) in object {
+            if key == "options" || is_file_field(&key, &self.file_names) {
+                return Err(format!("options cannot contain {}", key));
+            }
+            let text = match value {
+                serde_json::Value::Null => continue,
+                serde_json::Value::String(s) => s,
+                serde_json::Value::Bool(b) => b.to_string(),
+                serde_json::Value::Number(n) => number_text(&n),
+                _ => {
+                    return Err(format!(
+                        "options.{} must be a string, number or boolean",
+                        key
+                    ))
+                }
+            };
+            if !self.seen.contains(&key) {
+                self.queued.push_back(FormField::Text(key, text));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A JSON number as field text. Integral floats lose their fraction, so
+/// `400.0` reaches integer fields as `400`.
+fn number_text(n: &serde_json::Number) -> String {
+    match n.as_f64() {
+        Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() < 1e15 => format!("{}", f as i64),
+        _ => n.to_string(),
+    }
+}
+
+impl FormField<'_> {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            FormField::Multipart(field) => field.name(),
+            FormField::Text(name, _) | FormField::File(name, _) => Some(name),
+        }
+    }
+
+    /// Client-declared `Content-Type`; only multipart parts carry one.
+    pub fn content_type(&self) -> Option<&str> {
+        match self {
+            FormField::Multipart(field) => field.content_type(),
+            _ => None,
+        }
+    }
+
+    /// Client-supplied filename; only multipart parts carry one.
+    pub fn file_name(&self) -> Option<&str> {
+        match self {
+            FormField::Multipart(field) => field.file_name(),
+            _ => None,
+        }
+    }
+
+    pub async fn text(self) -> Result<String, MultipartError> {
+        match self {
+            FormField::Multipart(field) => field.text().await,
+            FormField::Text(_, value) => Ok(value),
+            FormField::File(_, bytes) => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+
+    pub async fn bytes(self) -> Result<Bytes, MultipartError> {
+        match self {
+            FormField::Multipart(field) => field.bytes().await,
+            FormField::Text(_, value) => Ok(Bytes::from(value)),
+            FormField::File(_, bytes) => Ok(bytes),
+        }
+    }
+}
+
+/// Name a handler should match a field on: `file` for any of `file_names`,
+/// since clients and form libraries call the image part different things,
+/// and the field's own name otherwise.
+pub fn field_name(name: Option<&str>, file_names: &[String]) -> String {
+    match name.unwrap_or("") {
+        name if file_names.iter().any(|f| f == name) => "file".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Whether `name` is a field carrying file bytes rather than text.
+fn is_file_field(name: &str, file_names: &[String]) -> bool {
+    matches!(name, "file" | "watermark") || file_names.iter().any(|f| f == name)
+}
+
+/// Parses a boolean field value. Accepts `true`/`false`, `1`/`0` and
+/// `yes`/`no` in any case; anything else is `None` so the caller can reject
+/// it instead of guessing.
+pub fn parse_bool(val: &str) -> Option<bool> {
+    match val.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Some(true),
+        "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bool_accepts_common_spellings() {
+        for val in ["true", "TRUE", "1", "yes", " Yes "] {
+            assert_eq!(parse_bool(val), Some(true), "{}", val);
+        }
+        for val in ["false", "False", "0", "no", "NO"] {
+            assert_eq!(parse_bool(val), Some(false), "{}", val);
+        }
+        for val in ["", "flase", "on", "2", "y"] {
+            assert_eq!(parse_bool(val), None, "{}", val);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_options_numbers_become_field_text() {
+        let mut form = FormFields::new(
+            Source::Raw(Vec::new().into_iter()),
+            FieldLimits::default(),
+            Vec::new(),
+        );
+        form.queue_options(r#"{"width": 400.0, "quality": 82.5, "height": 300}"#)
+            .unwrap();
+
+        let mut fields = Vec::new();
+        while let Some(field) = form.next_field().await.unwrap() {
+            if let FormField::Text(name, value) = field {
+                fields.push((name, value));
+            }
+        }
+        fields.sort();
+        assert_eq!(
+            fields,
+            [
+                ("height".to_string(), "300".to_string()),
+                ("quality".to_string(), "82.5".to_string()),
+                ("width".to_string(), "400".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_budget_counts_fields_and_waiting_time() {
+        let limits = FieldLimits {
+            max_fields: 2,
+            parse_time: Duration::from_millis(50),
+        };
+        let mut budget = FieldBudget::new(limits);
+        for _ in 0..2 {
+            let field = budget.next(async { Ok(Some(())) }).await;
+            assert!(matches!(field, Ok(Some(()))));
+        }
+        let field = budget.next(async { Ok(Some(())) }).await;
+        assert!(matches!(field, Err(FieldError::TooMany { limit: 2 })));
+
+        let mut budget = FieldBudget::new(limits);
+        let stalled = std::future::pending::<Result<Option<()>, MultipartError>>();
+        let field = budget.next(stalled).await;
+        assert!(matches!(field, Err(FieldError::TooSlow { .. })));
+        // The budget is spent, so even a ready field is refused now
+        let field = budget.next(async { Ok(Some(())) }).await;
+        assert!(matches!(field, Err(FieldError::TooSlow { .. })));
+    }
+}
@@ -1,22 +1,50 @@
-use axum::{http::StatusCode, response::Json};
+use axum::{extract::State, http::StatusCode, response::Json};
 use serde::Serialize;
+use std::sync::atomic::Ordering;
 use std::time::SystemTime;
 
+use crate::pool::Lane;
+use crate::state::AppState;
+
 #[derive(Serialize)]
 pub struct HealthResponse {
     status: String,
     version: String,
     uptime_seconds: u64,
+    /// Conversions queued or running right now.
+    in_flight: usize,
+    /// Conversions finished since startup, successful or not.
+    total_processed: u64,
 }
 
 #[derive(Serialize)]
 pub struct ReadyResponse {
     ready: bool,
+    checks: Vec<ReadyCheck>,
+}
+
+/// One dependency `/ready` depends on. `detail` says why a check failed.
+#[derive(Serialize)]
+pub struct ReadyCheck {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl ReadyCheck {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(()) => (true, None),
+            Err(detail) => (false, Some(detail)),
+        };
+        Self { name, ok, detail }
+    }
 }
 
 static START_TIME: std::sync::OnceLock<SystemTime> = std::sync::OnceLock::new();
 
-pub async fn health_check() -> (StatusCode, Json<HealthResponse>) {
+pub async fn health_check(State(state): State<AppState>) -> (StatusCode, Json<HealthResponse>) {
     let start = START_TIME.get_or_init(SystemTime::now);
     let uptime = SystemTime::now()
         .duration_since(*start)
@@ -27,11 +55,62 @@ pub async fn health_check() -> (StatusCode, Json<HealthResponse>) {
         status: "ok".to_string(),
         version: env!("CARGO_PKG_VERSION").to_string(),
         uptime_seconds: uptime,
+        in_flight: state.pools.active(),
+        total_processed: state.pools.processed(),
     };
 
     (StatusCode::OK, Json(response))
 }
 
-pub async fn ready_check() -> (StatusCode, Json<ReadyResponse>) {
-    (StatusCode::OK, Json(ReadyResponse { ready: true }))
+/// Readiness probe: 200 only while every check passes, so load balancers
+/// stop routing here during startup, shutdown and degradation.
+///
+/// - `encoders`: the startup self-test has passed and shutdown hasn't begun.
+/// - `worker_pool`: at least one conversion slot is free.
+/// - `disk_cache`: the cache directory is writable. Only present when the
+///   disk cache is enabled.
+pub async fn ready_check(State(state): State<AppState>) -> (StatusCode, Json<ReadyResponse>) {
+    let mut checks = vec![
+        ReadyCheck::new(
+            "encoders",
+            if state.ready.load(Ordering::Acquire) {
+                Ok(())
+            } else {
+                Err("self-test not passed yet, or shutting down".to_string())
+            },
+        ),
+        ReadyCheck::new(
+            "worker_pool",
+            if state.pools.available(Lane::Light) + state.pools.available(Lane::Heavy) > 0 {
+                Ok(())
+            } else {
+                Err(format!(
+                    "all slots busy, {} jobs queued or running",
+                    state.pools.active()
+                ))
+            },
+        ),
+    ];
+    if let Some(cache) = &state.disk_cache {
+        checks.push(ReadyCheck::new(
+            "disk_cache",
+            cache.check_writable().await.map_err(|e| e.to_string()),
+        ));
+    }
+
+    let ready = checks.iter().all(|check| check.ok);
+    if !ready {
+        let failing: Vec<_> = checks
+            .iter()
+            .filter(|check| !check.ok)
+            .map(|check| check.name)
+            .collect();
+        tracing::debug!(?failing, "Readiness check failed");
+    }
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadyResponse { ready, checks }))
 }
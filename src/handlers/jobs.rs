@@ -0,0 +1,162 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use tracing::Instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::jobs::{JobResult, JobStatus, Submission};
+use crate::middleware::auth::ClientLabel;
+use crate::request_id::{self, RequestId};
+use crate::state::AppState;
+
+/// Body of a `202 Accepted`, both when a job is queued and while it runs.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct JobPending {
+    pub job_id: String,
+    pub status: &'static str,
+}
+
+/// Runs `conversion` in the background and answers `202` with the job's id
+/// straight away. The conversion's response, whatever its status, is
+/// buffered into the job store for `GET /jobs/{id}` to hand out to `owner`,
+/// the label of the client that submitted it. A submission with the same
+/// `key` as a pending job joins it instead, so it's converted once.
+pub fn spawn_job<F>(
+    state: &AppState,
+    request_id: RequestId,
+    owner: Arc<str>,
+    key: [u8; 32],
+    conversion: F,
+) -> Response
+where
+    F: Future<Output = Response> + Send + 'static,
+{
+    if state.config.max_jobs == 0 {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            "async jobs are disabled on this server",
+            request_id,
+        )
+        .into_response();
+    }
+    let id = match state.jobs.submit(owner, key) {
+        Some(Submission::New(id)) => id,
+        Some(Submission::Joined(id)) => {
+            tracing::info!(%request_id, job_id = %id, "Joined identical pending job");
+            return pending(id, request_id);
+        }
+        None => {
+            tracing::warn!(%request_id, "Job store full of pending jobs");
+            return ApiError::new(
+                ErrorCode::TooManyJobs,
+                "too many jobs in progress; retry later",
+                request_id,
+            )
+            .into_response();
+        }
+    };
+    tracing::info!(%request_id, job_id = %id, "Queued background conversion");
+
+    let jobs = state.jobs.clone();
+    tokio::spawn(
+        async move {
+            let max_bytes = jobs.max_bytes();
+            let result = match buffer(conversion.await, max_bytes).await {
+                Ok(result) => result,
+                Err(e) => {
+                    tracing::warn!(%request_id, job_id = %id, error = %e, "Job result not kept");
+                    let error = ApiError::new(
+                        ErrorCode::OutputTooLarge,
+                        format!("job result exceeds the {} bytes a job may keep", max_bytes),
+                        request_id,
+                    );
+                    // An error body is a few hundred bytes, far below any budget
+                    buffer(error.into_response(), usize::MAX)
+                        .await
+                        .expect("error bodies buffer")
+                }
+            };
+            tracing::info!(%request_id, job_id = %id, status = %result.status, "Background conversion finished");
+            jobs.complete(id, result);
+        }
+        .instrument(tracing::Span::current()),
+    );
+
+    pending(id, request_id)
+}
+
+/// Reads a response's body into memory, failing past `limit` bytes.
+async fn buffer(response: Response, limit: usize) -> Result<JobResult, axum::Error> {
+    let (parts, body) = response.into_parts();
+    Ok(JobResult {
+        status: parts.status,
+        headers: parts.headers,
+        body: axum::body::to_bytes(body, limit).await?,
+    })
+}
+
+/// `GET /jobs/{id}`: `202` while the job runs, then the conversion's own
+/// response (the image and its headers, or its error) until `JOB_TTL_SECS`
+/// after it finished. Only the client that submitted a job can see it;
+/// for others it doesn't exist.
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "`job_id` from the `202` that queued the job")),
+    responses(
+        (status = 200, description = "Finished: the conversion's own response, headers included (errors keep their status)"),
+        (status = 202, description = "Still running", body = JobPending),
+        (status = 401, description = "Missing or invalid bearer token"),
+        (status = 404, description = "Unknown, expired or another client's job", body = crate::handlers::error::ErrorBody),
+    ),
+    security(("bearer" = [])),
+)]
+pub async fn job_status(
+    State(state): State<AppState>,
+    client: Option<Extension<ClientLabel>>,
+    request_id: RequestId,
+    Path(id): Path<String>,
+) -> Response {
+    let client: Arc<str> = client.map_or_else(|| "unknown".into(), |Extension(label)| label.0);
+
+    let Some((id, status)) = Uuid::parse_str(&id)
+        .ok()
+        .and_then(|id| Some((id, state.jobs.get(&id, &client)?)))
+    else {
+        return ApiError::new(
+            ErrorCode::JobNotFound,
+            "job not found; it may have expired",
+            request_id,
+        )
+        .into_response();
+    };
+    match status {
+        JobStatus::Pending => pending(id, request_id),
+        JobStatus::Done(result) => (result.status, result.headers, result.body).into_response(),
+    }
+}
+
+fn pending(id: Uuid, request_id: RequestId) -> Response {
+    let location = format!("/jobs/{}", id);
+    let body = JobPending {
+        job_id: id.to_string(),
+        status: "pending",
+    };
+    (
+        StatusCode::ACCEPTED,
+        [
+            ("Location", location.parse().unwrap()),
+            (request_id::HEADER, request_id.header_value()),
+        ],
+        Json(body),
+    )
+        .into_response()
+}
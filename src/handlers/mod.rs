@@ -1,2 +1,9 @@
 pub mod convert;
+pub mod error;
+pub mod form;
 pub mod health;
+pub mod jobs;
+pub mod openapi;
+pub mod quality_scan;
+pub mod shrink;
+pub mod srcset;
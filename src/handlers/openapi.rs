@@ -0,0 +1,246 @@
+use axum::Json;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::handlers::convert::{
+    DryRunResult, FormatSize, FormatVariant, SizeSummary, VariantsResult,
+};
+use crate::handlers::error::{ErrorBody, ErrorDetail};
+use crate::handlers::jobs::JobPending;
+
+/// OpenAPI 3 description of the API, served unauthenticated at
+/// `GET /openapi.json` for client generators. Paths come from the
+/// `#[utoipa::path]` annotations on the handlers and schemas from the
+/// response types themselves.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        crate::handlers::convert::convert_image,
+        crate::handlers::jobs::job_status,
+    ),
+    components(schemas(
+        ConvertForm,
+        JobPending,
+        DryRunResult,
+        VariantsResult,
+        FormatVariant,
+        SizeSummary,
+        FormatSize,
+        ErrorBody,
+        ErrorDetail,
+    )),
+    modifiers(&BearerAuth),
+)]
+pub struct ApiDoc;
+
+/// Registers the `bearer` scheme the handlers' `security` entries refer to.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// Multipart fields `POST /convert` reads. The handler parses the form field
+/// by field, so this struct only describes it; a test checks it against the
+/// field names matched in `convert`. Constraints and interactions are in
+/// docs/convert.md.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct ConvertForm {
+    /// Source image.
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+    /// JSON object of any other fields, validated as if sent separately.
+    options: Option<String>,
+    /// Output format: `webp`, `avif`, `png`, `jpeg` or `auto`. Defaults to
+    /// the server's `DEFAULT_FORMAT`, normally `webp`.
+    format: Option<String>,
+    /// Comma-separated formats to encode in one call; the response is JSON.
+    formats: Option<String>,
+    /// Encoder quality.
+    #[schema(minimum = 1, maximum = 100)]
+    quality: Option<f32>,
+    /// Target width in pixels.
+    #[schema(minimum = 1)]
+    width: Option<u32>,
+    /// Target height in pixels.
+    #[schema(minimum = 1)]
+    height: Option<u32>,
+    /// Resize behavior when both sides are set: `fill`, `pad` or `cover`.
+    fit: Option<String>,
+    /// What `cover` keeps: a compass direction, `center` or `smart`.
+    gravity: Option<String>,
+    /// Horizontal focal point for `cover`, 0–1.
+    focus_x: Option<f32>,
+    /// Vertical focal point for `cover`, 0–1.
+    focus_y: Option<f32>,
+    /// Device pixel ratio multiplying `width` and `height`.
+    #[schema(minimum = 1, maximum = 4)]
+    dpr: Option<f32>,
+    /// Multiple of the source size to resize to, instead of `width` and
+    /// `height`.
+    #[schema(maximum = 4)]
+    scale: Option<f32>,
+    /// Encode WebP/AVIF without loss.
+    lossless: Option<bool>,
+    /// WebP near-lossless preprocessing; 100 is off.
+    #[schema(maximum = 100)]
+    near_lossless: Option<u8>,
+    /// WebP alpha plane quality.
+    #[schema(maximum = 100)]
+    alpha_quality: Option<u8>,
+    /// Write a progressive JPEG.
+    progressive: Option<bool>,
+    /// JPEG chroma subsampling: `420`, `422` or `444`.
+    chroma: Option<String>,
+    /// AVIF encoder speed; lower is smaller and slower.
+    #[schema(minimum = 1, maximum = 10)]
+    speed: Option<u8>,
+    /// Named quality and size preset: `thumbnail`, `web`, `high`, `lossless`.
+    preset: Option<String>,
+    /// Only change the format, at the source dimensions.
+    transcode: Option<bool>,
+    /// Reject a `file` whose `Content-Type` disagrees with its bytes.
+    strict_content_type: Option<bool>,
+    /// Encoding timeout for this request, up to the server's maximum.
+    #[schema(minimum = 1)]
+    timeout_ms: Option<u64>,
+    /// Time the conversion should finish in; picks the AVIF speed.
+    #[schema(minimum = 1)]
+    deadline_ms: Option<u64>,
+    /// AVIF tiling: `auto` (the encoder's default, across all cores) or
+    /// `off` (one thread).
+    tiling: Option<String>,
+    /// Clockwise rotation in degrees, a multiple of 90.
+    rotate: Option<i32>,
+    /// Mirroring: `h`, `v` or `none`.
+    flip: Option<String>,
+    /// Corner mask: `circle` or `rounded`.
+    mask: Option<String>,
+    /// Corner radius for `rounded`, in pixels.
+    #[schema(minimum = 1)]
+    radius: Option<u32>,
+    /// Gaussian blur sigma.
+    #[schema(maximum = 20)]
+    blur: Option<f32>,
+    /// Unsharp mask: `amount` or `amount,threshold`.
+    sharpen: Option<String>,
+    /// Crop away a uniform border.
+    trim: Option<bool>,
+    /// Per-channel tolerance for `trim`.
+    trim_tolerance: Option<u8>,
+    /// Brightness shift in percent, -100 to 100.
+    brightness: Option<f32>,
+    /// Contrast change in percent, -100 to 100.
+    contrast: Option<f32>,
+    /// Gamma; above 1 lightens midtones.
+    #[schema(minimum = 0.1, maximum = 3)]
+    gamma: Option<f32>,
+    /// Saturation change in percent, -100 to 100.
+    saturation: Option<f32>,
+    /// Desaturate after resizing.
+    grayscale: Option<bool>,
+    /// Color to multiply luminance by: `#RRGGBB`, `#RGB` or a name.
+    tint: Option<String>,
+    /// Overlay image.
+    #[schema(value_type = Option<String>, format = Binary)]
+    watermark: Option<Vec<u8>>,
+    /// Where the watermark goes: a compass direction or `center`.
+    watermark_gravity: Option<String>,
+    /// Watermark opacity, 0–1.
+    watermark_opacity: Option<f32>,
+    /// Multi-frame inputs: `reject` (default) or `frame` to take one.
+    animated: Option<String>,
+    /// Frame to extract from an animated input.
+    frame: Option<u32>,
+    /// Times an animated WebP output plays; 0 loops forever.
+    loop_count: Option<u16>,
+    /// Canvas color declared by an animated WebP output.
+    anim_background: Option<String>,
+    /// Downsample 16-bit sources to 8 bits per channel.
+    force_8bit: Option<bool>,
+    /// AVIF bit depth: `auto`, `8` or `10`.
+    bit_depth: Option<String>,
+    /// Color to flatten transparency onto.
+    background: Option<String>,
+    /// Copyright notice written to the output's EXIF.
+    copyright: Option<String>,
+    /// Author written to the output's EXIF.
+    author: Option<String>,
+    /// Description written to the output's EXIF.
+    description: Option<String>,
+    /// Store a JPEG preview in the output's EXIF.
+    embed_thumbnail: Option<bool>,
+    /// Print resolution written to the output.
+    #[schema(minimum = 1)]
+    dpi: Option<u16>,
+    /// Reject images that don't look like photographs.
+    require_photo: Option<bool>,
+    /// Lowest SSIM to keep; searches for the lowest quality that does.
+    min_ssim: Option<f64>,
+    /// Largest output size as a fraction of the input's.
+    target_ratio: Option<f32>,
+    /// Report the output's SSIM in `X-Quality-Score`.
+    score: Option<bool>,
+    /// Send `Content-Disposition: attachment`.
+    download: Option<bool>,
+    /// Respond with the output's size and dimensions instead of the image.
+    dry_run: Option<bool>,
+    /// Run as a background job: answer `202` with a `job_id` to poll at
+    /// `GET /jobs/{id}`. Also accepted as the `async` query parameter.
+    #[schema(rename = "async")]
+    async_job: Option<bool>,
+    /// AES-256-GCM key, 64 hex characters, when built with `encrypt`.
+    encryption_key: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use utoipa::openapi::{RefOr, Schema};
+
+    /// Names in the arms of `convert`'s field match, the only string arms
+    /// at that indentation in the file.
+    fn handled_fields() -> BTreeSet<String> {
+        include_str!("convert.rs")
+            .lines()
+            .filter(|line| line.starts_with("            \"") && line.contains("=>"))
+            .flat_map(|line| line.split("=>").next().unwrap().split('|'))
+            .map(|name| name.trim().trim_matches('"').to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_convert_form_lists_every_handled_field() {
+        let doc = ApiDoc::openapi();
+        let Some(RefOr::T(Schema::Object(form))) = doc
+            .components
+            .as_ref()
+            .and_then(|components| components.schemas.get("ConvertForm"))
+        else {
+            panic!("ConvertForm schema missing");
+        };
+        let documented: BTreeSet<String> = form.properties.keys().cloned().collect();
+        let handled = handled_fields();
+        assert!(handled.contains("quality") && handled.contains("async"));
+        assert_eq!(documented, handled);
+    }
+
+    #[test]
+    fn test_jobs_path_is_documented() {
+        let doc = ApiDoc::openapi();
+        assert!(doc.paths.paths.contains_key("/jobs/{id}"));
+    }
+}
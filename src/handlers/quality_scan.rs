@@ -0,0 +1,193 @@
+use axum::{
+    extract::{Multipart, State},
+    response::{IntoResponse, Json, Response},
+};
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::handlers::form::{field_name, FieldBudget};
+use crate::processor::{quality_scan as scan, OutputFormat, ProcessError, ProcessOptions};
+use crate::request_id::{self, RequestId};
+use crate::state::AppState;
+
+/// Most qualities one request may ask for; each one is a full encode.
+pub const MAX_SCAN_QUALITIES: usize = 10;
+
+/// Encoded size at one quality in a `/quality-scan` response.
+#[derive(Debug, Serialize)]
+pub struct QualityScanEntry {
+    pub quality: u8,
+    /// Encoded size in bytes.
+    pub bytes: usize,
+}
+
+/// Encodes one upload at several qualities and reports only the sizes, for
+/// tuning quality per asset. The image is decoded once and every encode runs
+/// in a single pool job under the usual encoding timeout.
+pub async fn quality_scan(
+    State(state): State<AppState>,
+    request_id: RequestId,
+    mut multipart: Multipart,
+) -> Response {
+    let mut fields = FieldBudget::new(state.config.field_limits);
+    let mut file_bytes: Option<Bytes> = None;
+    let mut qualities: Vec<u8> = Vec::new();
+    let mut format = OutputFormat::WebP;
+
+    loop {
+        let field = match fields.next(multipart.next_field()).await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!(%request_id, error = %e, "Multipart parsing error");
+                return e.api_error(request_id).into_response();
+            }
+        };
+
+        let name = field_name(field.name(), &state.config.file_field_names);
+
+        match name.as_str() {
+            "file" => match field.bytes().await {
+                Ok(bytes) => file_bytes = Some(bytes),
+                Err(e) => {
+                    tracing::warn!(%request_id, error = %e, "Failed to read file field");
+                    return ApiError::new(
+                        ErrorCode::InvalidMultipart,
+                        "Failed to read uploaded file",
+                        request_id,
+                    )
+                    .into_response();
+                }
+            },
+            "qualities" => {
+                if let Ok(val) = field.text().await {
+                    let parsed: Result<Vec<u8>, _> =
+                        val.split(',').map(|q| q.trim().parse::<u8>()).collect();
+                    match parsed {
+                        Ok(list) if list.iter().all(|q| (1..=100).contains(q)) => qualities = list,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::QualityOutOfRange,
+                                "qualities must be a comma-separated list of integers between 1 and 100",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "format" => {
+                if let Ok(val) = field.text().await {
+                    match val.to_lowercase().as_str() {
+                        "webp" => format = OutputFormat::WebP,
+                        "avif" => format = OutputFormat::Avif,
+                        "jpeg" | "jpg" => format = OutputFormat::Jpeg,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "format must be 'webp', 'avif' or 'jpeg'",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some(bytes) = file_bytes else {
+        tracing::warn!(%request_id, "Request missing required file field");
+        return ApiError::new(ErrorCode::MissingFile, "Missing file field", request_id)
+            .into_response();
+    };
+
+    if bytes.is_empty() {
+        tracing::warn!(%request_id, "Request carried an empty file");
+        return ApiError::new(ErrorCode::EmptyFile, "uploaded file is empty", request_id)
+            .into_response();
+    }
+
+    if !state.config.allowed_formats.contains(&format) {
+        return ApiError::new(
+            ErrorCode::FormatNotEnabled,
+            format!("format not enabled: {}", format.name()),
+            request_id,
+        )
+        .into_response();
+    }
+
+    qualities.sort_unstable();
+    qualities.dedup();
+    if qualities.is_empty() || qualities.len() > MAX_SCAN_QUALITIES {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            format!(
+                "qualities must list between 1 and {} values",
+                MAX_SCAN_QUALITIES
+            ),
+            request_id,
+        )
+        .into_response();
+    }
+
+    tracing::info!(
+        %request_id,
+        format = ?format,
+        ?qualities,
+        file_size = bytes.len(),
+        "Processing quality scan"
+    );
+
+    let options = ProcessOptions {
+        format,
+        png_compression: state.config.png_compression,
+        webp_lossless_effort: state.config.webp_lossless_effort,
+        decode_timeout: Some(state.config.decode_timeout),
+        limits: state.config.limits,
+        allowed_inputs: state.config.allowed_inputs.clone(),
+        ..Default::default()
+    };
+    let lane = state.pools.lane_for(bytes.len(), None, None);
+    let span = tracing::Span::current();
+    let job = state.pools.spawn(lane, move || {
+        span.in_scope(|| scan(&bytes, options, &qualities))
+    });
+
+    match timeout(state.config.encoding_timeout, job).await {
+        Ok(Ok(Ok(sizes))) => {
+            let entries: Vec<QualityScanEntry> = sizes
+                .into_iter()
+                .map(|(quality, bytes)| QualityScanEntry { quality, bytes })
+                .collect();
+            (
+                [(request_id::HEADER, request_id.header_value())],
+                Json(entries),
+            )
+                .into_response()
+        }
+        Ok(Ok(Err(e))) => {
+            tracing::warn!(%request_id, error = %e, "Quality scan failed");
+            match e.downcast_ref::<ProcessError>() {
+                Some(err) => ApiError::new(ErrorCode::from(err), err.to_string(), request_id),
+                None => ApiError::new(
+                    ErrorCode::DecodeFailed,
+                    "Image processing failed",
+                    request_id,
+                ),
+            }
+            .into_response()
+        }
+        Ok(Err(e)) => {
+            tracing::error!(%request_id, error = %e, "Task join error");
+            ApiError::new(ErrorCode::Internal, "Internal error", request_id).into_response()
+        }
+        Err(_) => {
+            tracing::error!(%request_id, "Quality scan timed out");
+            ApiError::new(ErrorCode::Timeout, "Processing timed out", request_id).into_response()
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use axum::{
+    extract::{Multipart, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use tokio::time::timeout;
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::handlers::form::{field_name, FieldBudget};
+use crate::processor::{
+    process_image, shrink_target, PngCompression, ProcessError, ProcessOptions, Warning,
+};
+use crate::request_id::{self, RequestId};
+use crate::state::AppState;
+
+/// SSIM the re-encoded image must keep against the input when the request
+/// doesn't set `min_ssim`.
+pub const DEFAULT_SHRINK_SSIM: f64 = 0.98;
+
+/// Re-encodes an upload in its own format at its own dimensions, as small as
+/// it gets while keeping `min_ssim`. Lossy inputs go through the same quality
+/// search as `/convert`'s `min_ssim`; lossless ones are recompressed at the
+/// highest PNG/WebP effort. An input that can't be beaten is sent back as is.
+pub async fn shrink(
+    State(state): State<AppState>,
+    request_id: RequestId,
+    mut multipart: Multipart,
+) -> Response {
+    let mut fields = FieldBudget::new(state.config.field_limits);
+    let mut file_bytes: Option<Bytes> = None;
+    let mut min_ssim = DEFAULT_SHRINK_SSIM;
+
+    loop {
+        let field = match fields.next(multipart.next_field()).await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!(%request_id, error = %e, "Multipart parsing error");
+                return e.api_error(request_id).into_response();
+            }
+        };
+
+        let name = field_name(field.name(), &state.config.file_field_names);
+
+        match name.as_str() {
+            "file" => match field.bytes().await {
+                Ok(bytes) => file_bytes = Some(bytes),
+                Err(e) => {
+                    tracing::warn!(%request_id, error = %e, "Failed to read file field");
+                    return ApiError::new(
+                        ErrorCode::InvalidMultipart,
+                        "Failed to read uploaded file",
+                        request_id,
+                    )
+                    .into_response();
+                }
+            },
+            "min_ssim" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f64>() {
+                        Ok(s) if s > 0.0 && s <= 1.0 => min_ssim = s,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "min_ssim must be a number above 0 and at most 1",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some(bytes) = file_bytes else {
+        tracing::warn!(%request_id, "Request missing required file field");
+        return ApiError::new(ErrorCode::MissingFile, "Missing file field", request_id)
+            .into_response();
+    };
+
+    if bytes.is_empty() {
+        tracing::warn!(%request_id, "Request carried an empty file");
+        return ApiError::new(ErrorCode::EmptyFile, "uploaded file is empty", request_id)
+            .into_response();
+    }
+
+    let Some((format, lossless)) = shrink_target(&bytes) else {
+        tracing::info!(%request_id, "Rejected shrink of a format that can't be written back");
+        return ApiError::new(
+            ErrorCode::UnsupportedFormat,
+            "only jpeg, png and webp inputs can be shrunk",
+            request_id,
+        )
+        .into_response();
+    };
+
+    if !state.config.allowed_formats.contains(&format) {
+        return ApiError::new(
+            ErrorCode::FormatNotEnabled,
+            format!("format not enabled: {}", format.name()),
+            request_id,
+        )
+        .into_response();
+    }
+
+    tracing::info!(
+        %request_id,
+        format = ?format,
+        lossless,
+        min_ssim,
+        file_size = bytes.len(),
+        "Processing shrink"
+    );
+
+    let encoding_timeout = state.config.encoding_timeout;
+    let options = ProcessOptions {
+        format,
+        lossless,
+        min_ssim: (!lossless).then_some(min_ssim),
+        // Leave room for the final fallback encode within the timeout
+        search_budget: Some(encoding_timeout * 3 / 4),
+        png_compression: PngCompression::Best,
+        webp_lossless_effort: state.config.webp_lossless_effort,
+        decode_timeout: Some(state.config.decode_timeout),
+        limits: state.config.limits,
+        allowed_inputs: state.config.allowed_inputs.clone(),
+        ..Default::default()
+    };
+    let lane = state.pools.lane_for(bytes.len(), None, None);
+    let span = tracing::Span::current();
+    let input = bytes.clone();
+    let job = state.pools.spawn(lane, move || {
+        span.in_scope(|| process_image(&input, options))
+    });
+
+    let processed = match timeout(encoding_timeout, job).await {
+        Ok(Ok(Ok(processed))) => processed,
+        Ok(Ok(Err(e))) => {
+            tracing::warn!(%request_id, error = %e, "Shrink failed");
+            return match e.downcast_ref::<ProcessError>() {
+                Some(err) => ApiError::new(ErrorCode::from(err), err.to_string(), request_id),
+                None => ApiError::new(
+                    ErrorCode::DecodeFailed,
+                    "Image processing failed",
+                    request_id,
+                ),
+            }
+            .into_response();
+        }
+        Ok(Err(e)) => {
+            tracing::error!(%request_id, error = %e, "Task join error");
+            return ApiError::new(ErrorCode::Internal, "Internal error", request_id)
+                .into_response();
+        }
+        Err(_) => {
+            tracing::error!(%request_id, "Shrink timed out");
+            return ApiError::new(ErrorCode::Timeout, "Processing timed out", request_id)
+                .into_response();
+        }
+    };
+
+    // Re-encoding an APNG as PNG would keep only its default frame
+    if processed.warnings.contains(&Warning::AnimationDropped) {
+        return ApiError::new(
+            ErrorCode::AnimatedInput,
+            "animated images can't be shrunk",
+            request_id,
+        )
+        .into_response();
+    }
+
+    let original_bytes = bytes.len();
+    let mut warnings = processed.warnings;
+    let body = if processed.bytes.len() < original_bytes {
+        processed.bytes
+    } else {
+        warnings.push(Warning::NoSavings);
+        bytes
+    };
+    tracing::info!(
+        %request_id,
+        original_bytes,
+        output_bytes = body.len(),
+        "Shrink finished"
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", format.mime().parse().unwrap());
+    headers.insert(request_id::HEADER, request_id.header_value());
+    headers.insert("X-Original-Bytes", original_bytes.into());
+    headers.insert("X-Image-Bytes", body.len().into());
+    if !warnings.contains(&Warning::NoSavings) {
+        if let Some(quality) = processed.searched_quality {
+            headers.insert("X-Quality", quality.into());
+        }
+        if let Some(score) = processed.quality_score {
+            headers.insert("X-Quality-Score", format!("{:.4}", score).parse().unwrap());
+        }
+    }
+    if !warnings.is_empty() {
+        let codes: Vec<&str> = warnings.iter().map(|w| w.code()).collect();
+        headers.insert("X-Warnings", codes.join(",").parse().unwrap());
+    }
+    (StatusCode::OK, headers, body).into_response()
+}
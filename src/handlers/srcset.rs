@@ -0,0 +1,238 @@
+use axum::{
+    extract::{Multipart, State},
+    response::{IntoResponse, Json, Response},
+};
+use base64::Engine;
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::handlers::form::{field_name, FieldBudget};
+use crate::processor::{process_image, OutputFormat, ProcessError, ProcessOptions};
+use crate::request_id::{self, RequestId};
+use crate::state::AppState;
+
+/// Most widths one request may ask for; each one is a full decode and encode.
+pub const MAX_SRCSET_WIDTHS: usize = 8;
+
+/// One encoded size in a `/srcset` response.
+#[derive(Debug, Serialize)]
+pub struct SrcsetEntry {
+    pub width: u32,
+    /// Encoded size in bytes.
+    pub bytes: usize,
+    pub base64: String,
+}
+
+/// Encodes one upload at several widths for `<img srcset>`. Each width is
+/// converted independently on the work pools, so a request competes for
+/// capacity like the equivalent number of `/convert` calls.
+pub async fn srcset(
+    State(state): State<AppState>,
+    request_id: RequestId,
+    mut multipart: Multipart,
+) -> Response {
+    let max_dimension = state.config.limits.max_side();
+
+    let mut fields = FieldBudget::new(state.config.field_limits);
+    let mut file_bytes: Option<Bytes> = None;
+    let mut widths: Vec<u32> = Vec::new();
+    let mut quality: Option<f32> = None;
+    let mut format = OutputFormat::WebP;
+
+    loop {
+        let field = match fields.next(multipart.next_field()).await {
+            Ok(Some(f)) => f,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::warn!(%request_id, error = %e, "Multipart parsing error");
+                return e.api_error(request_id).into_response();
+            }
+        };
+
+        let name = field_name(field.name(), &state.config.file_field_names);
+
+        match name.as_str() {
+            "file" => match field.bytes().await {
+                Ok(bytes) => file_bytes = Some(bytes),
+                Err(e) => {
+                    tracing::warn!(%request_id, error = %e, "Failed to read file field");
+                    return ApiError::new(
+                        ErrorCode::InvalidMultipart,
+                        "Failed to read uploaded file",
+                        request_id,
+                    )
+                    .into_response();
+                }
+            },
+            "widths" => {
+                if let Ok(val) = field.text().await {
+                    let parsed: Result<Vec<u32>, _> =
+                        val.split(',').map(|w| w.trim().parse::<u32>()).collect();
+                    match parsed {
+                        Ok(list) if list.iter().all(|&w| w > 0 && w <= max_dimension) => {
+                            widths = list
+                        }
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                format!(
+                                    "widths must be a comma-separated list of integers between 1 and {}",
+                                    max_dimension
+                                ),
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "quality" => {
+                if let Ok(val) = field.text().await {
+                    match val.parse::<f32>() {
+                        Ok(q) if (1.0..=100.0).contains(&q) => quality = Some(q),
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::QualityOutOfRange,
+                                "quality must be between 1 and 100",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            "format" => {
+                if let Ok(val) = field.text().await {
+                    match val.to_lowercase().as_str() {
+                        "webp" => format = OutputFormat::WebP,
+                        "avif" => format = OutputFormat::Avif,
+                        "png" => format = OutputFormat::Png,
+                        "jpeg" | "jpg" => format = OutputFormat::Jpeg,
+                        _ => {
+                            return ApiError::new(
+                                ErrorCode::InvalidParameter,
+                                "format must be 'webp', 'avif', 'png' or 'jpeg'",
+                                request_id,
+                            )
+                            .into_response()
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Some(bytes) = file_bytes else {
+        tracing::warn!(%request_id, "Request missing required file field");
+        return ApiError::new(ErrorCode::MissingFile, "Missing file field", request_id)
+            .into_response();
+    };
+
+    if bytes.is_empty() {
+        tracing::warn!(%request_id, "Request carried an empty file");
+        return ApiError::new(ErrorCode::EmptyFile, "uploaded file is empty", request_id)
+            .into_response();
+    }
+
+    if !state.config.allowed_formats.contains(&format) {
+        return ApiError::new(
+            ErrorCode::FormatNotEnabled,
+            format!("format not enabled: {}", format.name()),
+            request_id,
+        )
+        .into_response();
+    }
+
+    widths.sort_unstable();
+    widths.dedup();
+    if widths.is_empty() || widths.len() > MAX_SRCSET_WIDTHS {
+        return ApiError::new(
+            ErrorCode::InvalidParameter,
+            format!("widths must list between 1 and {} sizes", MAX_SRCSET_WIDTHS),
+            request_id,
+        )
+        .into_response();
+    }
+
+    let quality = quality.unwrap_or_else(|| state.config.default_quality.for_format(format));
+
+    tracing::info!(
+        %request_id,
+        format = ?format,
+        ?widths,
+        quality,
+        file_size = bytes.len(),
+        "Processing srcset"
+    );
+
+    let encoding_timeout = state.config.encoding_timeout;
+    let mut tasks = JoinSet::new();
+    for &width in &widths {
+        let options = ProcessOptions {
+            quality,
+            width: Some(width),
+            format,
+            png_compression: state.config.png_compression,
+            webp_lossless_effort: state.config.webp_lossless_effort,
+            decode_timeout: Some(state.config.decode_timeout),
+            limits: state.config.limits,
+            allowed_inputs: state.config.allowed_inputs.clone(),
+            ..Default::default()
+        };
+        let pools = state.pools.clone();
+        let bytes = bytes.clone();
+        let span = tracing::Span::current();
+        tasks.spawn(async move {
+            let lane = pools.lane_for(bytes.len(), Some(width), None);
+            let job = pools.spawn(lane, move || {
+                span.in_scope(|| process_image(&bytes, options))
+            });
+            (width, timeout(encoding_timeout, job).await)
+        });
+    }
+
+    let mut entries = Vec::with_capacity(widths.len());
+    while let Some(joined) = tasks.join_next().await {
+        // Returning early drops the set, which aborts the remaining widths
+        match joined {
+            Ok((width, Ok(Ok(Ok(processed))))) => entries.push(SrcsetEntry {
+                width,
+                bytes: processed.bytes.len(),
+                base64: base64::engine::general_purpose::STANDARD.encode(&processed.bytes),
+            }),
+            Ok((width, Ok(Ok(Err(e))))) => {
+                tracing::warn!(%request_id, width, error = %e, "Srcset conversion failed");
+                return match e.downcast_ref::<ProcessError>() {
+                    Some(err) => ApiError::new(ErrorCode::from(err), err.to_string(), request_id),
+                    None => ApiError::new(
+                        ErrorCode::DecodeFailed,
+                        "Image processing failed",
+                        request_id,
+                    ),
+                }
+                .into_response();
+            }
+            Ok((width, Err(_))) => {
+                tracing::error!(%request_id, width, "Srcset encoding timed out");
+                return ApiError::new(ErrorCode::Timeout, "Processing timed out", request_id)
+                    .into_response();
+            }
+            Ok((_, Ok(Err(e)))) | Err(e) => {
+                tracing::error!(%request_id, error = %e, "Task join error");
+                return ApiError::new(ErrorCode::Internal, "Internal error", request_id)
+                    .into_response();
+            }
+        }
+    }
+    entries.sort_unstable_by_key(|entry| entry.width);
+
+    (
+        [(request_id::HEADER, request_id.header_value())],
+        Json(entries),
+    )
+        .into_response()
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Coalesces concurrent identical work. The first caller for a key runs the
+/// job; callers arriving while it is still running await the same result
+/// instead of repeating it. Entries are dropped as soon as the job finishes,
+/// so this is not a cache.
+pub struct InFlight<K, V> {
+    pending: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> InFlight<K, V> {
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `job` unless an identical one is already in flight. Returns the
+    /// value and whether this caller was the one that ran the job.
+    pub async fn run<F, Fut>(&self, key: K, job: F) -> (V, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = self
+            .pending
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(OnceCell::new()))
+            .clone();
+
+        // If the leader is cancelled mid-job, the next waiter runs `job` itself
+        let mut leader = false;
+        let value = cell
+            .get_or_init(|| {
+                leader = true;
+                job()
+            })
+            .await
+            .clone();
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            pending.remove(&key);
+        }
+        (value, leader)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Default for InFlight<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_identical_jobs_run_once() {
+        let inflight: InFlight<&str, u32> = InFlight::new();
+        let runs = AtomicUsize::new(0);
+        let job = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            42
+        };
+
+        let (a, b) = tokio::join!(inflight.run("same", job), inflight.run("same", job));
+        assert_eq!(a.0, 42);
+        assert_eq!(b.0, 42);
+        assert!(a.1 ^ b.1, "exactly one caller should lead");
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // Finished jobs are forgotten, so a later call runs again
+        inflight.run("same", job).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_different_keys_run_separately() {
+        let inflight: InFlight<&str, u32> = InFlight::new();
+        let runs = AtomicUsize::new(0);
+        let job = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            1
+        };
+
+        tokio::join!(inflight.run("a", job), inflight.run("b", job));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
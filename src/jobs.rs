@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::http::{HeaderMap, StatusCode};
+use bytes::Bytes;
+use uuid::Uuid;
+
+/// How often the background task sweeps expired jobs.
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A finished job's response, buffered so it can be fetched more than once.
+#[derive(Clone)]
+pub struct JobResult {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Outcome of `JobStore::submit`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Submission {
+    /// A new job; the caller runs the conversion.
+    New(Uuid),
+    /// An identical job is already pending; the caller was added to its
+    /// owners and nothing needs to run.
+    Joined(Uuid),
+}
+
+/// What `GET /jobs/{id}` reports.
+pub enum JobStatus {
+    Pending,
+    Done(JobResult),
+}
+
+struct Entry {
+    /// Labels of the clients that submitted the job; only they may fetch it.
+    owners: Vec<Arc<str>>,
+    /// The submission's job key, so it leaves `pending_keys` on completion.
+    key: [u8; 32],
+    result: Option<JobResult>,
+    /// When the job finished; the TTL runs from here. Pending jobs don't
+    /// expire, since the encoding timeout already bounds them.
+    finished: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Inner {
+    jobs: HashMap<Uuid, Entry>,
+    /// Job keys of the pending jobs, so duplicates join them.
+    pending_keys: HashMap<[u8; 32], Uuid>,
+    /// Body bytes of the finished jobs held.
+    total_bytes: usize,
+}
+
+/// Background conversions started with `POST /convert?async=true`, bounded
+/// by count and by the bytes of the results held. Results stay fetchable
+/// until `ttl` after they finish; the oldest finished jobs make room when
+/// either bound is reached, and with every slot still pending new jobs are
+/// refused. Identical submissions made while a job is pending share it.
+pub struct JobStore {
+    max_jobs: usize,
+    max_bytes: usize,
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl JobStore {
+    pub fn new(max_jobs: usize, max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            max_jobs,
+            max_bytes,
+            ttl,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Largest result a job may leave behind.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Registers a pending job for `owner` under `key`, or joins the
+    /// pending job with the same key. `None` when a new job is needed and
+    /// every slot is taken by a job that hasn't finished.
+    pub fn submit(&self, owner: Arc<str>, key: [u8; 32]) -> Option<Submission> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(&id) = inner.pending_keys.get(&key) {
+            if let Some(entry) = inner.jobs.get_mut(&id) {
+                if !entry.owners.contains(&owner) {
+                    entry.owners.push(owner);
+                }
+                return Some(Submission::Joined(id));
+            }
+        }
+        self.evict_locked(&mut inner);
+        if inner.jobs.len() >= self.max_jobs && !remove_oldest_finished(&mut inner) {
+            return None;
+        }
+        let id = Uuid::new_v4();
+        inner.jobs.insert(
+            id,
+            Entry {
+                owners: vec![owner],
+                key,
+                result: None,
+                finished: None,
+            },
+        );
+        inner.pending_keys.insert(key, id);
+        Some(Submission::New(id))
+    }
+
+    /// Stores a job's response, dropping the oldest finished jobs until it
+    /// fits the byte budget. Callers keep results within `max_bytes`. A job
+    /// evicted in the meantime stays gone.
+    pub fn complete(&self, id: Uuid, result: JobResult) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(key) = inner.jobs.get(&id).map(|entry| entry.key) else {
+            return;
+        };
+        inner.pending_keys.remove(&key);
+        let size = result.body.len();
+        while inner.total_bytes + size > self.max_bytes && remove_oldest_finished(&mut inner) {}
+        if let Some(entry) = inner.jobs.get_mut(&id) {
+            entry.result = Some(result);
+            entry.finished = Some(Instant::now());
+            inner.total_bytes += size;
+        }
+    }
+
+    /// `None` for ids never issued, already expired, or not submitted by
+    /// `owner`.
+    pub fn get(&self, id: &Uuid, owner: &str) -> Option<JobStatus> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner.jobs.get(id).filter(|entry| {
+            entry.owners.iter().any(|o| &**o == owner) && !self.is_expired(entry)
+        })?;
+        Some(match &entry.result {
+            Some(result) => JobStatus::Done(result.clone()),
+            None => JobStatus::Pending,
+        })
+    }
+
+    /// Drops expired jobs. Runs on every submit and periodically from the
+    /// background sweep.
+    pub fn evict(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.evict_locked(&mut inner);
+    }
+
+    /// Jobs currently held, pending or finished.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Body bytes of the finished results held.
+    pub fn total_bytes(&self) -> usize {
+        self.inner.lock().unwrap().total_bytes
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        entry
+            .finished
+            .is_some_and(|finished| finished.elapsed() >= self.ttl)
+    }
+
+    fn evict_locked(&self, inner: &mut Inner) {
+        let before = inner.jobs.len();
+        let mut freed = 0;
+        inner.jobs.retain(|_, entry| {
+            let keep = !self.is_expired(entry);
+            if !keep {
+                freed += entry.result.as_ref().map_or(0, |r| r.body.len());
+            }
+            keep
+        });
+        inner.total_bytes -= freed;
+        if inner.jobs.len() < before {
+            tracing::debug!(expired = before - inner.jobs.len(), "Jobs expired");
+        }
+    }
+}
+
+/// Drops the job that finished longest ago. `false` when none has finished.
+fn remove_oldest_finished(inner: &mut Inner) -> bool {
+    let Some(oldest) = inner
+        .jobs
+        .iter()
+        .filter_map(|(id, entry)| Some((*id, entry.finished?)))
+        .min_by_key(|(_, finished)| *finished)
+        .map(|(id, _)| id)
+    else {
+        return false;
+    };
+    if let Some(entry) = inner.jobs.remove(&oldest) {
+        inner.total_bytes -= entry.result.map_or(0, |r| r.body.len());
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(len: usize) -> JobResult {
+        JobResult {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from(vec![0u8; len]),
+        }
+    }
+
+    fn owner() -> Arc<str> {
+        "token1".into()
+    }
+
+    fn key(n: u8) -> [u8; 32] {
+        [n; 32]
+    }
+
+    fn submit(store: &JobStore, n: u8) -> Uuid {
+        match store.submit(owner(), key(n)) {
+            Some(Submission::New(id)) => id,
+            other => panic!("expected a new job, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_job_goes_from_pending_to_done() {
+        let store = JobStore::new(4, 1000, Duration::from_secs(60));
+        let id = submit(&store, 1);
+        assert!(matches!(store.get(&id, "token1"), Some(JobStatus::Pending)));
+
+        store.complete(id, result(4));
+        match store.get(&id, "token1") {
+            Some(JobStatus::Done(result)) => assert_eq!(result.body.len(), 4),
+            _ => panic!("job should be done"),
+        }
+        assert!(store.get(&Uuid::new_v4(), "token1").is_none());
+    }
+
+    #[test]
+    fn test_other_clients_cannot_see_a_job() {
+        let store = JobStore::new(4, 1000, Duration::from_secs(60));
+        let id = submit(&store, 2);
+        store.complete(id, result(4));
+        assert!(store.get(&id, "token2").is_none());
+        assert!(store.get(&id, "token1").is_some());
+    }
+
+    #[test]
+    fn test_identical_pending_submissions_share_a_job() {
+        let store = JobStore::new(4, 1000, Duration::from_secs(60));
+        let id = submit(&store, 1);
+        assert_eq!(
+            store.submit("token2".into(), key(1)),
+            Some(Submission::Joined(id))
+        );
+        assert_eq!(store.len(), 1);
+
+        store.complete(id, result(4));
+        assert!(store.get(&id, "token1").is_some());
+        assert!(store.get(&id, "token2").is_some());
+        // Once finished, the same key starts a fresh job
+        assert_ne!(submit(&store, 1), id);
+    }
+
+    #[test]
+    fn test_full_store_evicts_oldest_finished_job() {
+        let store = JobStore::new(2, 1000, Duration::from_secs(60));
+        let first = submit(&store, 3);
+        let second = submit(&store, 4);
+        // Both slots pending: nothing can be evicted
+        assert!(store.submit(owner(), key(99)).is_none());
+
+        store.complete(first, result(4));
+        let third = submit(&store, 5);
+        assert!(store.get(&first, "token1").is_none());
+        assert!(store.get(&second, "token1").is_some());
+        assert!(store.get(&third, "token1").is_some());
+        assert_eq!(store.total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_results_past_byte_budget_evict_oldest() {
+        let store = JobStore::new(8, 250, Duration::from_secs(60));
+        let ids: Vec<Uuid> = (0..3).map(|n| submit(&store, n)).collect();
+        for id in &ids {
+            store.complete(*id, result(100));
+        }
+        assert_eq!(store.total_bytes(), 200);
+        assert!(store.get(&ids[0], "token1").is_none());
+        assert!(store.get(&ids[1], "token1").is_some());
+        assert!(store.get(&ids[2], "token1").is_some());
+    }
+
+    #[test]
+    fn test_finished_jobs_expire() {
+        let store = JobStore::new(4, 1000, Duration::ZERO);
+        let pending = submit(&store, 7);
+        let done = submit(&store, 8);
+        store.complete(done, result(4));
+        assert!(store.get(&done, "token1").is_none());
+
+        store.evict();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.total_bytes(), 0);
+        assert!(store.get(&pending, "token1").is_some());
+    }
+}
@@ -1,4 +1,17 @@
+pub mod cache;
+pub mod color;
+pub mod config;
+#[cfg(feature = "encrypt")]
+pub mod crypto;
+pub mod disk_cache;
 pub mod handlers;
+pub mod inflight;
+pub mod jobs;
 pub mod middleware;
+pub mod pool;
 pub mod processor;
+pub mod request_id;
 pub mod server;
+pub mod state;
+#[cfg(feature = "otel")]
+pub mod telemetry;
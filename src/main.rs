@@ -1,26 +1,64 @@
 use dotenvy::dotenv;
 use std::env;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use std::net::SocketAddr;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
+use imgopt::config::Config;
+use imgopt::middleware::auth::parse_tokens;
+use imgopt::pool::blocking_threads;
+use imgopt::processor::OutputFormat;
 use imgopt::server;
 
 #[cfg(target_os = "linux")]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
-#[tokio::main]
-async fn main() {
+fn main() {
     dotenv().ok();
 
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer().json())
-        .init();
+    // Startup runs before the async runtime exists; the full subscriber,
+    // which may need the runtime for trace export, replaces this one below
+    let startup_logs = tracing::subscriber::set_default(subscriber());
 
-    // Fail fast: API_TOKEN must be set and non-empty before accepting any traffic
+    let log_format = env::var("LOG_FORMAT").unwrap_or_else(|_| "json".into());
+    if !matches!(
+        log_format.to_ascii_lowercase().as_str(),
+        "json" | "pretty" | "compact"
+    ) {
+        tracing::warn!(value = %log_format, "Unknown LOG_FORMAT, using json");
+    }
+
+    // Unlike most settings, a bad DEFAULT_FORMAT doesn't fall back: it would
+    // quietly change the format of every response that doesn't pick one
+    if let Ok(raw) = env::var("DEFAULT_FORMAT") {
+        if let Err(e) = raw.parse::<OutputFormat>() {
+            tracing::error!(value = %raw, error = %e, "DEFAULT_FORMAT is not a known format");
+            std::process::exit(1);
+        }
+    }
+
+    let config = Config::from_env();
+
+    // Fail fast: API_TOKENS or API_TOKEN must be set and non-empty before accepting any
+    // traffic, unless authentication was switched off on purpose
+    let has_token_list = match env::var("API_TOKENS").map(|raw| parse_tokens(&raw)) {
+        Ok(Ok(tokens)) => !tokens.is_empty(),
+        Ok(Err(e)) => {
+            tracing::error!(error = %e, "API_TOKENS is malformed");
+            std::process::exit(1);
+        }
+        Err(_) => false,
+    };
     match env::var("API_TOKEN") {
+        _ if config.auth_disabled => {
+            tracing::warn!(
+                "AUTH_DISABLED is set: every request is accepted without a token. \
+                 Only run this way behind something that authenticates callers."
+            );
+        }
+        _ if has_token_list => {}
         Err(_) => {
             tracing::error!("API_TOKEN environment variable is required but not set");
             std::process::exit(1);
@@ -32,8 +70,36 @@ async fn main() {
         Ok(_) => {}
     }
 
-    let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-    let addr = format!("0.0.0.0:{}", port);
+    // Only an explicit choice is held to ALLOWED_FORMATS; the built-in WebP
+    // default just fails per request when WebP is disabled, as it always has
+    if env::var("DEFAULT_FORMAT").is_ok()
+        && !config.allowed_formats.contains(&config.default_format)
+    {
+        tracing::error!(
+            format = config.default_format.name(),
+            "DEFAULT_FORMAT must be one of ALLOWED_FORMATS"
+        );
+        std::process::exit(1);
+    }
+
+    // BIND_ADDRESS (a full socket address) wins over PORT, which binds all IPv4 interfaces
+    let addr = match env::var("BIND_ADDRESS") {
+        Ok(raw) => match raw.parse::<SocketAddr>() {
+            Ok(addr) => addr.to_string(),
+            Err(e) => {
+                tracing::error!(
+                    value = %raw,
+                    error = %e,
+                    "BIND_ADDRESS must be a socket address such as 127.0.0.1:8080 or [::]:3000"
+                );
+                std::process::exit(1);
+            }
+        },
+        Err(_) => {
+            let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
+            format!("0.0.0.0:{}", port)
+        }
+    };
 
     tracing::info!(
         addr = %addr,
@@ -41,10 +107,65 @@ async fn main() {
         "Starting imgopt server"
     );
 
-    if let Err(e) = server::start(&addr).await {
+    // Conversions only ever occupy as many blocking threads as the two pools
+    // allow, so the runtime needs no more than that
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .max_blocking_threads(blocking_threads(
+            config.light_pool_size,
+            config.heavy_pool_size,
+        ))
+        .build()
+        .unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Failed to start the async runtime");
+            std::process::exit(1);
+        });
+
+    drop(startup_logs);
+    let _runtime_context = runtime.enter();
+    init_tracing();
+
+    let result = runtime.block_on(server::start(&addr, config));
+    #[cfg(feature = "otel")]
+    imgopt::telemetry::shutdown();
+    if let Err(e) = result {
         tracing::error!(error = %e, "Server terminated with error");
         std::process::exit(1);
     }
 
     tracing::info!("Server shut down cleanly");
 }
+
+/// Log filtering and formatting from `RUST_LOG` and `LOG_FORMAT`.
+fn subscriber() -> impl Subscriber + for<'a> LookupSpan<'a> + Send + Sync {
+    // JSON for log shippers; pretty/compact are easier to read in a terminal
+    let log_format = env::var("LOG_FORMAT").unwrap_or_else(|_| "json".into());
+    let fmt_layer = match log_format.to_ascii_lowercase().as_str() {
+        "pretty" => tracing_subscriber::fmt::layer().pretty().boxed(),
+        "compact" => tracing_subscriber::fmt::layer().compact().boxed(),
+        _ => tracing_subscriber::fmt::layer().json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new(
+            env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
+        ))
+        .with(fmt_layer)
+}
+
+/// Installs the global subscriber, adding OTLP span export when built with
+/// the `otel` feature. Must run inside the runtime.
+fn init_tracing() {
+    #[cfg(feature = "otel")]
+    {
+        let (otel, error) = match imgopt::telemetry::layer() {
+            Ok(layer) => (layer, None),
+            Err(e) => (None, Some(e)),
+        };
+        subscriber().with(otel).init();
+        if let Some(e) = error {
+            tracing::error!(error = %e, "OpenTelemetry export disabled: exporter setup failed");
+        }
+    }
+    #[cfg(not(feature = "otel"))]
+    subscriber().init();
+}
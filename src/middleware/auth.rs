@@ -5,32 +5,99 @@ use axum::{
 };
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use subtle::ConstantTimeEq;
 use tower::{Layer, Service};
 
-#[derive(Clone)]
-pub struct AuthLayer {
+/// Label of the token that authenticated a request. Inserted into the
+/// request extensions so handlers can log which client called them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientLabel(pub Arc<str>);
+
+struct Credential {
+    label: ClientLabel,
     // Pre-formatted expected Authorization header value ("Bearer <token>"),
     // built once at startup to avoid per-request allocations and env reads.
     expected: String,
 }
 
+#[derive(Clone)]
+pub struct AuthLayer {
+    credentials: Arc<[Credential]>,
+    disabled: bool,
+}
+
 impl AuthLayer {
-    pub fn new(token: String) -> Self {
+    /// Accepts any of the given `(label, token)` pairs.
+    pub fn new(tokens: Vec<(String, String)>) -> Self {
+        let credentials = tokens
+            .into_iter()
+            .map(|(label, token)| Credential {
+                label: ClientLabel(label.into()),
+                expected: format!("Bearer {}", token),
+            })
+            .collect();
+        Self {
+            credentials,
+            disabled: false,
+        }
+    }
+
+    /// Lets every request through unchecked, for deployments where something
+    /// in front of the service (mTLS, a private network) already
+    /// authenticates callers. Requests carry no `ClientLabel`.
+    pub fn disabled() -> Self {
         Self {
-            expected: format!("Bearer {}", token),
+            credentials: Arc::new([]),
+            disabled: true,
         }
     }
 }
 
+/// Parses `API_TOKENS`: comma-separated tokens, each optionally written as
+/// `name:token`. Unnamed tokens are labelled `token1`, `token2`, ... by
+/// position. Empty entries are skipped.
+///
+/// An entry with a `:` is always read as `name:token`, split at the first
+/// one, so a token that contains `:` itself must be given a name. The name
+/// must be letters, digits, `-` or `_`; anything else means a bare token
+/// was mistaken for a named one, and the whole list is rejected.
+pub fn parse_tokens(raw: &str) -> Result<Vec<(String, String)>, String> {
+    let mut tokens = Vec::new();
+    for (i, entry) in raw.split(',').map(str::trim).enumerate() {
+        let (label, token) = match entry.split_once(':') {
+            Some((name, token)) => {
+                let name = name.trim();
+                let is_label = !name.is_empty()
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                if !is_label {
+                    return Err(format!(
+                        "entry {} has a `:` but no valid name before it; write it as name:token",
+                        i + 1
+                    ));
+                }
+                (name.to_string(), token.trim())
+            }
+            None => (format!("token{}", i + 1), entry),
+        };
+        if !token.is_empty() {
+            tokens.push((label, token.to_string()));
+        }
+    }
+    Ok(tokens)
+}
+
 impl<S> Layer<S> for AuthLayer {
     type Service = AuthService<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
         AuthService {
             inner,
-            expected: self.expected.clone(),
+            credentials: self.credentials.clone(),
+            disabled: self.disabled,
         }
     }
 }
@@ -38,7 +105,8 @@ impl<S> Layer<S> for AuthLayer {
 #[derive(Clone)]
 pub struct AuthService<S> {
     inner: S,
-    expected: String,
+    credentials: Arc<[Credential]>,
+    disabled: bool,
 }
 
 impl<S> Service<Request<Body>> for AuthService<S>
@@ -54,10 +122,11 @@ where
         self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        // Skip auth for health/ready probes
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        // Skip auth for health/ready probes and the API description, and
+        // everything when disabled
         let path = req.uri().path();
-        if path == "/health" || path == "/ready" {
+        if self.disabled || matches!(path, "/health" | "/ready" | "/openapi.json") {
             let fut = self.inner.call(req);
             return Box::pin(async move {
                 let res = fut.await?;
@@ -65,18 +134,36 @@ where
             });
         }
 
-        let auth_header = req.headers().get("Authorization");
+        // Several Authorization headers are ambiguous: refuse rather than
+        // silently trusting whichever one comes first.
+        let mut auth_headers = req.headers().get_all("Authorization").iter();
+        let auth_header = auth_headers.next();
+        if auth_headers.next().is_some() {
+            return Box::pin(async move {
+                Ok((StatusCode::BAD_REQUEST, "Multiple Authorization headers").into_response())
+            });
+        }
 
-        let authorized = match auth_header {
-            Some(header) => {
-                let header_str = header.to_str().unwrap_or("");
-                // SEC-004: constant-time comparison to prevent timing attacks
-                self.expected.as_bytes().ct_eq(header_str.as_bytes()).into()
+        let matched = auth_header.and_then(|header| {
+            let header_str = header.to_str().unwrap_or("");
+            // SEC-004: constant-time comparison to prevent timing attacks. Every
+            // credential is checked so timing doesn't reveal which one matched.
+            let mut matched = None;
+            for credential in self.credentials.iter() {
+                let equal: bool = credential
+                    .expected
+                    .as_bytes()
+                    .ct_eq(header_str.as_bytes())
+                    .into();
+                if equal && matched.is_none() {
+                    matched = Some(credential.label.clone());
+                }
             }
-            None => false,
-        };
+            matched
+        });
 
-        if authorized {
+        if let Some(label) = matched {
+            req.extensions_mut().insert(label);
             let fut = self.inner.call(req);
             Box::pin(async move {
                 let res = fut.await?;
@@ -87,3 +174,98 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::{service_fn, ServiceExt};
+
+    /// Runs a request through `AuthService` and returns the status plus the
+    /// label the inner service saw.
+    async fn call(layer: &AuthLayer, header: Option<&str>) -> (StatusCode, Option<ClientLabel>) {
+        let service = layer.layer(service_fn(|req: Request<Body>| async move {
+            let label = req.extensions().get::<ClientLabel>().cloned();
+            let body = label.map(|l| l.0.to_string()).unwrap_or_default();
+            Ok::<_, std::convert::Infallible>(Response::new(Body::from(body)))
+        }));
+        let mut request = Request::builder().uri("/convert");
+        if let Some(value) = header {
+            request = request.header("Authorization", value);
+        }
+        let response = service
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let label = (status == StatusCode::OK)
+            .then(|| ClientLabel(String::from_utf8(body.to_vec()).unwrap().into()));
+        (status, label)
+    }
+
+    #[test]
+    fn test_parse_tokens_labels() {
+        assert_eq!(
+            parse_tokens("abc, mobile:def ,,ghi").unwrap(),
+            vec![
+                ("token1".to_string(), "abc".to_string()),
+                ("mobile".to_string(), "def".to_string()),
+                ("token4".to_string(), "ghi".to_string()),
+            ]
+        );
+        assert!(parse_tokens(" , cms: ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_tokens_colons_need_a_name() {
+        // Only the first `:` separates the name, so a named token may contain more
+        assert_eq!(
+            parse_tokens("cms:ab:cd").unwrap(),
+            vec![("cms".to_string(), "ab:cd".to_string())]
+        );
+        for raw in ["abc,:def", "first,a b:c", "x.y:token"] {
+            assert!(parse_tokens(raw).is_err(), "{}", raw);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_any_configured_token_is_accepted_and_labelled() {
+        let layer = AuthLayer::new(parse_tokens("cms:first,second").unwrap());
+
+        let (status, label) = call(&layer, Some("Bearer first")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(label, Some(ClientLabel("cms".into())));
+
+        let (status, label) = call(&layer, Some("Bearer second")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(label, Some(ClientLabel("token2".into())));
+
+        let (status, _) = call(&layer, Some("Bearer third")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = call(&layer, None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_disabled_layer_lets_everything_through() {
+        let layer = AuthLayer::disabled();
+        for header in [None, Some("Bearer anything"), Some("garbage")] {
+            let (status, _) = call(&layer, header).await;
+            assert_eq!(status, StatusCode::OK, "{:?}", header);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_token_captured_at_construction() {
+        let layer = AuthLayer::new(vec![("token1".to_string(), "startup".to_string())]);
+
+        // Changing the environment afterwards must not affect a built layer
+        unsafe { std::env::set_var("API_TOKEN", "rotated-later") };
+        let (status, _) = call(&layer, Some("Bearer startup")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = call(&layer, Some("Bearer rotated-later")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
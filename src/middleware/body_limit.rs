@@ -0,0 +1,78 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::request_id::RequestId;
+
+/// Message for any rejection caused by `MAX_UPLOAD_MB`, naming the cap so
+/// clients know how far over they are.
+pub fn too_large_message(max_upload_mb: u64) -> String {
+    format!(
+        "Request body exceeds the {} MB upload limit (MAX_UPLOAD_MB)",
+        max_upload_mb
+    )
+}
+
+/// Rewrites the body limit layer's bare `413` into the usual JSON error.
+/// Responses that are already JSON (from handlers that hit the limit while
+/// streaming) pass through unchanged.
+pub async fn json_rejection(
+    State(max_upload_mb): State<u64>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id = RequestId::from_request(&request);
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|ct| ct.as_bytes().starts_with(b"application/json"));
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE || is_json {
+        return response;
+    }
+
+    tracing::warn!(%request_id, max_upload_mb, "Rejected oversize request body");
+    ApiError::new(
+        ErrorCode::PayloadTooLarge,
+        too_large_message(max_upload_mb),
+        request_id,
+    )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::post, Router};
+    use tower::ServiceExt;
+    use tower_http::limit::RequestBodyLimitLayer;
+
+    #[tokio::test]
+    async fn test_body_limit_rejection_is_json_with_cap() {
+        let app = Router::new()
+            .route("/upload", post(|body: String| async move { body }))
+            .layer(RequestBodyLimitLayer::new(8))
+            .layer(axum::middleware::from_fn_with_state(3, json_rejection));
+
+        let response = app
+            .oneshot(
+                Request::post("/upload")
+                    .header(header::CONTENT_LENGTH, 16)
+                    .body(Body::from(vec![b'x'; 16]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["error"]["code"], "payload_too_large");
+        assert!(json["error"]["message"].as_str().unwrap().contains("3 MB"));
+    }
+}
@@ -0,0 +1,254 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Address of the end user behind any trusted proxies. Inserted into the
+/// request extensions so handlers and later layers (logging)
+/// don't have to repeat the header parsing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// An address block such as `10.0.0.0/8` or `fd00::/8`. A bare address is a
+/// block of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Compares the top `prefix` bits of two `bits`-wide addresses.
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool {
+    let shift = bits - prefix;
+    shift >= bits || (net >> shift) == (ip >> shift)
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s.trim(), None),
+        };
+        let network = addr
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow::anyhow!("invalid address in {:?}", s))?
+            .to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| anyhow::anyhow!("invalid prefix length in {:?}", s))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+/// Parses `TRUSTED_PROXIES`: comma-separated addresses or CIDR blocks.
+/// Malformed entries are skipped with a warning.
+pub fn parse_cidrs(raw: &str) -> Vec<Cidr> {
+    let mut cidrs = Vec::new();
+    for entry in raw.split(',').filter(|entry| !entry.trim().is_empty()) {
+        match entry.parse() {
+            Ok(cidr) => cidrs.push(cidr),
+            Err(e) => tracing::warn!(
+                value = %entry.trim(),
+                error = %e,
+                "Ignoring entry in TRUSTED_PROXIES"
+            ),
+        }
+    }
+    cidrs
+}
+
+/// Works out the client address for a request that arrived from `peer`.
+/// Forwarding headers are only believed when `peer` is a trusted proxy;
+/// otherwise anyone could claim any address. `X-Forwarded-For` is read right
+/// to left, skipping trusted hops, so the first untrusted address is the one
+/// our own proxies saw connect. `X-Real-IP` is the fallback for proxies that
+/// only set that.
+pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted: &[Cidr]) -> IpAddr {
+    let peer = peer.to_canonical();
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+    if !is_trusted(peer) {
+        return peer;
+    }
+
+    let hops: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| hop.trim().parse::<IpAddr>().map(|ip| ip.to_canonical()))
+        .collect::<Result<_, _>>()
+        // A chain with garbage in it can't be walked reliably
+        .unwrap_or_default();
+    if let Some(&first) = hops.first() {
+        // Every hop trusted: the leftmost is as close to the client as we know
+        return hops
+            .iter()
+            .rev()
+            .copied()
+            .find(|&ip| !is_trusted(ip))
+            .unwrap_or(first);
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .map_or(peer, |ip| ip.to_canonical())
+}
+
+/// Resolves the client address and stores it as a `ClientIp` extension and
+/// on the request span. Requests served without connection info (in-process
+/// tests) pass through untouched.
+pub async fn resolve_client_ip(
+    State(trusted): State<Arc<[Cidr]>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer {
+        let ip = resolve(peer, request.headers(), &trusted);
+        tracing::Span::current().record("client_ip", tracing::field::display(ip));
+        request.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, value.parse().unwrap());
+        }
+        map
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parse_and_contains() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains(ip("10.1.200.3")));
+        assert!(!block.contains(ip("10.2.0.1")));
+        assert!(block.contains(ip("::ffff:10.1.0.9")));
+
+        let single: Cidr = "192.168.0.7".parse().unwrap();
+        assert!(single.contains(ip("192.168.0.7")));
+        assert!(!single.contains(ip("192.168.0.8")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("10.1.0.1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("proxy.local".parse::<Cidr>().is_err());
+        assert_eq!(
+            parse_cidrs("10.0.0.0/8, bogus,,::1"),
+            vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_spoofed_header_from_untrusted_peer_is_ignored() {
+        let trusted = parse_cidrs("10.0.0.0/8");
+        let spoofed = headers(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "1.2.3.4")]);
+        assert_eq!(
+            resolve(ip("203.0.113.9"), &spoofed, &trusted),
+            ip("203.0.113.9")
+        );
+        assert_eq!(resolve(ip("203.0.113.9"), &spoofed, &[]), ip("203.0.113.9"));
+    }
+
+    #[test]
+    fn test_forwarded_chain_from_trusted_proxy() {
+        let trusted = parse_cidrs("10.0.0.0/8");
+        // The client prepended a fake hop; our proxies appended the real one
+        let chain = headers(&[("x-forwarded-for", "1.2.3.4, 198.51.100.7, 10.0.0.3")]);
+        assert_eq!(
+            resolve(ip("10.0.0.2"), &chain, &trusted),
+            ip("198.51.100.7")
+        );
+
+        let split = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-for", "10.0.0.3"),
+        ]);
+        assert_eq!(
+            resolve(ip("10.0.0.2"), &split, &trusted),
+            ip("198.51.100.7")
+        );
+
+        let real_ip = headers(&[("x-real-ip", "198.51.100.8")]);
+        assert_eq!(
+            resolve(ip("10.0.0.2"), &real_ip, &trusted),
+            ip("198.51.100.8")
+        );
+
+        let garbage = headers(&[("x-forwarded-for", "unknown, 198.51.100.7")]);
+        assert_eq!(resolve(ip("10.0.0.2"), &garbage, &trusted), ip("10.0.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_middleware_inserts_client_ip() {
+        let trusted: Arc<[Cidr]> = parse_cidrs("127.0.0.1").into();
+        let app = Router::new()
+            .route(
+                "/",
+                get(|Extension(ClientIp(ip)): Extension<ClientIp>| async move { ip.to_string() }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                trusted,
+                resolve_client_ip,
+            ));
+
+        let mut request = Request::get("/")
+            .header("x-forwarded-for", "198.51.100.7")
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+        let response = app.oneshot(request).await.unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&bytes[..], b"198.51.100.7");
+    }
+}
@@ -1 +1,5 @@
 pub mod auth;
+pub mod body_limit;
+pub mod client_ip;
+pub mod request_id;
+pub mod timeout;
@@ -0,0 +1,55 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+
+use crate::request_id::RequestId;
+
+/// Resolves the request id once and stores it in the request extensions and
+/// on the request span. Later layers and handlers read it back with
+/// `RequestId::from_request` or the `RequestId` extractor; resolving it again
+/// would mint a different UUID whenever the caller sent no usable id.
+pub async fn assign_request_id(mut request: Request, next: Next) -> Response {
+    let request_id = RequestId::from_headers(request.headers());
+    tracing::Span::current().record("request_id", tracing::field::display(request_id));
+    request.extensions_mut().insert(request_id);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_layers_and_handler_share_one_generated_id() {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|request_id: RequestId| async move { request_id.to_string() }),
+            )
+            .layer(axum::middleware::from_fn(
+                |request: Request, next: Next| async move {
+                    let request_id = RequestId::from_request(&request);
+                    let mut response = next.run(request).await;
+                    response
+                        .headers_mut()
+                        .insert("x-seen-by-layer", request_id.header_value());
+                    response
+                },
+            ))
+            .layer(axum::middleware::from_fn(assign_request_id));
+
+        let response = app
+            .oneshot(Request::get("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let seen = response.headers()["x-seen-by-layer"]
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(uuid::Uuid::parse_str(&seen).is_ok());
+        assert_eq!(body, seen.as_bytes());
+    }
+}
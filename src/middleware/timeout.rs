@@ -0,0 +1,65 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::time::Duration;
+
+use crate::handlers::error::{ApiError, ErrorCode};
+use crate::request_id::RequestId;
+
+/// Bounds a whole request, body upload included, so a client trickling a
+/// multipart body can't hold a connection and its buffers indefinitely.
+/// `ENCODING_TIMEOUT_SECS` still limits the conversion itself.
+pub async fn request_timeout(
+    State(limit): State<Duration>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request_id = RequestId::from_request(&request);
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!(
+                %request_id,
+                timeout_secs = limit.as_secs(),
+                "Request timed out"
+            );
+            ApiError::new(ErrorCode::Timeout, "Request timed out", request_id).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_slow_handler_gets_json_408() {
+        let app = Router::new()
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    "done"
+                }),
+            )
+            .layer(axum::middleware::from_fn_with_state(
+                Duration::from_millis(20),
+                request_timeout,
+            ));
+
+        let response = app
+            .oneshot(Request::get("/slow").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(json["error"]["code"], "timeout");
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
+
+/// Which pool a conversion runs on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Light,
+    Heavy,
+}
+
+/// Two independently bounded pools for blocking conversion work, so a burst
+/// of large conversions can't queue ahead of quick thumbnails. Both run on
+/// tokio's blocking threads; each pool caps how many of them it may occupy,
+/// and `main` sizes the runtime's blocking pool to match (see
+/// `blocking_threads`).
+pub struct WorkPools {
+    light: Arc<Semaphore>,
+    heavy: Arc<Semaphore>,
+    heavy_input_bytes: usize,
+    heavy_dimension: u32,
+    /// Jobs queued or running on either pool.
+    active: Arc<AtomicUsize>,
+    /// Jobs that have run to completion, successfully or not, since startup.
+    processed: Arc<AtomicU64>,
+}
+
+/// Counts a job in `WorkPools::active` until dropped, whether it finishes,
+/// fails or is abandoned while still queued.
+struct ActiveJob(Arc<AtomicUsize>);
+
+impl ActiveJob {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::AcqRel);
+        Self(counter.clone())
+    }
+}
+
+impl Drop for ActiveJob {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Blocking threads kept beyond the two pools for other blocking work
+/// (startup self-test, file I/O) so it never waits behind conversions.
+const BLOCKING_HEADROOM: usize = 4;
+
+/// Upper bound for tokio's blocking thread pool. The default of 512 is far
+/// more than the pools can ever occupy; capping it keeps thread count and
+/// memory predictable under load.
+pub fn blocking_threads(light_size: usize, heavy_size: usize) -> usize {
+    light_size + heavy_size + BLOCKING_HEADROOM
+}
+
+impl WorkPools {
+    /// `heavy_input_bytes` and `heavy_dimension` are the thresholds at or
+    /// above which a request goes to the heavy pool.
+    pub fn new(
+        light_size: usize,
+        heavy_size: usize,
+        heavy_input_bytes: usize,
+        heavy_dimension: u32,
+    ) -> Self {
+        Self {
+            light: Arc::new(Semaphore::new(light_size)),
+            heavy: Arc::new(Semaphore::new(heavy_size)),
+            heavy_input_bytes,
+            heavy_dimension,
+            active: Arc::new(AtomicUsize::new(0)),
+            processed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Classifies a request by upload size and requested output size.
+    pub fn lane_for(&self, input_len: usize, width: Option<u32>, height: Option<u32>) -> Lane {
+        let large_output = width
+            .into_iter()
+            .chain(height)
+            .any(|side| side >= self.heavy_dimension);
+        if input_len >= self.heavy_input_bytes || large_output {
+            Lane::Heavy
+        } else {
+            Lane::Light
+        }
+    }
+
+    /// Runs `job` on a blocking thread once `lane` has capacity. The slot is
+    /// held until the job itself returns, even if the caller stops waiting.
+    pub async fn spawn<F, T>(&self, lane: Lane, job: F) -> Result<T, JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let active = ActiveJob::new(&self.active);
+        let processed = self.processed.clone();
+        let permit = self
+            .semaphore(lane)
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let _active = active;
+            let result = job();
+            processed.fetch_add(1, Ordering::Relaxed);
+            result
+        })
+        .await
+    }
+
+    /// Jobs queued or running across both pools.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Jobs that have run to completion since startup. Jobs abandoned while
+    /// still queued aren't counted.
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once no job is queued or running.
+    pub async fn idle(&self) {
+        while self.active() > 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Free slots in `lane`.
+    pub fn available(&self, lane: Lane) -> usize {
+        self.semaphore(lane).available_permits()
+    }
+
+    fn semaphore(&self, lane: Lane) -> &Arc<Semaphore> {
+        match lane {
+            Lane::Light => &self.light,
+            Lane::Heavy => &self.heavy,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_lane_for_input_size_and_dimensions() {
+        let pools = WorkPools::new(4, 2, 1024, 2048);
+        assert_eq!(pools.lane_for(100, Some(200), None), Lane::Light);
+        assert_eq!(pools.lane_for(4096, None, None), Lane::Heavy);
+        assert_eq!(pools.lane_for(100, None, Some(3000)), Lane::Heavy);
+    }
+
+    #[tokio::test]
+    async fn test_large_image_occupies_heavy_pool() {
+        let pools = Arc::new(WorkPools::new(4, 2, 1024, 2048));
+        let lane = pools.lane_for(8 * 1024, None, None);
+        assert_eq!(lane, Lane::Heavy);
+
+        let (release, wait) = mpsc::channel::<()>();
+        let task = tokio::spawn({
+            let pools = pools.clone();
+            async move { pools.spawn(lane, move || wait.recv().unwrap()).await }
+        });
+
+        while pools.available(Lane::Heavy) == 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(pools.available(Lane::Heavy), 1);
+        assert_eq!(pools.available(Lane::Light), 4);
+
+        assert_eq!(pools.active(), 1);
+
+        release.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert_eq!(pools.available(Lane::Heavy), 2);
+        assert_eq!(pools.active(), 0);
+        assert_eq!(pools.processed(), 1);
+    }
+}
@@ -0,0 +1,296 @@
+use image::codecs::png::PngDecoder;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, ImageDecoder, RgbaImage};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+use super::{
+    check_decode_deadline, check_source_dimensions, Fit, Flip, Limits, OutputFormat, ProcessError,
+    ProcessOptions,
+};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Every frame of an animation, composited to the full canvas, with the
+/// delay before the next one.
+pub(super) struct Animation {
+    frames: Vec<(RgbaImage, Duration)>,
+}
+
+impl Animation {
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Resizes every frame to `width`×`height`.
+    pub fn resize(self, width: u32, height: u32) -> Self {
+        if self.frames[0].0.dimensions() == (width, height) {
+            return self;
+        }
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|(frame, delay)| {
+                let frame = image::imageops::resize(&frame, width, height, FilterType::Lanczos3);
+                (frame, delay)
+            })
+            .collect();
+        Self { frames }
+    }
+
+    /// Encodes an animated WebP with each frame's timing preserved, playing
+    /// `loop_count` times (0 is forever) over an RGBA `background`.
+    /// `lossless_effort` selects the lossless bitstream at that effort.
+    pub fn encode_webp(
+        &self,
+        quality: f32,
+        lossless_effort: Option<u8>,
+        loop_count: u16,
+        background: [u8; 4],
+    ) -> anyhow::Result<Vec<u8>> {
+        let (width, height) = self.frames[0].0.dimensions();
+        let mut config =
+            WebPConfig::new().map_err(|_| anyhow::anyhow!("WebP config init failed"))?;
+        config.quality = quality;
+        if let Some(effort) = lossless_effort {
+            config.lossless = 1;
+            config.quality = f32::from(effort.min(100));
+        }
+
+        let mut encoder = AnimEncoder::new(width, height, &config);
+        encoder.set_loop_count(i32::from(loop_count));
+        // `set_bgcolor` stores its bytes as given, but the ANIM chunk holds
+        // the color as B, G, R, A
+        let [r, g, b, a] = background;
+        encoder.set_bgcolor([b, g, r, a]);
+        let mut timestamp = 0i32;
+        for (frame, delay) in &self.frames {
+            encoder.add_frame(AnimFrame::from_rgba(
+                frame.as_raw(),
+                width,
+                height,
+                timestamp,
+            ));
+            let delay_ms = i32::try_from(delay.as_millis()).unwrap_or(i32::MAX);
+            timestamp = timestamp.saturating_add(delay_ms);
+        }
+        let webp = encoder
+            .try_encode()
+            .map_err(|e| anyhow::anyhow!("Animated WebP encoding failed: {:?}", e))?;
+        Ok(webp.to_vec())
+    }
+}
+
+/// Number of frames an APNG declares in its `acTL` chunk, or `None` for a
+/// still PNG or any other input. Only an `acTL` ahead of the first `IDAT`
+/// counts, as the spec requires.
+pub(super) fn apng_frame_count(bytes: &[u8]) -> Option<u32> {
+    let mut pos = bytes
+        .starts_with(PNG_SIGNATURE)
+        .then_some(PNG_SIGNATURE.len())?;
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        match &header[4..] {
+            b"acTL" => {
+                let count = bytes.get(pos + 8..pos + 12)?;
+                return Some(u32::from_be_bytes(count.try_into().unwrap()));
+            }
+            b"IDAT" => return None,
+            // Length, type and CRC around the data
+            _ => pos = pos.checked_add(len)?.checked_add(12)?,
+        }
+    }
+    None
+}
+
+/// Whether an animation can be carried through to the output: it has to be
+/// headed for WebP, and only a plain resize may be requested since the other
+/// transforms work on a single image.
+pub(super) fn can_keep(options: &ProcessOptions) -> bool {
+    (options.format == OutputFormat::WebP || options.auto_format)
+        && options.fit == Fit::Fill
+        && options.rotate == 0
+        && options.flip == Flip::None
+        && options.trim.is_none()
+        && options.blur.is_none()
+        && options.sharpen.is_none()
+        && options.brightness.is_none()
+        && options.contrast.is_none()
+        && options.gamma.is_none()
+        && options.saturation.is_none()
+        && !options.grayscale
+        && options.tint.is_none()
+        && options.watermark.is_none()
+        && options.mask.is_none()
+        && options.background.is_none()
+}
+
+/// Decodes every frame of an APNG that declared `declared` frames. The
+/// declared count is checked against `limits` before any frame is decoded,
+/// and the real count again as frames arrive, since the header may lie.
+pub(super) fn decode_apng(
+    bytes: &[u8],
+    declared: u32,
+    deadline: Option<(Instant, Duration)>,
+    limits: Limits,
+) -> anyhow::Result<Animation> {
+    let decoder = PngDecoder::new(Cursor::new(bytes))?;
+    let (w, h) = decoder.dimensions();
+    check_source_dimensions(w, h, limits)?;
+    check_frame_limits(declared as usize, w, h, limits)?;
+
+    let mut frames = Vec::new();
+    let mut duration = Duration::ZERO;
+    for result in decoder.apng()?.into_frames() {
+        let frame = result?;
+        check_decode_deadline(deadline)?;
+        let delay = Duration::from(frame.delay());
+        duration += delay;
+        if let Some(limit) = limits.max_animation_duration.filter(|&l| duration > l) {
+            return Err(ProcessError::AnimationTooLong { limit }.into());
+        }
+        check_frame_limits(frames.len() + 1, w, h, limits)?;
+        frames.push((frame.into_buffer(), delay));
+    }
+    if frames.is_empty() {
+        return Err(anyhow::anyhow!("Animated input contains no frames"));
+    }
+    Ok(Animation { frames })
+}
+
+fn check_frame_limits(count: usize, w: u32, h: u32, limits: Limits) -> anyhow::Result<()> {
+    if count > limits.max_frames {
+        return Err(ProcessError::TooManyFrames {
+            limit: limits.max_frames,
+        }
+        .into());
+    }
+    if count as u64 * w as u64 * h as u64 > limits.max_animation_pixels {
+        return Err(ProcessError::AnimationTooLarge {
+            limit: limits.max_animation_pixels,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&crc.finalize().to_be_bytes());
+    }
+
+    /// Splits a PNG into its chunks.
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut out = Vec::new();
+        let mut pos = PNG_SIGNATURE.len();
+        while pos + 8 <= png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            out.push((
+                png[pos + 4..pos + 8].try_into().unwrap(),
+                &png[pos + 8..pos + 8 + len],
+            ));
+            pos += 12 + len;
+        }
+        out
+    }
+
+    fn frame_control(sequence: u32, w: u32, h: u32, delay_ms: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        for value in [sequence, w, h, 0, 0] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&delay_ms.to_be_bytes());
+        data.extend_from_slice(&1000u16.to_be_bytes());
+        data.extend_from_slice(&[0, 0]); // dispose none, blend source
+        data
+    }
+
+    /// APNG with one solid-color frame per entry of `colors`, each shown for
+    /// 100ms. The encoder has no APNG support, so each frame is encoded as a
+    /// PNG and its image data restitched into `fdAT` chunks.
+    pub fn create_apng(w: u32, h: u32, colors: &[[u8; 4]]) -> Vec<u8> {
+        let mut out = PNG_SIGNATURE.to_vec();
+        let mut sequence = 0;
+        for (i, &color) in colors.iter().enumerate() {
+            let mut png = Vec::new();
+            RgbaImage::from_pixel(w, h, Rgba(color))
+                .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+                .unwrap();
+            let parts = chunks(&png);
+            if i == 0 {
+                chunk(&mut out, b"IHDR", parts[0].1);
+                let mut actl = (colors.len() as u32).to_be_bytes().to_vec();
+                actl.extend_from_slice(&0u32.to_be_bytes());
+                chunk(&mut out, b"acTL", &actl);
+            }
+            chunk(&mut out, b"fcTL", &frame_control(sequence, w, h, 100));
+            sequence += 1;
+            for (_, data) in parts.iter().filter(|(kind, _)| kind == b"IDAT") {
+                if i == 0 {
+                    chunk(&mut out, b"IDAT", data);
+                } else {
+                    let mut fdat = sequence.to_be_bytes().to_vec();
+                    fdat.extend_from_slice(data);
+                    chunk(&mut out, b"fdAT", &fdat);
+                    sequence += 1;
+                }
+            }
+        }
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+
+    #[test]
+    fn test_apng_frame_count() {
+        let apng = create_apng(4, 4, &[[255, 0, 0, 255], [0, 0, 255, 255]]);
+        assert_eq!(apng_frame_count(&apng), Some(2));
+
+        let mut still = Vec::new();
+        RgbaImage::new(4, 4)
+            .write_to(&mut Cursor::new(&mut still), image::ImageFormat::Png)
+            .unwrap();
+        assert_eq!(apng_frame_count(&still), None);
+        assert_eq!(apng_frame_count(b"GIF89a"), None);
+        assert_eq!(apng_frame_count(&apng[..20]), None);
+    }
+
+    #[test]
+    fn test_frame_limits() {
+        let apng = create_apng(4, 4, &[[255, 0, 0, 255], [0, 0, 255, 255]]);
+        let decode = |limits| decode_apng(&apng, 2, None, limits).map(|a| a.frame_count());
+        assert_eq!(decode(Limits::default()).unwrap(), 2);
+
+        let few_frames = Limits {
+            max_frames: 1,
+            ..Limits::default()
+        };
+        assert_eq!(
+            decode(few_frames)
+                .unwrap_err()
+                .downcast_ref::<ProcessError>(),
+            Some(&ProcessError::TooManyFrames { limit: 1 })
+        );
+
+        let few_pixels = Limits {
+            max_animation_pixels: 20,
+            ..Limits::default()
+        };
+        assert_eq!(
+            decode(few_pixels)
+                .unwrap_err()
+                .downcast_ref::<ProcessError>(),
+            Some(&ProcessError::AnimationTooLarge { limit: 20 })
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! CMYK and YCCK JPEG decoding. Print workflows produce these, and the
+//! generic decoder path gives wrong or inverted colors for them, so the raw
+//! ink values are read here and converted to RGB directly.
+
+use image::{DynamicImage, RgbImage};
+use zune_core::colorspace::ColorSpace;
+use zune_core::options::DecoderOptions;
+use zune_jpeg::JpegDecoder;
+
+const MARKER_SOS: u8 = 0xDA;
+const MARKER_APP14: u8 = 0xEE;
+
+/// How a four-component JPEG stores its ink values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct CmykLayout {
+    /// An Adobe APP14 segment is present. Adobe software writes CMYK
+    /// inverted (0 = full ink), which every other reader follows whenever
+    /// the segment exists.
+    pub adobe: bool,
+}
+
+/// Walks the JPEG header segments up to the first scan. Returns a layout
+/// when the frame has four components (CMYK, or YCCK per the Adobe
+/// transform flag, which the decoder turns back into CMYK).
+pub(super) fn layout(bytes: &[u8]) -> Option<CmykLayout> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut adobe = false;
+    let mut components = None;
+    let mut at = 2;
+    while at + 4 <= bytes.len() {
+        if bytes[at] != 0xFF {
+            return None;
+        }
+        let marker = bytes[at + 1];
+        if marker == MARKER_SOS {
+            break;
+        }
+        let len = u16::from_be_bytes([bytes[at + 2], bytes[at + 3]]) as usize;
+        let payload = bytes.get(at + 4..at + 2 + len)?;
+        match marker {
+            MARKER_APP14 if payload.starts_with(b"Adobe") => adobe = true,
+            // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                components = payload.get(5).copied();
+            }
+            _ => {}
+        }
+        at += 2 + len;
+    }
+    (components == Some(4)).then_some(CmykLayout { adobe })
+}
+
+/// Decodes a four-component JPEG to RGB. The naive conversion is used
+/// (no ICC profile): each channel is `(1 - ink) * (1 - black)`.
+pub(super) fn decode(bytes: &[u8], layout: CmykLayout) -> anyhow::Result<DynamicImage> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::CMYK);
+    let mut decoder = JpegDecoder::new_with_options(bytes, options);
+    let cmyk = decoder
+        .decode()
+        .map_err(|e| anyhow::anyhow!("CMYK JPEG decoding failed: {:?}", e))?;
+    let (width, height) = decoder
+        .dimensions()
+        .ok_or_else(|| anyhow::anyhow!("CMYK JPEG has no frame header"))?;
+
+    let rgb: Vec<u8> = cmyk
+        .chunks_exact(4)
+        .flat_map(|px| {
+            // Work in "remaining light" terms: 255 means no ink
+            let light = |v: u8| u32::from(if layout.adobe { v } else { 255 - v });
+            let k = light(px[3]);
+            [0, 1, 2].map(|i| ((light(px[i]) * k + 127) / 255) as u8)
+        })
+        .collect();
+    let img = RgbImage::from_raw(width as u32, height as u32, rgb)
+        .ok_or_else(|| anyhow::anyhow!("CMYK JPEG decoded to an unexpected size"))?;
+    Ok(DynamicImage::ImageRgb8(img))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Solid 8×8 CMYK JPEG. jpeg-encoder writes an Adobe segment and stores
+    /// the values inverted, as Photoshop does.
+    fn cmyk_jpeg(ink: [u8; 4]) -> Vec<u8> {
+        let pixels: Vec<u8> = ink.repeat(64);
+        let mut out = Vec::new();
+        jpeg_encoder::Encoder::new(&mut out, 95)
+            .encode(&pixels, 8, 8, jpeg_encoder::ColorType::Cmyk)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_layout_detects_four_components() {
+        assert_eq!(
+            layout(&cmyk_jpeg([0, 0, 0, 0])),
+            Some(CmykLayout { adobe: true })
+        );
+
+        let mut rgb = Vec::new();
+        jpeg_encoder::Encoder::new(&mut rgb, 90)
+            .encode(&[0u8; 8 * 8 * 3], 8, 8, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        assert_eq!(layout(&rgb), None);
+        assert_eq!(layout(b"not a jpeg"), None);
+    }
+}
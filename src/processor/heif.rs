@@ -0,0 +1,37 @@
+use image::{DynamicImage, RgbaImage};
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+use super::{check_source_dimensions, Limits};
+
+/// Decodes the primary image of a HEIC/HEIF container to RGBA8 via libheif.
+pub(super) fn decode(bytes: &[u8], limits: Limits) -> anyhow::Result<DynamicImage> {
+    let ctx = HeifContext::read_from_bytes(bytes)
+        .map_err(|e| anyhow::anyhow!("HEIF parsing failed: {}", e))?;
+    let handle = ctx
+        .primary_image_handle()
+        .map_err(|e| anyhow::anyhow!("HEIF has no primary image: {}", e))?;
+
+    // SEC-002: reject oversized images before allocating the decode buffer
+    check_source_dimensions(handle.width(), handle.height(), limits)?;
+
+    let lib_heif = LibHeif::new();
+    let image = lib_heif
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(|e| anyhow::anyhow!("HEIF decoding failed: {}", e))?;
+
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .ok_or_else(|| anyhow::anyhow!("HEIF decoder returned no interleaved plane"))?;
+
+    // Rows may be padded, so copy them out one at a time using the stride
+    let width = plane.width as usize;
+    let mut pixels = Vec::with_capacity(width * plane.height as usize * 4);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..width * 4]);
+    }
+
+    RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| anyhow::anyhow!("HEIF plane size mismatch"))
+}
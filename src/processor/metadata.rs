@@ -0,0 +1,514 @@
+//! Metadata writing for encoded output. The encoders only produce pixel data,
+//! so EXIF blocks are built here and spliced into the PNG/WebP containers.
+
+const TIFF_SHORT: u16 = 3;
+const TIFF_LONG: u16 = 4;
+const TIFF_ASCII: u16 = 2;
+const TIFF_RATIONAL: u16 = 5;
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+const TAG_X_RESOLUTION: u16 = 0x011A;
+const TAG_Y_RESOLUTION: u16 = 0x011B;
+const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+pub(super) const TAG_IMAGE_DESCRIPTION: u16 = 0x010E;
+pub(super) const TAG_ARTIST: u16 = 0x013B;
+pub(super) const TAG_COPYRIGHT: u16 = 0x8298;
+
+/// EXIF resolution unit value for inches.
+const RESOLUTION_UNIT_INCH: u16 = 2;
+/// Inches per meter, for PNG's pixels-per-meter `pHYs` unit.
+const METERS_PER_INCH: f64 = 0.0254;
+
+/// EXIF compression value for a JPEG-compressed thumbnail.
+const COMPRESSION_JPEG: u16 = 6;
+
+/// Minimal little-endian EXIF writer: IFD0 text tags plus an optional IFD1
+/// JPEG thumbnail. This covers what the service embeds; it is not a general
+/// EXIF editor.
+#[derive(Debug, Default)]
+pub(super) struct Exif {
+    /// `(tag, text)` pairs written to IFD0 as ASCII.
+    pub ascii: Vec<(u16, String)>,
+    /// JPEG bytes stored as the IFD1 thumbnail.
+    pub thumbnail: Option<Vec<u8>>,
+    /// Print resolution in dots per inch, written to IFD0.
+    pub dpi: Option<u16>,
+}
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    value: Vec<u8>,
+}
+
+impl Entry {
+    fn short(tag: u16, value: u16) -> Self {
+        Self {
+            tag,
+            kind: TIFF_SHORT,
+            count: 1,
+            value: value.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn long(tag: u16, value: u32) -> Self {
+        Self {
+            tag,
+            kind: TIFF_LONG,
+            count: 1,
+            value: value.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn rational(tag: u16, numerator: u32, denominator: u32) -> Self {
+        let mut value = numerator.to_le_bytes().to_vec();
+        value.extend_from_slice(&denominator.to_le_bytes());
+        Self {
+            tag,
+            kind: TIFF_RATIONAL,
+            count: 1,
+            value,
+        }
+    }
+
+    fn ascii(tag: u16, text: &str) -> Self {
+        let mut value = text.as_bytes().to_vec();
+        value.push(0);
+        Self {
+            tag,
+            kind: TIFF_ASCII,
+            count: value.len() as u32,
+            value,
+        }
+    }
+
+    /// Bytes stored outside the 12-byte entry, padded to an even length.
+    fn overflow_len(&self) -> usize {
+        match self.value.len() {
+            0..=4 => 0,
+            n => n + n % 2,
+        }
+    }
+}
+
+/// Total size of an IFD including its out-of-line values.
+fn ifd_len(entries: &[Entry]) -> usize {
+    2 + 12 * entries.len() + 4 + entries.iter().map(Entry::overflow_len).sum::<usize>()
+}
+
+/// Appends an IFD at the current end of `out`. Offsets are relative to the
+/// TIFF header, which must be at the start of `out`.
+fn write_ifd(out: &mut Vec<u8>, entries: &[Entry], next_ifd: u32) {
+    let mut data_offset = out.len() + 2 + 12 * entries.len() + 4;
+    let mut overflow = Vec::new();
+
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for e in entries {
+        out.extend_from_slice(&e.tag.to_le_bytes());
+        out.extend_from_slice(&e.kind.to_le_bytes());
+        out.extend_from_slice(&e.count.to_le_bytes());
+        if e.value.len() <= 4 {
+            let mut inline = [0u8; 4];
+            inline[..e.value.len()].copy_from_slice(&e.value);
+            out.extend_from_slice(&inline);
+        } else {
+            out.extend_from_slice(&(data_offset as u32).to_le_bytes());
+            overflow.extend_from_slice(&e.value);
+            overflow.resize(overflow.len() + e.value.len() % 2, 0);
+            data_offset += e.overflow_len();
+        }
+    }
+    out.extend_from_slice(&next_ifd.to_le_bytes());
+    out.extend_from_slice(&overflow);
+}
+
+impl Exif {
+    /// Serializes to a TIFF structure (`II*\0` header), the payload format of
+    /// PNG `eXIf` and WebP `EXIF` chunks.
+    pub fn to_tiff(&self) -> Vec<u8> {
+        // Pixels are written upright, so orientation is always "normal"
+        let mut ifd0 = vec![Entry::short(TAG_ORIENTATION, 1)];
+        if let Some(dpi) = self.dpi {
+            ifd0.push(Entry::rational(TAG_X_RESOLUTION, dpi.into(), 1));
+            ifd0.push(Entry::rational(TAG_Y_RESOLUTION, dpi.into(), 1));
+            ifd0.push(Entry::short(TAG_RESOLUTION_UNIT, RESOLUTION_UNIT_INCH));
+        }
+        ifd0.extend(
+            self.ascii
+                .iter()
+                .map(|(tag, text)| Entry::ascii(*tag, text)),
+        );
+        ifd0.sort_by_key(|e| e.tag);
+
+        let mut out = b"II*\0".to_vec();
+        out.extend_from_slice(&8u32.to_le_bytes());
+
+        match &self.thumbnail {
+            None => write_ifd(&mut out, &ifd0, 0),
+            Some(jpeg) => {
+                let ifd1_start = 8 + ifd_len(&ifd0);
+                let thumbnail_start = ifd1_start + 2 + 3 * 12 + 4;
+                write_ifd(&mut out, &ifd0, ifd1_start as u32);
+                let ifd1 = [
+                    Entry::short(TAG_COMPRESSION, COMPRESSION_JPEG),
+                    Entry::long(TAG_JPEG_OFFSET, thumbnail_start as u32),
+                    Entry::long(TAG_JPEG_LENGTH, jpeg.len() as u32),
+                ];
+                write_ifd(&mut out, &ifd1, 0);
+                out.extend_from_slice(jpeg);
+            }
+        }
+        out
+    }
+}
+
+/// Inserts an ancillary chunk directly after IHDR, ahead of any image data.
+pub(super) fn png_insert_chunk(png: &[u8], kind: &[u8; 4], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    // 8-byte signature, then IHDR: length, type, 13 data bytes, CRC
+    const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+    if png.len() < IHDR_END || &png[12..16] != b"IHDR" {
+        return Err(anyhow::anyhow!("Encoded PNG has an unexpected layout"));
+    }
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+
+    let mut out = Vec::with_capacity(png.len() + data.len() + 12);
+    out.extend_from_slice(&png[..IHDR_END]);
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+    out.extend_from_slice(&png[IHDR_END..]);
+    Ok(out)
+}
+
+/// `pHYs` payload declaring `dpi` in both directions.
+pub(super) fn png_phys(dpi: u16) -> [u8; 9] {
+    let ppm = (f64::from(dpi) / METERS_PER_INCH).round() as u32;
+    let mut data = [0u8; 9];
+    data[..4].copy_from_slice(&ppm.to_be_bytes());
+    data[4..8].copy_from_slice(&ppm.to_be_bytes());
+    data[8] = 1; // unit: meter
+    data
+}
+
+/// Horizontal print resolution stored in the input: a PNG `pHYs` chunk or
+/// the density of a JPEG's JFIF header. `None` when absent, given only as an
+/// aspect ratio, or out of range.
+pub(super) fn source_dpi(bytes: &[u8]) -> Option<u16> {
+    let dpi = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let mut pos = 8;
+        loop {
+            let header = bytes.get(pos..pos + 8)?;
+            let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+            match &header[4..] {
+                b"pHYs" => {
+                    let data = bytes.get(pos + 8..pos + 17)?;
+                    if data[8] != 1 {
+                        return None;
+                    }
+                    let ppm = u32::from_be_bytes(data[..4].try_into().unwrap());
+                    break f64::from(ppm) * METERS_PER_INCH;
+                }
+                b"IDAT" => return None,
+                _ => pos = pos.checked_add(len)?.checked_add(12)?,
+            }
+        }
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF, 0xE0]) {
+        // APP0: length, "JFIF\0", version, units, x density, y density
+        let app0 = bytes.get(6..16)?;
+        if &app0[..5] != b"JFIF\0" {
+            return None;
+        }
+        let density = f64::from(u16::from_be_bytes([app0[8], app0[9]]));
+        match app0[7] {
+            1 => density,
+            2 => density * 2.54,
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+    let dpi = dpi.round();
+    (1.0..=f64::from(u16::MAX))
+        .contains(&dpi)
+        .then_some(dpi as u16)
+}
+
+const VP8X_FLAG_ALPHA: u8 = 0x10;
+const VP8X_FLAG_EXIF: u8 = 0x08;
+const VP8X_FLAG_XMP: u8 = 0x04;
+
+/// Splits a WebP file into its RIFF chunks.
+fn webp_chunks(webp: &[u8]) -> anyhow::Result<Vec<([u8; 4], &[u8])>> {
+    if webp.len() < 12 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return Err(anyhow::anyhow!("Encoded WebP has an unexpected layout"));
+    }
+
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= webp.len() {
+        let fourcc: [u8; 4] = webp[pos..pos + 4].try_into().unwrap();
+        let size = u32::from_le_bytes(webp[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let start = pos + 8;
+        let end = start
+            .checked_add(size)
+            .filter(|&end| end <= webp.len())
+            .ok_or_else(|| anyhow::anyhow!("Truncated WebP chunk"))?;
+        chunks.push((fourcc, &webp[start..end]));
+        pos = end + size % 2;
+    }
+    Ok(chunks)
+}
+
+/// Whether a WebP holds lossless (`VP8L`) image data rather than lossy `VP8`.
+pub(super) fn webp_is_lossless(webp: &[u8]) -> bool {
+    webp_chunks(webp).is_ok_and(|chunks| {
+        chunks.iter().any(|(fourcc, _)| fourcc == b"VP8L")
+            && !chunks.iter().any(|(fourcc, _)| fourcc == b"VP8 ")
+    })
+}
+
+fn write_riff_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Attaches EXIF and/or XMP to a WebP, converting a simple (VP8/VP8L) file to
+/// the extended VP8X layout when needed. Existing EXIF/XMP are replaced.
+pub(super) fn webp_add_metadata(
+    webp: &[u8],
+    width: u32,
+    height: u32,
+    exif: Option<&[u8]>,
+    xmp: Option<&[u8]>,
+) -> anyhow::Result<Vec<u8>> {
+    let chunks = webp_chunks(webp)?;
+
+    let mut vp8x = [0u8; 10];
+    match chunks.iter().find(|(fourcc, _)| fourcc == b"VP8X") {
+        Some((_, existing)) if existing.len() >= 10 => vp8x.copy_from_slice(&existing[..10]),
+        _ => {
+            vp8x[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+            vp8x[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+            // A simple lossless file signals alpha in its VP8L header (bit 28)
+            let has_alpha = chunks.iter().any(|(fourcc, data)| {
+                fourcc == b"ALPH"
+                    || (fourcc == b"VP8L"
+                        && data.len() >= 5
+                        && u32::from_le_bytes(data[1..5].try_into().unwrap()) >> 28 & 1 == 1)
+            });
+            if has_alpha {
+                vp8x[0] |= VP8X_FLAG_ALPHA;
+            }
+        }
+    }
+    if exif.is_some() {
+        vp8x[0] |= VP8X_FLAG_EXIF;
+    }
+    if xmp.is_some() {
+        vp8x[0] |= VP8X_FLAG_XMP;
+    }
+
+    let mut body = b"WEBP".to_vec();
+    write_riff_chunk(&mut body, b"VP8X", &vp8x);
+    for (fourcc, data) in &chunks {
+        let replaced =
+            (fourcc == b"EXIF" && exif.is_some()) || (fourcc == b"XMP " && xmp.is_some());
+        if fourcc != b"VP8X" && !replaced {
+            write_riff_chunk(&mut body, fourcc, data);
+        }
+    }
+    // Spec order: image data first, then EXIF, then XMP
+    if let Some(exif) = exif {
+        write_riff_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = xmp {
+        write_riff_chunk(&mut body, b"XMP ", xmp);
+    }
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Inserts an APP1 `Exif` segment after SOI (and after a leading JFIF APP0,
+/// which readers expect to come first).
+pub(super) fn jpeg_insert_exif(jpeg: &[u8], exif: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return Err(anyhow::anyhow!("Encoded JPEG has an unexpected layout"));
+    }
+    // Segment length counts itself and the "Exif\0\0" header
+    let length = u16::try_from(2 + 6 + exif.len())
+        .map_err(|_| anyhow::anyhow!("EXIF block too large for a JPEG segment"))?;
+
+    let mut at = 2;
+    if jpeg.get(2..4) == Some(&[0xFF, 0xE0]) && jpeg.len() >= 6 {
+        at = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+    if at > jpeg.len() {
+        return Err(anyhow::anyhow!("Encoded JPEG has an unexpected layout"));
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len() + length as usize + 2);
+    out.extend_from_slice(&jpeg[..at]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&length.to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(exif);
+    out.extend_from_slice(&jpeg[at..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// Returns the payload of the first PNG chunk of the given type.
+    pub fn png_chunk<'a>(png: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        let mut pos = 8;
+        while pos + 8 <= png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            if &png[pos + 4..pos + 8] == kind {
+                return Some(&png[pos + 8..pos + 8 + len]);
+            }
+            pos += 12 + len;
+        }
+        None
+    }
+
+    /// Returns the TIFF block of the first APP1 `Exif` segment of a JPEG.
+    pub fn jpeg_exif(jpeg: &[u8]) -> Option<&[u8]> {
+        let mut pos = 2;
+        while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+            let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+            let payload = jpeg.get(pos + 4..pos + 2 + len)?;
+            if jpeg[pos + 1] == 0xE1 && payload.starts_with(b"Exif\0\0") {
+                return Some(&payload[6..]);
+            }
+            pos += 2 + len;
+        }
+        None
+    }
+
+    /// Returns the payload of the first WebP chunk of the given type.
+    pub fn webp_chunk<'a>(webp: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        webp_chunks(webp)
+            .ok()?
+            .into_iter()
+            .find(|(fourcc, _)| fourcc == kind)
+            .map(|(_, data)| data)
+    }
+
+    fn read_u16(tiff: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(tiff[at..at + 2].try_into().unwrap())
+    }
+
+    fn read_u32(tiff: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(tiff[at..at + 4].try_into().unwrap())
+    }
+
+    /// Reads `(tag, type, count, value-or-offset)` entries of the IFD at `at`
+    /// and the offset of the next IFD.
+    pub fn read_ifd(tiff: &[u8], at: usize) -> (Vec<(u16, u16, u32, u32)>, u32) {
+        let count = read_u16(tiff, at) as usize;
+        let entries = (0..count)
+            .map(|i| {
+                let e = at + 2 + i * 12;
+                (
+                    read_u16(tiff, e),
+                    read_u16(tiff, e + 2),
+                    read_u32(tiff, e + 4),
+                    read_u32(tiff, e + 8),
+                )
+            })
+            .collect();
+        (entries, read_u32(tiff, at + 2 + count * 12))
+    }
+
+    /// Extracts the IFD1 JPEG thumbnail of a TIFF/EXIF block.
+    pub fn exif_thumbnail(tiff: &[u8]) -> Option<&[u8]> {
+        let (_, ifd1) = read_ifd(tiff, read_u32(tiff, 4) as usize);
+        if ifd1 == 0 {
+            return None;
+        }
+        let (entries, _) = read_ifd(tiff, ifd1 as usize);
+        let find = |tag| entries.iter().find(|e| e.0 == tag).map(|e| e.3 as usize);
+        let offset = find(TAG_JPEG_OFFSET)?;
+        let length = find(TAG_JPEG_LENGTH)?;
+        tiff.get(offset..offset + length)
+    }
+
+    /// Reads an IFD0 ASCII tag (without the trailing NUL).
+    pub fn exif_ascii(tiff: &[u8], tag: u16) -> Option<String> {
+        let (entries, _) = read_ifd(tiff, read_u32(tiff, 4) as usize);
+        let (_, _, count, value) = *entries.iter().find(|e| e.0 == tag)?;
+        let count = count as usize;
+        let inline = value.to_le_bytes();
+        let bytes = if count <= 4 {
+            &inline[..count]
+        } else {
+            &tiff[value as usize..value as usize + count]
+        };
+        Some(
+            String::from_utf8_lossy(bytes)
+                .trim_end_matches('\0')
+                .to_string(),
+        )
+    }
+
+    #[test]
+    fn test_exif_round_trip() {
+        let exif = Exif {
+            ascii: vec![
+                (TAG_COPYRIGHT, "(c) Rush CMS".to_string()),
+                (TAG_ARTIST, "Ana".to_string()),
+            ],
+            thumbnail: Some(vec![0xFF, 0xD8, 0xFF, 0xD9]),
+            ..Default::default()
+        };
+        let tiff = exif.to_tiff();
+        assert_eq!(&tiff[0..4], b"II*\0");
+        assert_eq!(
+            exif_ascii(&tiff, TAG_COPYRIGHT).as_deref(),
+            Some("(c) Rush CMS")
+        );
+        assert_eq!(exif_ascii(&tiff, TAG_ARTIST).as_deref(), Some("Ana"));
+        assert_eq!(exif_thumbnail(&tiff), Some(&[0xFF, 0xD8, 0xFF, 0xD9][..]));
+
+        // IFD0 entries must be sorted by tag
+        let (entries, _) = read_ifd(&tiff, 8);
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn test_webp_add_metadata_to_simple_file() {
+        // Smallest valid layout: RIFF header plus one (empty-ish) VP8L chunk
+        let mut simple = b"WEBP".to_vec();
+        write_riff_chunk(&mut simple, b"VP8L", &[0x2f, 0, 0, 0, 0x10]);
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(simple.len() as u32).to_le_bytes());
+        webp.extend_from_slice(&simple);
+
+        let out = webp_add_metadata(&webp, 4, 3, Some(b"II*\0"), None).unwrap();
+        let vp8x = webp_chunk(&out, b"VP8X").unwrap();
+        assert_eq!(vp8x[0], VP8X_FLAG_ALPHA | VP8X_FLAG_EXIF);
+        assert_eq!(&vp8x[4..10], &[3, 0, 0, 2, 0, 0]);
+        assert_eq!(webp_chunk(&out, b"EXIF"), Some(&b"II*\0"[..]));
+        assert_eq!(
+            u32::from_le_bytes(out[4..8].try_into().unwrap()) as usize,
+            out.len() - 8
+        );
+    }
+}
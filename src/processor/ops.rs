@@ -0,0 +1,648 @@
+use image::{DynamicImage, ImageBuffer, Primitive, Rgb, RgbImage, Rgba, RgbaImage};
+
+/// Composites an image with alpha onto an opaque background color.
+/// Images without an alpha channel are returned unchanged.
+pub(super) fn flatten(img: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return img;
+    }
+
+    let rgba = img.to_rgba8();
+    let mut rgb = RgbImage::new(rgba.width(), rgba.height());
+    for (dst, src) in rgb.pixels_mut().zip(rgba.pixels()) {
+        let alpha = src[3] as u32;
+        for ((d, &s), &b) in dst.0.iter_mut().zip(&src.0[..3]).zip(&background) {
+            let blended = s as u32 * alpha + b as u32 * (255 - alpha);
+            *d = ((blended + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// Replaces each pixel with its luminance multiplied by `color`, so white maps
+/// to `color` and black stays black. Applied to a grayscale image this gives
+/// sepia-style toning. Alpha is preserved.
+pub(super) fn tint(img: DynamicImage, color: [u8; 3]) -> DynamicImage {
+    let scale = |luma: u8, c: u8| ((luma as u32 * c as u32 + 127) / 255) as u8;
+    let toned = |luma: u8| {
+        [
+            scale(luma, color[0]),
+            scale(luma, color[1]),
+            scale(luma, color[2]),
+        ]
+    };
+
+    if img.color().has_alpha() {
+        let src = img.to_luma_alpha8();
+        let out = RgbaImage::from_fn(src.width(), src.height(), |x, y| {
+            let [luma, alpha] = src.get_pixel(x, y).0;
+            let [r, g, b] = toned(luma);
+            Rgba([r, g, b, alpha])
+        });
+        DynamicImage::ImageRgba8(out)
+    } else {
+        let src = img.to_luma8();
+        let out = RgbImage::from_fn(src.width(), src.height(), |x, y| {
+            Rgb(toned(src.get_pixel(x, y)[0]))
+        });
+        DynamicImage::ImageRgb8(out)
+    }
+}
+
+/// Alpha-composites `mark` onto `img` with its top-left corner at `at`,
+/// scaling the mark's alpha by `opacity`. Parts outside `img` are clipped.
+pub(super) fn overlay(
+    img: DynamicImage,
+    mark: &RgbaImage,
+    at: (u32, u32),
+    opacity: f32,
+) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut base = img.to_rgba8();
+    let opacity = opacity.clamp(0.0, 1.0);
+
+    for (x, y, px) in mark.enumerate_pixels() {
+        let (bx, by) = (at.0 + x, at.1 + y);
+        if bx >= base.width() || by >= base.height() {
+            continue;
+        }
+        let a = px[3] as f32 / 255.0 * opacity;
+        let dst = base.get_pixel_mut(bx, by);
+        for c in 0..3 {
+            dst[c] = (dst[c] as f32 * (1.0 - a) + px[c] as f32 * a).round() as u8;
+        }
+        dst[3] = (dst[3] as f32 + (255.0 - dst[3] as f32) * a).round() as u8;
+    }
+
+    let base = DynamicImage::ImageRgba8(base);
+    if has_alpha {
+        base
+    } else {
+        DynamicImage::ImageRgb8(base.to_rgb8())
+    }
+}
+
+/// Centers `img` on a transparent `width`×`height` canvas. Parts that
+/// don't fit are clipped.
+pub(super) fn pad(img: DynamicImage, width: u32, height: u32) -> DynamicImage {
+    let mut canvas = RgbaImage::new(width, height);
+    let x = (width as i64 - img.width() as i64) / 2;
+    let y = (height as i64 - img.height() as i64) / 2;
+    image::imageops::overlay(&mut canvas, &img.to_rgba8(), x, y);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+/// Makes everything outside a centered circle as wide as the shorter side
+/// transparent. The edge is anti-aliased.
+pub(super) fn circle_mask(img: DynamicImage) -> DynamicImage {
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let radius = w.min(h) / 2.0;
+    apply_coverage(img, |x, y| {
+        let distance = ((x - w / 2.0).powi(2) + (y - h / 2.0).powi(2)).sqrt();
+        (radius - distance + 0.5).clamp(0.0, 1.0)
+    })
+}
+
+/// Rounds all four corners with `radius`, capped at half the shorter side.
+/// The edge is anti-aliased.
+pub(super) fn round_corners(img: DynamicImage, radius: f32) -> DynamicImage {
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let r = radius.min(w.min(h) / 2.0);
+    apply_coverage(img, |x, y| {
+        // Distance into a corner square; zero on either axis means the
+        // pixel lies in a straight edge band and is fully kept
+        let dx = (r - x).max(x - (w - r)).max(0.0);
+        let dy = (r - y).max(y - (h - r)).max(0.0);
+        if dx == 0.0 || dy == 0.0 {
+            return 1.0;
+        }
+        (r - (dx * dx + dy * dy).sqrt() + 0.5).clamp(0.0, 1.0)
+    })
+}
+
+/// Scales each pixel's alpha by `coverage` sampled at the pixel center.
+fn apply_coverage(img: DynamicImage, coverage: impl Fn(f32, f32) -> f32) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for (x, y, px) in rgba.enumerate_pixels_mut() {
+        let c = coverage(x as f32 + 0.5, y as f32 + 0.5);
+        if c < 1.0 {
+            px[3] = (px[3] as f32 * c).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Radius of the blur an unsharp mask is computed against.
+const UNSHARP_SIGMA: f32 = 1.0;
+
+/// Unsharp mask: adds `amount` times the difference between the image and a
+/// blurred copy, skipping differences below `threshold` so flat areas and
+/// noise aren't amplified. Alpha is left untouched.
+pub(super) fn unsharp(img: DynamicImage, amount: f32, threshold: u8) -> DynamicImage {
+    let has_alpha = img.color().has_alpha();
+    let mut sharp = img.to_rgba8();
+    let blurred = img.blur(UNSHARP_SIGMA).to_rgba8();
+
+    for (px, soft) in sharp.pixels_mut().zip(blurred.pixels()) {
+        for (c, &b) in px.0[..3].iter_mut().zip(&soft.0[..3]) {
+            let diff = *c as f32 - b as f32;
+            if diff.abs() >= threshold as f32 {
+                *c = (*c as f32 + amount * diff).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    let sharp = DynamicImage::ImageRgba8(sharp);
+    if has_alpha {
+        sharp
+    } else {
+        DynamicImage::ImageRgb8(sharp.to_rgb8())
+    }
+}
+
+/// Tone adjustments, applied per channel in this order: `brightness` shifts
+/// by that percentage of full scale, `contrast` scales the distance from mid
+/// gray by `1 + contrast / 100`, `gamma` above 1 lifts midtones, and
+/// `saturation` scales the distance from each pixel's luminance by
+/// `1 + saturation / 100`, so `-100` removes all color. Neutral values are
+/// `0`, `0`, `1` and `0`. Alpha is left untouched. Sources with more than
+/// 8 bits per channel are adjusted at 16 bits, so they keep their depth.
+pub(super) fn tone(
+    img: DynamicImage,
+    brightness: f32,
+    contrast: f32,
+    gamma: f32,
+    saturation: f32,
+) -> DynamicImage {
+    let color = img.color();
+    let has_alpha = color.has_alpha();
+    let wide = color.bytes_per_pixel() > color.channel_count();
+    let params = (brightness, contrast, gamma, saturation);
+
+    match (wide, has_alpha) {
+        (false, true) => DynamicImage::ImageRgba8(tone_pixels(img.to_rgba8(), params, |v| v as u8)),
+        (false, false) => DynamicImage::ImageRgb8(
+            DynamicImage::ImageRgba8(tone_pixels(img.to_rgba8(), params, |v| v as u8)).to_rgb8(),
+        ),
+        (true, true) => {
+            DynamicImage::ImageRgba16(tone_pixels(img.to_rgba16(), params, |v| v as u16))
+        }
+        (true, false) => DynamicImage::ImageRgb16(
+            DynamicImage::ImageRgba16(tone_pixels(img.to_rgba16(), params, |v| v as u16))
+                .to_rgb16(),
+        ),
+    }
+}
+
+/// `tone` over one RGBA buffer, with a lookup table spanning its channel
+/// type's full range. `narrow` converts a rounded, in-range value back.
+fn tone_pixels<T: Primitive + Into<f32>>(
+    mut out: ImageBuffer<Rgba<T>, Vec<T>>,
+    (brightness, contrast, gamma, saturation): (f32, f32, f32, f32),
+    narrow: impl Fn(f32) -> T,
+) -> ImageBuffer<Rgba<T>, Vec<T>> {
+    let max: f32 = T::DEFAULT_MAX_VALUE.into();
+    let contrast = 1.0 + contrast / 100.0;
+    let levels: Vec<f32> = (0..=max as u32)
+        .map(|v| {
+            let v = v as f32 + brightness / 100.0 * max;
+            let v = (v - max / 2.0) * contrast + max / 2.0;
+            let v = v.clamp(0.0, max) / max;
+            v.powf(1.0 / gamma) * max
+        })
+        .collect();
+    let saturation = 1.0 + saturation / 100.0;
+
+    for px in out.pixels_mut() {
+        let [r, g, b] = [0, 1, 2].map(|c| {
+            let v: f32 = px[c].into();
+            levels[v as usize]
+        });
+        // Rec. 709 weights, as `DynamicImage::grayscale` uses
+        let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        for (c, v) in [r, g, b].into_iter().enumerate() {
+            px[c] = narrow((luma + (v - luma) * saturation).round().clamp(0.0, max));
+        }
+    }
+    out
+}
+
+/// Bounding box `(x, y, width, height)` of everything that differs from the
+/// border color by more than `tolerance` in any channel. The border color is
+/// the corner color most other corners agree with, so a logo touching one
+/// corner doesn't throw it off. `None` when the whole image is border.
+pub(super) fn trim_bounds(img: &DynamicImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let close = |a: &Rgba<u8>, b: &Rgba<u8>| {
+        a.0.iter()
+            .zip(b.0)
+            .all(|(&x, y)| x.abs_diff(y) <= tolerance)
+    };
+    let corners = [
+        *rgba.get_pixel(0, 0),
+        *rgba.get_pixel(w - 1, 0),
+        *rgba.get_pixel(0, h - 1),
+        *rgba.get_pixel(w - 1, h - 1),
+    ];
+    // Reversed so ties go to the top-left corner
+    let border = *corners
+        .iter()
+        .rev()
+        .max_by_key(|c| corners.iter().filter(|o| close(c, o)).count())?;
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (w, h, 0, 0);
+    for (x, y, px) in rgba.enumerate_pixels() {
+        if !close(px, &border) {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+    }
+    (min_x <= max_x).then(|| (min_x, min_y, max_x - min_x + 1, max_y - min_y + 1))
+}
+
+/// Edge of the thumbnail colors are counted on; keeps the check cheap and
+/// independent of source size.
+const COLOR_SAMPLE_EDGE: u32 = 64;
+
+/// Distinct colors in a small thumbnail, at 5 bits per channel so sensor noise
+/// and gradients count but compression jitter mostly doesn't. Photographs land
+/// in the hundreds or thousands; screenshots and flat graphics far lower.
+pub(super) fn color_count(img: &DynamicImage) -> u32 {
+    let sample = img
+        .thumbnail(COLOR_SAMPLE_EDGE, COLOR_SAMPLE_EDGE)
+        .to_rgb8();
+    let mut seen = vec![false; 1 << 15];
+    let mut count = 0;
+    for p in sample.pixels() {
+        let bin = (p[0] as usize >> 3) << 10 | (p[1] as usize >> 3) << 5 | p[2] as usize >> 3;
+        if !seen[bin] {
+            seen[bin] = true;
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Luma step between neighbouring pixels that counts as a hard edge.
+const HARD_EDGE_STEP: u8 = 48;
+
+/// Share of neighbouring pixel pairs in a small thumbnail whose luma differs
+/// by a hard step. Text, UI and line art are full of such steps even after
+/// downscaling; photographs change gradually almost everywhere.
+pub(super) fn edge_density(img: &DynamicImage) -> f32 {
+    let sample = img
+        .thumbnail(COLOR_SAMPLE_EDGE, COLOR_SAMPLE_EDGE)
+        .to_luma8();
+    let (w, h) = sample.dimensions();
+    let mut pairs = 0u32;
+    let mut edges = 0u32;
+    for y in 0..h {
+        for x in 0..w {
+            let here = sample.get_pixel(x, y)[0];
+            for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                if nx < w && ny < h {
+                    pairs += 1;
+                    if here.abs_diff(sample.get_pixel(nx, ny)[0]) >= HARD_EDGE_STEP {
+                        edges += 1;
+                    }
+                }
+            }
+        }
+    }
+    if pairs == 0 {
+        0.0
+    } else {
+        edges as f32 / pairs as f32
+    }
+}
+
+/// Top-left corner of the `crop_w`×`crop_h` window with the most detail, for
+/// `Fit::Cover` with smart gravity. Detail is the summed luma step to each
+/// pixel's right and lower neighbour, measured on a small thumbnail so the
+/// search stays cheap on large sources. Flat images keep the centered window.
+pub(super) fn busiest_window(img: &DynamicImage, crop_w: u32, crop_h: u32) -> (u32, u32) {
+    let sample = img
+        .thumbnail(COLOR_SAMPLE_EDGE, COLOR_SAMPLE_EDGE)
+        .to_luma8();
+    let (w, h) = sample.dimensions();
+
+    // Summed-area table of the detail map, one row and column of padding
+    let stride = w as usize + 1;
+    let mut sums = vec![0u32; stride * (h as usize + 1)];
+    for y in 0..h {
+        for x in 0..w {
+            let here = sample.get_pixel(x, y)[0];
+            let mut detail = 0u32;
+            for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                if nx < w && ny < h {
+                    detail += here.abs_diff(sample.get_pixel(nx, ny)[0]) as u32;
+                }
+            }
+            let (i, j) = (x as usize + 1, y as usize + 1);
+            sums[j * stride + i] = detail + sums[(j - 1) * stride + i] + sums[j * stride + i - 1]
+                - sums[(j - 1) * stride + i - 1];
+        }
+    }
+
+    let scale_x = img.width() as f64 / w as f64;
+    let scale_y = img.height() as f64 / h as f64;
+    let win_w = ((crop_w as f64 / scale_x).round() as u32).clamp(1, w);
+    let win_h = ((crop_h as f64 / scale_y).round() as u32).clamp(1, h);
+    let score = |x: u32, y: u32| {
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = (x0 + win_w as usize, y0 + win_h as usize);
+        sums[y1 * stride + x1] + sums[y0 * stride + x0]
+            - sums[y0 * stride + x1]
+            - sums[y1 * stride + x0]
+    };
+
+    let mut best = ((w - win_w) / 2, (h - win_h) / 2);
+    let mut best_score = score(best.0, best.1);
+    for y in 0..=h - win_h {
+        for x in 0..=w - win_w {
+            let s = score(x, y);
+            if s > best_score {
+                best = (x, y);
+                best_score = s;
+            }
+        }
+    }
+
+    let place = |at: u32, scale: f64, crop: u32, full: u32| {
+        ((at as f64 * scale).round() as u32).min(full.saturating_sub(crop))
+    };
+    (
+        place(best.0, scale_x, crop_w, img.width()),
+        place(best.1, scale_y, crop_h, img.height()),
+    )
+}
+
+/// Side of the square windows SSIM is computed over.
+const SSIM_WINDOW: u32 = 8;
+
+/// Mean structural similarity (SSIM) of two equally sized images, on luma,
+/// over 8×8 windows overlapping by half. 1.0 means identical; visible
+/// compression damage typically shows up below ~0.95.
+pub(super) fn ssim(a: &DynamicImage, b: &DynamicImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (a, b) = (a.to_luma8(), b.to_luma8());
+    debug_assert_eq!(a.dimensions(), b.dimensions());
+    let (w, h) = a.dimensions();
+    let win = SSIM_WINDOW.min(w).min(h);
+    let step = (win / 2).max(1) as usize;
+    let n = (win * win) as f64;
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for y in (0..=h - win).step_by(step) {
+        for x in (0..=w - win).step_by(step) {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..win {
+                for dx in 0..win {
+                    let pa = a.get_pixel(x + dx, y + dy)[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy)[0] as f64;
+                    sa += pa;
+                    sb += pb;
+                    saa += pa * pa;
+                    sbb += pb * pb;
+                    sab += pa * pb;
+                }
+            }
+            let (ma, mb) = (sa / n, sb / n);
+            let var_a = saa / n - ma * ma;
+            let var_b = sbb / n - mb * mb;
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + C1) * (2.0 * cov + C2))
+                / ((ma * ma + mb * mb + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        1.0
+    } else {
+        total / windows as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten_composites_onto_background() {
+        let mut img = RgbaImage::from_pixel(2, 1, Rgba([255, 0, 0, 255]));
+        img.put_pixel(1, 0, Rgba([255, 0, 0, 0]));
+
+        let flat = flatten(DynamicImage::ImageRgba8(img), [0, 0, 255]).to_rgb8();
+        assert_eq!(flat.get_pixel(0, 0).0, [255, 0, 0]);
+        assert_eq!(flat.get_pixel(1, 0).0, [0, 0, 255]);
+    }
+
+    #[test]
+    fn test_tone_moves_mid_gray() {
+        let gray = |value| DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, Rgb([value; 3])));
+        let adjust = |value, brightness, contrast, gamma, saturation| {
+            tone(gray(value), brightness, contrast, gamma, saturation)
+                .to_rgb8()
+                .get_pixel(0, 0)
+                .0[0]
+        };
+
+        assert_eq!(adjust(128, 0.0, 0.0, 1.0, 0.0), 128);
+        // 20% of full scale either way
+        assert_eq!(adjust(128, 20.0, 0.0, 1.0, 0.0), 179);
+        assert_eq!(adjust(128, -20.0, 0.0, 1.0, 0.0), 77);
+        assert_eq!(adjust(128, 100.0, 0.0, 1.0, 0.0), 255);
+        // Contrast pushes away from mid gray, and -100 flattens onto it
+        assert_eq!(adjust(64, 0.0, 50.0, 1.0, 0.0), 32);
+        assert_eq!(adjust(192, 0.0, 50.0, 1.0, 0.0), 224);
+        assert_eq!(adjust(192, 0.0, -100.0, 1.0, 0.0), 128);
+        // Gamma lifts or sinks midtones and keeps the ends in place
+        assert_eq!(adjust(128, 0.0, 0.0, 2.0, 0.0), 181);
+        assert_eq!(adjust(128, 0.0, 0.0, 0.5, 0.0), 64);
+        assert_eq!(adjust(255, 0.0, 0.0, 2.0, 0.0), 255);
+        assert_eq!(adjust(0, 0.0, 0.0, 0.5, 0.0), 0);
+        // Gray has no color to saturate
+        assert_eq!(adjust(128, 0.0, 0.0, 1.0, 100.0), 128);
+    }
+
+    #[test]
+    fn test_tone_keeps_16_bit_depth() {
+        let gray = |color: DynamicImage| tone(color, 0.0, 0.0, 2.0, 0.0);
+
+        let rgb = gray(DynamicImage::ImageRgb16(ImageBuffer::from_pixel(
+            1,
+            1,
+            Rgb([32768u16; 3]),
+        )));
+        assert_eq!(rgb.color(), image::ColorType::Rgb16);
+        // Same curve as at 8 bits, without the 8-bit rounding
+        assert_eq!(rgb.to_rgb16().get_pixel(0, 0).0[0], 46341);
+
+        let rgba = gray(DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            1,
+            1,
+            Rgba([32768u16, 32768, 32768, 1000]),
+        )));
+        assert_eq!(rgba.color(), image::ColorType::Rgba16);
+        assert_eq!(rgba.to_rgba16().get_pixel(0, 0).0[3], 1000);
+    }
+
+    #[test]
+    fn test_tone_saturation() {
+        let orange = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, Rgba([200, 100, 0, 90])));
+        let saturate = |amount| {
+            tone(orange.clone(), 0.0, 0.0, 1.0, amount)
+                .to_rgba8()
+                .get_pixel(0, 0)
+                .0
+        };
+
+        let [r, g, b, a] = saturate(-100.0);
+        assert_eq!((r, g, a), (g, b, 90));
+        let [r, _, b, _] = saturate(50.0);
+        assert!(r > 200 && b == 0);
+    }
+
+    #[test]
+    fn test_flatten_half_transparent() {
+        let img = RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 128]));
+        let flat = flatten(DynamicImage::ImageRgba8(img), [0, 0, 0]).to_rgb8();
+        assert_eq!(flat.get_pixel(0, 0).0, [128, 128, 128]);
+    }
+
+    #[test]
+    fn test_color_count_flat_vs_noisy() {
+        let flat = RgbaImage::from_fn(32, 32, |x, _| {
+            if x < 16 {
+                Rgba([255, 255, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        assert_eq!(color_count(&DynamicImage::ImageRgba8(flat)), 2);
+
+        let noisy = RgbaImage::from_fn(64, 64, |x, y| {
+            let v = x * 7919 + y * 104_729;
+            Rgba([(v % 251) as u8, (v % 241) as u8, (v % 239) as u8, 255])
+        });
+        assert!(color_count(&DynamicImage::ImageRgba8(noisy)) > 1000);
+    }
+
+    #[test]
+    fn test_ssim_identical_and_degraded() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, y| {
+            Rgba([(x * 8) as u8, (y * 8) as u8, 128, 255])
+        }));
+        assert!((ssim(&img, &img) - 1.0).abs() < 1e-9);
+
+        let flat =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(32, 32, Rgba([128, 128, 128, 255])));
+        assert!(ssim(&img, &flat) < 0.5);
+    }
+
+    #[test]
+    fn test_tint_scales_luminance() {
+        let img = RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 100]));
+        let toned = tint(DynamicImage::ImageRgba8(img), [255, 136, 0]).to_rgba8();
+        assert_eq!(toned.get_pixel(0, 0).0, [255, 136, 0, 100]);
+    }
+
+    #[test]
+    fn test_unsharp_increases_edge_contrast() {
+        let edge = RgbImage::from_fn(16, 16, |x, _| {
+            if x < 8 {
+                Rgb([100, 100, 100])
+            } else {
+                Rgb([150, 150, 150])
+            }
+        });
+        let sharp = unsharp(DynamicImage::ImageRgb8(edge), 1.5, 0).to_rgb8();
+        assert!(sharp.get_pixel(7, 8)[0] < 100);
+        assert!(sharp.get_pixel(8, 8)[0] > 150);
+        // Far from the edge nothing changes
+        assert_eq!(sharp.get_pixel(0, 8)[0], 100);
+
+        // A threshold above the local contrast leaves the image alone
+        let flat = unsharp(
+            DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([90, 90, 90]))),
+            2.0,
+            10,
+        );
+        assert_eq!(flat.to_rgb8().get_pixel(1, 1)[0], 90);
+    }
+
+    #[test]
+    fn test_overlay_blends_by_opacity_and_clips() {
+        let base = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])));
+        let mark = RgbaImage::from_pixel(2, 2, Rgba([200, 100, 0, 255]));
+        let out = overlay(base, &mark, (3, 3), 0.5).to_rgb8();
+        assert_eq!(out.get_pixel(3, 3).0, [100, 50, 0]);
+        assert_eq!(out.get_pixel(2, 2).0, [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_trim_bounds_finds_content_inside_margin() {
+        let img = RgbImage::from_fn(20, 10, |x, y| {
+            if (5..15).contains(&x) && (2..6).contains(&y) {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([250, 250, 250])
+            }
+        });
+        let img = DynamicImage::ImageRgb8(img);
+        assert_eq!(trim_bounds(&img, 10), Some((5, 2, 10, 4)));
+
+        let solid = DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([40, 40, 40])));
+        assert_eq!(trim_bounds(&solid, 0), None);
+    }
+
+    #[test]
+    fn test_edge_density_separates_stripes_from_gradient() {
+        let stripes = RgbImage::from_fn(64, 64, |x, _| {
+            if x % 4 < 2 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        });
+        let gradient = RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+        assert!(edge_density(&DynamicImage::ImageRgb8(stripes)) > 0.4);
+        assert_eq!(edge_density(&DynamicImage::ImageRgb8(gradient)), 0.0);
+    }
+
+    #[test]
+    fn test_busiest_window_finds_detail() {
+        // Flat gray with a checkerboard patch near the bottom
+        let img = RgbImage::from_fn(40, 200, |x, y| {
+            if (150..190).contains(&y) && (x / 8 + y / 8) % 2 == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([128, 128, 128])
+            }
+        });
+        let (x, y) = busiest_window(&DynamicImage::ImageRgb8(img), 40, 40);
+        assert_eq!(x, 0);
+        assert!((140..=160).contains(&y), "window at y={}", y);
+
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 200, Rgb([9, 9, 9])));
+        let (_, y) = busiest_window(&flat, 40, 40);
+        assert!((75..=85).contains(&y), "flat window at y={}", y);
+    }
+
+    #[test]
+    fn test_round_corners_clears_only_corners() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(40, 20, Rgb([9, 9, 9])));
+        let out = round_corners(img, 8.0).to_rgba8();
+        assert_eq!(out.get_pixel(0, 0)[3], 0);
+        assert_eq!(out.get_pixel(39, 19)[3], 0);
+        assert_eq!(out.get_pixel(20, 0)[3], 255);
+        assert_eq!(out.get_pixel(0, 10)[3], 255);
+        assert_eq!(out.get_pixel(20, 10).0, [9, 9, 9, 255]);
+    }
+}
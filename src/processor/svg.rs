@@ -0,0 +1,99 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use resvg::{tiny_skia, usvg};
+
+use super::{check_source_dimensions, Limits};
+
+/// Long-edge size used when an SVG declares no `width`/`height` and the
+/// request has no resize. usvg would otherwise fall back to the viewBox
+/// size (often 24×24 for icons) or 100×100.
+pub const DEFAULT_RENDER_EDGE: u32 = 1024;
+
+/// Rasterizes an SVG straight to the requested size so no resampling of the
+/// bitmap is needed afterwards. A single requested dimension keeps the SVG's
+/// aspect ratio.
+pub(super) fn render(
+    bytes: &[u8],
+    width: Option<u32>,
+    height: Option<u32>,
+    limits: Limits,
+) -> anyhow::Result<DynamicImage> {
+    let tree = usvg::Tree::from_data(bytes, &usvg::Options::default())
+        .map_err(|e| anyhow::anyhow!("SVG parsing failed: {}", e))?;
+    let size = tree.size();
+    let aspect = size.width() / size.height();
+
+    let (w, h) = match (width, height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, (w as f32 / aspect).round().max(1.0) as u32),
+        (None, Some(h)) => ((h as f32 * aspect).round().max(1.0) as u32, h),
+        (None, None) if has_declared_size(bytes) => (
+            size.width().round().max(1.0) as u32,
+            size.height().round().max(1.0) as u32,
+        ),
+        (None, None) if aspect >= 1.0 => (
+            DEFAULT_RENDER_EDGE,
+            (DEFAULT_RENDER_EDGE as f32 / aspect).round().max(1.0) as u32,
+        ),
+        (None, None) => (
+            (DEFAULT_RENDER_EDGE as f32 * aspect).round().max(1.0) as u32,
+            DEFAULT_RENDER_EDGE,
+        ),
+    };
+
+    // SEC-002: the render target is the allocation, so bound it like a decode
+    check_source_dimensions(w, h, limits)?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(w, h)
+        .ok_or_else(|| anyhow::anyhow!("Invalid SVG render size {}x{}", w, h))?;
+    let transform =
+        tiny_skia::Transform::from_scale(w as f32 / size.width(), h as f32 / size.height());
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied alpha; encoders expect straight alpha
+    let mut rgba = RgbaImage::new(w, h);
+    for (dst, src) in rgba.pixels_mut().zip(pixmap.pixels()) {
+        let c = src.demultiply();
+        *dst = Rgba([c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+/// True when the root `<svg>` element carries both `width` and `height`.
+fn has_declared_size(bytes: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(bytes);
+    let Some(start) = text.find("<svg") else {
+        return false;
+    };
+    let tag = match text[start..].find('>') {
+        Some(end) => &text[start..start + end],
+        None => return false,
+    };
+    tag.contains(" width=") && tag.contains(" height=")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECT: &[u8] = br#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20"><rect width="40" height="20" fill="red"/></svg>"#;
+    const ICON: &[u8] = br#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 24 12"><rect width="24" height="12" fill="blue"/></svg>"#;
+
+    #[test]
+    fn test_render_intrinsic_size() {
+        let img = render(RECT, None, None, Limits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (40, 20));
+        assert_eq!(img.to_rgba8().get_pixel(10, 10), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_render_single_dimension_keeps_aspect() {
+        let img = render(RECT, Some(200), None, Limits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (200, 100));
+    }
+
+    #[test]
+    fn test_render_without_declared_size_uses_default_canvas() {
+        let img = render(ICON, None, None, Limits::default()).unwrap();
+        assert_eq!((img.width(), img.height()), (DEFAULT_RENDER_EDGE, 512));
+    }
+}
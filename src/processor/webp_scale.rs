@@ -0,0 +1,60 @@
+use image::{DynamicImage, RgbImage, RgbaImage};
+use libwebp_sys::{
+    WebPDecode, WebPDecoderConfig, WebPFreeDecBuffer, WebPInitDecoderConfig, WebPRGBABuffer,
+    WEBP_CSP_MODE,
+};
+
+/// Decodes a still WebP directly at `width`×`height` using libwebp's built-in
+/// rescaler. For lossy input the scaling runs as part of the YUV→RGB
+/// conversion, so the full-size RGB image is never produced and no separate
+/// resampling pass is needed.
+pub(super) fn decode_scaled(
+    bytes: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> anyhow::Result<DynamicImage> {
+    let channels = if has_alpha { 4 } else { 3 };
+    let stride = width as usize * channels;
+    let mut pixels = vec![0u8; stride * height as usize];
+
+    // SAFETY: an all-zero config is a valid starting point that
+    // WebPInitDecoderConfig then fills with defaults.
+    let mut config: WebPDecoderConfig = unsafe { std::mem::zeroed() };
+    if unsafe { WebPInitDecoderConfig(&mut config) } == 0 {
+        return Err(anyhow::anyhow!("libwebp decoder version mismatch"));
+    }
+    config.options.use_scaling = 1;
+    config.options.scaled_width = width as i32;
+    config.options.scaled_height = height as i32;
+    config.output.colorspace = if has_alpha {
+        WEBP_CSP_MODE::MODE_RGBA
+    } else {
+        WEBP_CSP_MODE::MODE_RGB
+    };
+    // Decode into our own buffer so no libwebp-owned memory escapes
+    config.output.is_external_memory = 1;
+    config.output.u.RGBA = WebPRGBABuffer {
+        rgba: pixels.as_mut_ptr(),
+        stride: stride as i32,
+        size: pixels.len(),
+    };
+
+    // SAFETY: `bytes` outlives the call and `pixels` is sized for the
+    // requested output; libwebp writes only within `size`.
+    let status = unsafe { WebPDecode(bytes.as_ptr(), bytes.len(), &mut config) };
+    unsafe { WebPFreeDecBuffer(&mut config.output) };
+    if status as i32 != 0 {
+        return Err(anyhow::anyhow!(
+            "WebP scaled decoding failed (status {})",
+            status as i32
+        ));
+    }
+
+    let img = if has_alpha {
+        RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    img.ok_or_else(|| anyhow::anyhow!("WebP scaled buffer size mismatch"))
+}
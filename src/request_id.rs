@@ -0,0 +1,130 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, Extensions, HeaderMap, HeaderValue},
+};
+use std::convert::Infallible;
+use std::fmt;
+
+/// Request header a caller may use to pass its own correlation id.
+pub const HEADER: &str = "x-request-id";
+/// Longest caller-supplied id accepted. A UUID is 36 characters.
+pub const MAX_LEN: usize = 64;
+
+/// Correlation id for one request: the caller's `X-Request-Id` when it's
+/// usable, otherwise a fresh UUID. Stored inline so it is `Copy` like the
+/// `Uuid` it replaces.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct RequestId {
+    buf: [u8; MAX_LEN],
+    len: u8,
+}
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self::parse(&uuid::Uuid::new_v4().to_string()).expect("a UUID is a valid request id")
+    }
+
+    /// Takes the caller's id if present and valid. Invalid ids are logged
+    /// and replaced rather than rejected; they only exist for correlation.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(value) = headers.get(HEADER) else {
+            return Self::generate();
+        };
+        match value.to_str().ok().and_then(Self::parse) {
+            Some(id) => id,
+            None => {
+                let id = Self::generate();
+                tracing::warn!(request_id = %id, "Ignoring malformed X-Request-Id header");
+                id
+            }
+        }
+    }
+
+    /// The id the `assign_request_id` middleware stored on the request, so
+    /// every layer and the handler report the same one. Falls back to the
+    /// headers when that layer isn't installed (handler-only tests).
+    pub fn from_request(request: &Request) -> Self {
+        Self::from_parts(request.extensions(), request.headers())
+    }
+
+    fn from_parts(extensions: &Extensions, headers: &HeaderMap) -> Self {
+        extensions
+            .get::<Self>()
+            .copied()
+            .unwrap_or_else(|| Self::from_headers(headers))
+    }
+
+    /// Accepts 1–`MAX_LEN` characters of ASCII letters, digits and `-_.:`,
+    /// which covers UUIDs, ULIDs and typical tracing ids while keeping logs
+    /// and headers free of anything surprising.
+    pub fn parse(raw: &str) -> Option<Self> {
+        let valid = !raw.is_empty()
+            && raw.len() <= MAX_LEN
+            && raw
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b));
+        if !valid {
+            return None;
+        }
+        let mut buf = [0u8; MAX_LEN];
+        buf[..raw.len()].copy_from_slice(raw.as_bytes());
+        Some(Self {
+            buf,
+            len: raw.len() as u8,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buf[..self.len as usize]).expect("validated as ASCII")
+    }
+
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(self.as_str()).expect("validated as header-safe")
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(&parts.extensions, &parts.headers))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_headers_uses_valid_caller_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER, HeaderValue::from_static("trace-42:abc.DEF_1"));
+        assert_eq!(
+            RequestId::from_headers(&headers).as_str(),
+            "trace-42:abc.DEF_1"
+        );
+
+        for bad in ["has space", "semi;colon", "x".repeat(MAX_LEN + 1).as_str()] {
+            headers.insert(HEADER, HeaderValue::from_str(bad).unwrap());
+            let id = RequestId::from_headers(&headers);
+            assert_ne!(id.as_str(), bad);
+            assert!(uuid::Uuid::parse_str(id.as_str()).is_ok());
+        }
+
+        assert!(uuid::Uuid::parse_str(RequestId::from_headers(&HeaderMap::new()).as_str()).is_ok());
+    }
+}
@@ -1,49 +1,259 @@
 use axum::{
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, Method},
     routing::{get, post},
     Router,
 };
 use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 
+use crate::cache;
+use crate::config::Config;
 use crate::handlers;
+use crate::jobs;
 use crate::middleware;
+use crate::processor;
+use crate::state::AppState;
 
-pub fn create_router() -> Router {
-    let max_upload_mb: u64 = env::var("MAX_UPLOAD_MB")
-        .unwrap_or_else(|_| "10".to_string())
-        .parse()
-        .unwrap_or(10);
+/// Builds the application. `ready` backs `/ready`; the caller decides when
+/// the service counts as ready.
+pub fn create_router(ready: Arc<AtomicBool>) -> Router {
+    create_router_with(Config::from_env(), ready)
+}
+
+/// `create_router` with a given `Config` rather than one read from the
+/// environment, so tests can vary settings without touching process-wide
+/// variables other tests read concurrently.
+pub fn create_router_with(config: Config, ready: Arc<AtomicBool>) -> Router {
+    build_router(AppState::new(config, ready))
+}
 
+fn build_router(state: AppState) -> Router {
+    let max_upload_mb = state.config.max_upload_mb;
     let max_bytes = max_upload_mb * 1024 * 1024;
+    let trusted_proxies: Arc<[middleware::client_ip::Cidr]> =
+        state.config.trusted_proxies.clone().into();
 
-    // Read API_TOKEN once here at router-construction time (startup), not per request.
-    // main() already validated that the token is set and non-empty before reaching this point.
-    let api_token = env::var("API_TOKEN").unwrap_or_default();
+    // Read tokens once here at router-construction time (startup), not per request.
+    // main() already validated that at least one token is set before reaching this point,
+    // unless AUTH_DISABLED is set.
+    // API_TOKENS (several labelled clients) takes precedence over the single API_TOKEN.
+    let auth = if state.config.auth_disabled {
+        middleware::auth::AuthLayer::disabled()
+    } else {
+        let mut tokens = env::var("API_TOKENS")
+            .ok()
+            // main() refuses to start on a malformed list
+            .and_then(|raw| middleware::auth::parse_tokens(&raw).ok())
+            .unwrap_or_default();
+        if tokens.is_empty() {
+            let token = env::var("API_TOKEN").unwrap_or_default();
+            tokens.push(("token1".to_string(), token));
+        }
+        middleware::auth::AuthLayer::new(tokens)
+    };
 
-    Router::new()
+    let mut router = Router::new()
+        .route("/convert", post(handlers::convert::convert_image))
+        .route(
+            "/convert/:filename",
+            post(handlers::convert::convert_image_as),
+        )
+        .route("/srcset", post(handlers::srcset::srcset))
+        .route("/quality-scan", post(handlers::quality_scan::quality_scan))
+        .route("/shrink", post(handlers::shrink::shrink))
+        .route("/jobs/:id", get(handlers::jobs::job_status))
+        // Probes are added after this so they're never cut short
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.config.request_timeout,
+            middleware::timeout::request_timeout,
+        ))
         .route("/health", get(handlers::health::health_check))
         .route("/ready", get(handlers::health::ready_check))
-        .route("/convert", post(handlers::convert::convert_image))
-        // Layer execution order (outermost first): TraceLayer → BodyLimit → Auth → Handler
-        .layer(middleware::auth::AuthLayer::new(api_token))
+        .route("/openapi.json", get(handlers::openapi::openapi))
+        // Layer execution order (outermost first):
+        // TraceLayer → RequestId → ClientIp → Cors → BodyLimitJson → BodyLimit → Auth
+        //   → RequestTimeout → Handler
+        .layer(auth)
         .layer(RequestBodyLimitLayer::new(max_bytes as usize))
-        .layer(TraceLayer::new_for_http())
+        .layer(axum::middleware::from_fn_with_state(
+            max_upload_mb,
+            middleware::body_limit::json_rejection,
+        ));
+    // Outside auth so browser preflights, which carry no token, are answered
+    if let Some(cors) = cors_layer() {
+        router = router.layer(cors);
+    }
+    router
+        .layer(axum::middleware::from_fn_with_state(
+            trusted_proxies,
+            middleware::client_ip::resolve_client_ip,
+        ))
+        .layer(axum::middleware::from_fn(
+            middleware::request_id::assign_request_id,
+        ))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .with_state(state)
+}
+
+/// Periodic eviction of expired cache entries and jobs, which would
+/// otherwise linger until the next insert. The tasks stop when the returned
+/// set is dropped.
+fn spawn_sweepers(state: &AppState) -> JoinSet<()> {
+    let mut sweepers = JoinSet::new();
+    if let Some(cache) = state.cache.clone() {
+        sweepers.spawn(async move {
+            let mut interval = tokio::time::interval(cache::SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                cache.evict();
+            }
+        });
+    }
+    if state.config.max_jobs > 0 {
+        let store = state.jobs.clone();
+        sweepers.spawn(async move {
+            let mut interval = tokio::time::interval(jobs::SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                store.evict();
+            }
+        });
+    }
+    sweepers
+}
+
+/// Span wrapping each request. `request_id` and `client_ip` are filled in by
+/// the middleware that resolves them; with the `otel` feature
+/// the span joins the caller's trace.
+fn request_span(request: &Request) -> tracing::Span {
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id = tracing::field::Empty,
+        client_ip = tracing::field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    crate::telemetry::set_parent(&span, request.headers());
+    span
+}
+
+/// Builds CORS handling from `CORS_ALLOWED_ORIGINS` (comma-separated origins,
+/// or `*`). Unset or empty means no CORS headers at all.
+fn cors_layer() -> Option<CorsLayer> {
+    let raw = env::var("CORS_ALLOWED_ORIGINS").ok()?;
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return None;
+    }
+
+    let origins = if raw == "*" {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(raw.split(',').filter_map(|origin| {
+            let origin = origin.trim();
+            HeaderValue::from_str(origin)
+                .inspect_err(|_| tracing::warn!(%origin, "Ignoring invalid CORS origin"))
+                .ok()
+        }))
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::POST])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("x-request-id"),
+            ])
+            .expose_headers([HeaderName::from_static("x-request-id"), header::ETAG]),
+    )
 }
 
-pub async fn start(addr: &str) -> anyhow::Result<()> {
-    let app = create_router();
+pub async fn start(addr: &str, config: Config) -> anyhow::Result<()> {
+    let ready = Arc::new(AtomicBool::new(false));
+    let state = AppState::new(config, ready.clone());
+    let shutdown_timeout = state.config.shutdown_timeout;
+    let pools = state.pools.clone();
+    // Owned here so they end with the server
+    let _sweepers = spawn_sweepers(&state);
+    let app = build_router(state);
+
+    // Not ready until every encoder has produced output once
+    tokio::spawn({
+        let ready = ready.clone();
+        async move {
+            match tokio::task::spawn_blocking(processor::self_test).await {
+                Ok(Ok(())) => {
+                    ready.store(true, Ordering::Release);
+                    tracing::info!("Encoder self-test passed, ready for traffic");
+                }
+                Ok(Err(e)) => {
+                    tracing::error!(error = %e, "Encoder self-test failed, staying not ready")
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "Encoder self-test panicked, staying not ready")
+                }
+            }
+        }
+    });
+
     let listener = TcpListener::bind(addr)
         .await
         .map_err(|e| anyhow::anyhow!("Failed to bind to {}: {}", addr, e))?;
 
-    let shutdown_signal = make_shutdown_signal();
+    let (signalled_tx, signalled_rx) = tokio::sync::oneshot::channel();
+    let shutdown_signal = async move {
+        make_shutdown_signal().await;
+        // Fail readiness first so load balancers drain this instance
+        ready.store(false, Ordering::Release);
+        let _ = signalled_tx.send(());
+    };
 
-    axum::serve(listener, app)
+    let mut server = tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
         .with_graceful_shutdown(shutdown_signal)
         .await
         .map_err(|e| anyhow::anyhow!("Server error: {}", e))
+    });
+
+    tokio::select! {
+        result = &mut server => return result?,
+        _ = signalled_rx => {}
+    }
+
+    // Open connections finish first, then any conversion whose client went
+    // away; both share one deadline
+    let deadline = tokio::time::Instant::now() + shutdown_timeout;
+    let drained = async {
+        let result = server.await?;
+        pools.idle().await;
+        result
+    };
+    match tokio::time::timeout_at(deadline, drained).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!(
+                in_flight = pools.active(),
+                timeout_secs = shutdown_timeout.as_secs(),
+                "Shutdown deadline reached with conversions still running, exiting"
+            );
+            // Returning would block on the runtime waiting for blocking threads
+            std::process::exit(1);
+        }
+    }
 }
 
 async fn make_shutdown_signal() {
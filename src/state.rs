@@ -0,0 +1,69 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+use crate::cache::ResponseCache;
+use crate::config::Config;
+use crate::disk_cache::DiskCache;
+use crate::handlers::convert::ConvertOutcome;
+use crate::inflight::InFlight;
+use crate::jobs::JobStore;
+use crate::pool::WorkPools;
+
+/// Shared state handed to every handler via axum's `State` extractor.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    /// Identical in-flight conversions, keyed by `processor::content_key`.
+    pub inflight: Arc<InFlight<[u8; 32], ConvertOutcome>>,
+    /// Finished conversions, present when `CACHE_MAX_BYTES` is non-zero.
+    pub cache: Option<Arc<ResponseCache>>,
+    /// Finished conversions persisted under `CACHE_DIR`, consulted after
+    /// `cache` misses.
+    pub disk_cache: Option<Arc<DiskCache>>,
+    /// Background conversions started with `async=true`.
+    pub jobs: Arc<JobStore>,
+    /// Light/heavy pools that blocking conversions are dispatched to.
+    pub pools: Arc<WorkPools>,
+    /// Set once the startup encoder self-test passes; cleared again when
+    /// shutdown begins. Reported by `/ready`.
+    pub ready: Arc<AtomicBool>,
+}
+
+impl AppState {
+    pub fn new(config: Config, ready: Arc<AtomicBool>) -> Self {
+        let cache = (config.cache_max_bytes > 0)
+            .then(|| Arc::new(ResponseCache::new(config.cache_max_bytes, config.cache_ttl)));
+        let disk_cache = config
+            .cache_dir
+            .as_ref()
+            .filter(|_| config.cache_dir_max_bytes > 0)
+            .and_then(|dir| {
+                DiskCache::open(dir, config.cache_dir_max_bytes, config.cache_ttl)
+                    .inspect_err(|e| {
+                        tracing::warn!(dir = %dir.display(), error = %e, "Disk cache unavailable, disabling it")
+                    })
+                    .ok()
+            })
+            .map(Arc::new);
+        let pools = Arc::new(WorkPools::new(
+            config.light_pool_size,
+            config.heavy_pool_size,
+            config.heavy_input_bytes,
+            config.heavy_dimension,
+        ));
+        let jobs = Arc::new(JobStore::new(
+            config.max_jobs,
+            config.max_job_bytes,
+            config.job_ttl,
+        ));
+        Self {
+            config: Arc::new(config),
+            inflight: Arc::new(InFlight::new()),
+            cache,
+            disk_cache,
+            jobs,
+            pools,
+            ready,
+        }
+    }
+}
@@ -1,6 +1,11 @@
 use axum::{body::Body, http::Request};
-use imgopt::server::create_router;
+use base64::Engine;
+use imgopt::config::Config;
+use imgopt::processor::{FormatCaps, Limits, OutputFormat, SizeCap};
+use imgopt::server::{create_router, create_router_with};
 use reqwest::Client;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener as TokioTcpListener;
 use tower::ServiceExt;
@@ -16,13 +21,109 @@ const PNG_1X1: &[u8] = &[
     0x44, 0xAE, 0x42, 0x60, 0x82,
 ];
 
+/// Two-frame 4×4 animated GIF, built with the `image` encoder
+fn animated_gif() -> Vec<u8> {
+    use image::{codecs::gif::GifEncoder, Frame, Rgba, RgbaImage};
+
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder
+            .encode_frames(vec![
+                Frame::new(RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]))),
+                Frame::new(RgbaImage::from_pixel(4, 4, Rgba([0, 255, 0, 255]))),
+            ])
+            .unwrap();
+    }
+    bytes
+}
+
+/// Two-frame 4×4 APNG. The `image` encoder writes stills only, so each
+/// frame's PNG data is restitched into APNG chunks by hand
+fn animated_png() -> Vec<u8> {
+    use image::{Rgba, RgbaImage};
+
+    fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(kind);
+        out.extend_from_slice(data);
+        out.extend_from_slice(&crc.finalize().to_be_bytes());
+    }
+    // IHDR and concatenated IDAT payloads of a solid-color still
+    fn still(color: [u8; 4]) -> (Vec<u8>, Vec<u8>) {
+        let mut png = Vec::new();
+        RgbaImage::from_pixel(4, 4, Rgba(color))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let (mut ihdr, mut idat) = (Vec::new(), Vec::new());
+        let mut pos = 8;
+        while pos + 8 <= png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let data = &png[pos + 8..pos + 8 + len];
+            match &png[pos + 4..pos + 8] {
+                b"IHDR" => ihdr = data.to_vec(),
+                b"IDAT" => idat.extend_from_slice(data),
+                _ => {}
+            }
+            pos += 12 + len;
+        }
+        (ihdr, idat)
+    }
+    // 4×4 at the origin, shown for 100 ms
+    let frame_control = |sequence: u32| {
+        let mut data = Vec::new();
+        for value in [sequence, 4, 4, 0, 0] {
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+        data.extend_from_slice(&[0, 100, 3, 232, 0, 0]);
+        data
+    };
+
+    let (ihdr, first) = still([255, 0, 0, 255]);
+    let (_, second) = still([0, 0, 255, 255]);
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut out, b"IHDR", &ihdr);
+    chunk(&mut out, b"acTL", &[0, 0, 0, 2, 0, 0, 0, 0]);
+    chunk(&mut out, b"fcTL", &frame_control(0));
+    chunk(&mut out, b"IDAT", &first);
+    chunk(&mut out, b"fcTL", &frame_control(1));
+    let mut fdat = 2u32.to_be_bytes().to_vec();
+    fdat.extend_from_slice(&second);
+    chunk(&mut out, b"fdAT", &fdat);
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Readiness flag for a router that has passed its startup checks
+fn ready() -> Arc<AtomicBool> {
+    Arc::new(AtomicBool::new(true))
+}
+
 /// Spawn a test server on a random port and return its base URL.
 /// API_TOKEN must be set in the calling test before this is invoked.
 async fn spawn_server() -> String {
     let listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
     tokio::spawn(async move {
-        axum::serve(listener, create_router()).await.unwrap();
+        axum::serve(listener, create_router(ready())).await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    format!("http://{}", addr)
+}
+
+/// `spawn_server` with settings that differ from the environment's. Build
+/// `config` from `Config::from_env()`; setting variables instead would leak
+/// into servers other tests spawn meanwhile.
+async fn spawn_server_with(config: Config) -> String {
+    let listener = TokioTcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, create_router_with(config, ready()))
+            .await
+            .unwrap();
     });
     tokio::time::sleep(Duration::from_millis(50)).await;
     format!("http://{}", addr)
@@ -32,7 +133,7 @@ async fn spawn_server() -> String {
 
 #[tokio::test]
 async fn test_health() {
-    let app = create_router();
+    let app = create_router(ready());
     let response = app
         .oneshot(
             Request::builder()
@@ -43,11 +144,48 @@ async fn test_health() {
         .await
         .unwrap();
     assert_eq!(response.status(), 200);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(json["status"], "ok");
+    assert_eq!(json["in_flight"], 0);
+    assert!(json["total_processed"].is_u64());
 }
 
 #[tokio::test]
 async fn test_ready() {
-    let app = create_router();
+    let app = create_router(ready());
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/ready")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(json["ready"], true);
+    let names: Vec<_> = json["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|check| {
+            assert_eq!(check["ok"], true);
+            check["name"].as_str().unwrap().to_string()
+        })
+        .collect();
+    assert_eq!(names, ["encoders", "worker_pool"]);
+}
+
+#[tokio::test]
+async fn test_not_ready_until_flag_set() {
+    let app = create_router(Arc::new(AtomicBool::new(false)));
     let response = app
         .oneshot(
             Request::builder()
@@ -57,7 +195,51 @@ async fn test_ready() {
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), 503);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(json["ready"], false);
+    assert_eq!(json["checks"][0]["name"], "encoders");
+    assert_eq!(json["checks"][0]["ok"], false);
+    assert!(json["checks"][0]["detail"].is_string());
+    assert_eq!(json["checks"][1]["ok"], true);
+}
+
+// ── openapi ───────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_openapi_document_is_public() {
+    let app = create_router(ready());
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
     assert_eq!(response.status(), 200);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert!(json["openapi"].as_str().unwrap().starts_with('3'));
+
+    let convert = &json["paths"]["/convert"]["post"];
+    assert_eq!(convert["security"][0]["bearer"], serde_json::json!([]));
+    assert!(convert["requestBody"]["content"]["multipart/form-data"].is_object());
+    let form = &json["components"]["schemas"]["ConvertForm"];
+    assert_eq!(form["required"], serde_json::json!(["file"]));
+    for field in ["format", "quality", "width", "loop_count", "dry_run"] {
+        assert!(form["properties"][field].is_object(), "{}", field);
+    }
+    assert_eq!(
+        json["components"]["securitySchemes"]["bearer"]["scheme"],
+        "bearer"
+    );
 }
 
 // ── happy-path conversions ────────────────────────────────────────────────────
@@ -87,6 +269,70 @@ async fn test_convert_webp() {
     assert_eq!(&bytes[0..4], b"RIFF");
 }
 
+#[tokio::test]
+async fn test_default_format_applies_without_format_field() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server_with(Config {
+        default_format: OutputFormat::Png,
+        ..Config::from_env()
+    })
+    .await;
+
+    for (format, content_type) in [(None, "image/png"), (Some("webp"), "image/webp")] {
+        let mut form = reqwest::multipart::Form::new().part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        );
+        if let Some(format) = format {
+            form = form.text("format", format);
+        }
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-type"], content_type);
+    }
+}
+
+#[tokio::test]
+async fn test_disabled_format_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server_with(Config {
+        allowed_formats: vec![OutputFormat::WebP, OutputFormat::Png],
+        ..Config::from_env()
+    })
+    .await;
+
+    // auto may pick AVIF, so it needs AVIF enabled too
+    for format in ["avif", "auto"] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", format);
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), 400, "format={}", format);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"]["code"], "format_not_enabled");
+        assert_eq!(body["error"]["message"], "format not enabled: avif");
+    }
+}
+
 #[tokio::test]
 async fn test_convert_avif() {
     unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
@@ -114,6 +360,36 @@ async fn test_convert_avif() {
     assert_eq!(&bytes[4..8], b"ftyp");
 }
 
+#[tokio::test]
+async fn test_convert_format_from_path_extension() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let send = |path: &'static str| {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", "webp");
+        Client::new()
+            .post(format!("{}{}", base, path))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+    };
+
+    // The extension wins over the `format` field
+    let resp = send("/convert/photo.avif").await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/avif");
+
+    let resp = send("/convert/photo.gif").await.unwrap();
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_parameter");
+}
+
 #[tokio::test]
 async fn test_response_contains_request_id() {
     unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
@@ -136,6 +412,68 @@ async fn test_response_contains_request_id() {
     assert!(resp.headers().contains_key("x-request-id"));
 }
 
+#[tokio::test]
+async fn test_supplied_request_id_is_echoed() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .header("X-Request-Id", "edge-7f3a:42")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), "edge-7f3a:42");
+
+    let form = reqwest::multipart::Form::new().text("format", "webp");
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .header("X-Request-Id", "edge-7f3a:43")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 400);
+    assert_eq!(resp.headers().get("x-request-id").unwrap(), "edge-7f3a:43");
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["request_id"], "edge-7f3a:43");
+}
+
+#[tokio::test]
+async fn test_malformed_request_id_is_replaced() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .header("X-Request-Id", "not a valid id")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let id = resp
+        .headers()
+        .get("x-request-id")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(uuid::Uuid::parse_str(id).is_ok());
+}
+
 // ── authentication ────────────────────────────────────────────────────────────
 
 #[tokio::test]
@@ -179,6 +517,54 @@ async fn test_unauthorized_wrong_token() {
     assert_eq!(resp.status(), 401);
 }
 
+#[tokio::test]
+async fn test_multiple_authorization_headers_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+
+    // Even with the valid token first, the duplicate makes the request ambiguous
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .header("Authorization", "Bearer wrong_token")
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+}
+
+#[tokio::test]
+async fn test_cors_preflight_skips_auth() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    unsafe { std::env::set_var("CORS_ALLOWED_ORIGINS", "https://cms.example") };
+    let base = spawn_server().await;
+
+    // Browsers send preflights without credentials
+    let resp = Client::new()
+        .request(reqwest::Method::OPTIONS, format!("{}/convert", base))
+        .header("Origin", "https://cms.example")
+        .header("Access-Control-Request-Method", "POST")
+        .header("Access-Control-Request-Headers", "authorization")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers()
+            .get("access-control-allow-origin")
+            .and_then(|v| v.to_str().ok()),
+        Some("https://cms.example")
+    );
+}
+
 // ── input validation ──────────────────────────────────────────────────────────
 
 #[tokio::test]
@@ -197,6 +583,166 @@ async fn test_missing_file_field() {
         .unwrap();
 
     assert_eq!(resp.status(), 400);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "missing_file");
+    assert!(body["error"]["request_id"].is_string());
+}
+
+#[tokio::test]
+async fn test_scale_excludes_width_and_height() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (extra, status) in [(None, 200), (Some(("width", "4")), 400)] {
+        let mut form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("scale", "3")
+            .text("dry_run", "true");
+        if let Some((name, value)) = extra {
+            form = form.text(name, value);
+        }
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        if status == 200 {
+            assert_eq!(
+                (body["width"].as_u64(), body["height"].as_u64()),
+                (Some(3), Some(3))
+            );
+        } else {
+            assert_eq!(body["error"]["code"], "invalid_parameter");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_empty_file_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(Vec::new()).file_name("empty.png"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "empty_file");
+    assert_eq!(body["error"]["message"], "uploaded file is empty");
+}
+
+#[tokio::test]
+async fn test_truncated_png_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let mut png = Vec::new();
+    image::RgbaImage::from_fn(64, 64, |x, y| {
+        image::Rgba([(x * 4) as u8, (y * 4) as u8, 0, 255])
+    })
+    .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+    .unwrap();
+    png.truncate(png.len() / 2);
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(png).file_name("half.png"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "truncated_image");
+    assert_eq!(
+        body["error"]["message"],
+        "image appears to be truncated or corrupt"
+    );
+}
+
+#[tokio::test]
+async fn test_too_many_form_fields_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let mut form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+    for i in 0..200 {
+        form = form.text(format!("x{}", i), "1");
+    }
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_multipart");
+    assert_eq!(body["error"]["message"], "more than 20 form fields");
+}
+
+#[tokio::test]
+async fn test_options_entries_count_as_form_fields() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let options: serde_json::Map<String, serde_json::Value> = (0..200)
+        .map(|i| (format!("x{}", i), serde_json::json!(1)))
+        .collect();
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("options", serde_json::Value::Object(options).to_string());
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_multipart");
+    assert_eq!(body["error"]["message"], "more than 20 form fields");
 }
 
 #[tokio::test]
@@ -220,6 +766,8 @@ async fn test_quality_out_of_range() {
         .unwrap();
 
     assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "quality_out_of_range");
 }
 
 #[tokio::test]
@@ -267,3 +815,1594 @@ async fn test_height_zero_rejected() {
 
     assert_eq!(resp.status(), 400);
 }
+
+#[tokio::test]
+async fn test_non_image_rejected_by_magic_bytes() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(b"%PDF-1.7\n1 0 obj\n".to_vec()).file_name("doc.pdf"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "unsupported_format");
+    assert_eq!(
+        body["error"]["message"],
+        "unsupported or unrecognized image format"
+    );
+}
+
+#[tokio::test]
+async fn test_oversize_body_gets_json_413_with_limit() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let app = create_router(ready());
+
+    // One byte over the default 10 MB cap
+    let body = vec![0u8; 10 * 1024 * 1024 + 1];
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/convert")
+                .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+                .header("Content-Type", "image/png")
+                .header("Content-Length", body.len())
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 413);
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(json["error"]["code"], "payload_too_large");
+    assert!(json["error"]["message"].as_str().unwrap().contains("10 MB"));
+}
+
+#[tokio::test]
+async fn test_focus_point_requires_both_coordinates() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("fit", "cover")
+        .text("focus_x", "0.3");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_parameter");
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("focus_x and focus_y"));
+}
+
+#[tokio::test]
+async fn test_invalid_tiling_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("format", "avif")
+        .text("tiling", "2x2");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_parameter");
+    assert!(body["error"]["message"]
+        .as_str()
+        .unwrap()
+        .contains("tiling must be auto or off"));
+}
+
+#[tokio::test]
+async fn test_oversized_output_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let config = Config::from_env();
+    let base = spawn_server_with(Config {
+        limits: Limits {
+            max_output_bytes: 64,
+            ..config.limits
+        },
+        ..config
+    })
+    .await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("width", "200")
+        .text("format", "png");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "output_too_large");
+    assert_eq!(
+        body["error"]["message"],
+        "encoded output exceeds maximum size of 64 bytes"
+    );
+}
+
+// ── multi-frame inputs ────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_animated_input_rejected_without_frame() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(animated_gif()).file_name("anim.gif"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "animated_input");
+    assert_eq!(body["error"]["message"], "multi-frame input not supported");
+}
+
+#[tokio::test]
+async fn test_animated_input_with_frame() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(animated_gif()).file_name("anim.gif"),
+        )
+        .text("frame", "1");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/webp");
+}
+
+#[tokio::test]
+async fn test_animated_frame_mode_takes_first_frame() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let send = |fields: &'static [(&'static str, &'static str)]| {
+        let base = base.clone();
+        async move {
+            let mut form = reqwest::multipart::Form::new().part(
+                "file",
+                reqwest::multipart::Part::bytes(animated_gif()).file_name("anim.gif"),
+            );
+            for &(name, value) in fields {
+                form = form.text(name, value);
+            }
+            Client::new()
+                .post(format!("{}/convert", base))
+                .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+                .multipart(form)
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    let resp = send(&[("animated", "frame"), ("format", "png")]).await;
+    assert_eq!(resp.status(), 200);
+    let still = image::load_from_memory(&resp.bytes().await.unwrap()).unwrap();
+    assert_eq!((still.width(), still.height()), (4, 4));
+
+    assert_eq!(send(&[("animated", "reject")]).await.status(), 422);
+    assert_eq!(
+        send(&[("animated", "reject"), ("frame", "1")])
+            .await
+            .status(),
+        400
+    );
+    assert_eq!(send(&[("animated", "loop")]).await.status(), 400);
+}
+
+#[tokio::test]
+async fn test_animated_reject_applies_to_apng() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let send = |animated: Option<&'static str>| {
+        let base = base.clone();
+        async move {
+            let mut form = reqwest::multipart::Form::new().part(
+                "file",
+                reqwest::multipart::Part::bytes(animated_png()).file_name("anim.png"),
+            );
+            if let Some(value) = animated {
+                form = form.text("animated", value);
+            }
+            Client::new()
+                .post(format!("{}/convert", base))
+                .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+                .multipart(form)
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    // By default an APNG is kept animated for WebP output
+    let resp = send(None).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/webp");
+
+    // An explicit reject overrides that
+    let resp = send(Some("reject")).await;
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "animated_input");
+}
+
+#[tokio::test]
+async fn test_animated_input_frame_out_of_range() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(animated_gif()).file_name("anim.gif"),
+        )
+        .text("frame", "2");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+}
+
+// ── warnings ──────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_upscale_reports_warning_header() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("width", "10");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-warnings").unwrap(), "upscaled");
+}
+
+#[tokio::test]
+async fn test_no_warning_header_by_default() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert!(!resp.headers().contains_key("x-warnings"));
+}
+
+// ── output headers ────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_output_dimension_and_size_headers() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("width", "10");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("x-image-width").unwrap(), "10");
+    assert_eq!(resp.headers().get("x-image-height").unwrap(), "10");
+    let reported: usize = resp.headers()["x-image-bytes"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(reported, resp.bytes().await.unwrap().len());
+}
+
+#[tokio::test]
+async fn test_encode_duration_header() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    let encode_ms = resp.headers()["x-encode-ms"].to_str().unwrap();
+    assert!(encode_ms.parse::<u64>().is_ok(), "got {:?}", encode_ms);
+}
+
+#[tokio::test]
+async fn test_deadline_reports_chosen_avif_speed() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("format", "avif")
+        .text("deadline_ms", "60000");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    // A single pixel fits any deadline at the slowest speed
+    assert_eq!(resp.headers().get("x-avif-speed").unwrap(), "1");
+}
+
+#[tokio::test]
+async fn test_min_ssim_reports_searched_quality() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (format, status) in [("jpeg", 200), ("avif", 400)] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", format)
+            .text("min_ssim", "0.9");
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status, "{}", format);
+        if status == 200 {
+            let quality: u8 = resp.headers()["x-quality"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((1..=100).contains(&quality));
+            let ssim: f64 = resp.headers()["x-quality-score"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(ssim >= 0.9, "ssim {}", ssim);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_target_ratio_reports_achieved_ratio() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let cases = [
+        (vec![("format", "jpeg")], 200),
+        (vec![("format", "png")], 400),
+        (vec![("format", "webp"), ("lossless", "true")], 400),
+        (vec![("format", "jpeg"), ("min_ssim", "0.9")], 400),
+    ];
+    for (fields, status) in cases {
+        let mut form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("target_ratio", "0.5");
+        for &(name, value) in &fields {
+            form = form.text(name, value);
+        }
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status, "{:?}", fields);
+        if status == 200 {
+            // A 1x1 JPEG's headers alone outweigh the whole PNG
+            assert_eq!(resp.headers()["x-quality"], "1");
+            assert_eq!(resp.headers()["x-warnings"], "target_missed");
+            let ratio: f64 = resp.headers()["x-compression-ratio"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            let body = resp.bytes().await.unwrap();
+            let expected = body.len() as f64 / PNG_1X1.len() as f64;
+            assert!((ratio - expected).abs() < 0.001, "ratio {}", ratio);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_timeout_ms_is_clamped_not_rejected() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    // Both exceed MAX_ENCODING_TIMEOUT_SECS, which defaults to the encoding timeout
+    for (timeout_ms, status) in [("60000", 200), ("999999999", 200), ("0", 400)] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("timeout_ms", timeout_ms);
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status, "timeout_ms={}", timeout_ms);
+    }
+}
+
+#[tokio::test]
+async fn test_timeout_ms_extends_the_encoding_timeout() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server_with(Config {
+        encoding_timeout: Duration::from_millis(1),
+        max_encoding_timeout: Duration::from_secs(60),
+        ..Config::from_env()
+    })
+    .await;
+
+    let convert = |timeout_ms: Option<&'static str>| {
+        let base = base.clone();
+        async move {
+            let mut form = reqwest::multipart::Form::new()
+                .part(
+                    "file",
+                    reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+                )
+                .text("format", "avif")
+                .text("width", "600")
+                .text("height", "600");
+            if let Some(ms) = timeout_ms {
+                form = form.text("timeout_ms", ms);
+            }
+            Client::new()
+                .post(format!("{}/convert", base))
+                .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+                .multipart(form)
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    // The 1 ms default can't fit an AVIF encode; the requested 60 s can
+    let resp = convert(None).await;
+    assert_eq!(resp.status(), 408);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "timeout");
+
+    let resp = convert(Some("60000")).await;
+    assert_eq!(resp.status(), 200);
+}
+
+#[tokio::test]
+async fn test_slow_decode_times_out() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server_with(Config {
+        decode_timeout: Duration::from_millis(1),
+        ..Config::from_env()
+    })
+    .await;
+
+    // Compresses to a few kilobytes but inflates to 27 MB of pixels
+    let mut png = Vec::new();
+    image::RgbImage::from_pixel(3000, 3000, image::Rgb([90, 120, 200]))
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(png).file_name("large.png"),
+        )
+        .text("format", "png");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 408);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "timeout");
+    assert_eq!(body["error"]["message"], "Decoding timed out");
+}
+
+#[tokio::test]
+async fn test_copyright_written_to_output() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for format in ["jpeg", "webp", "png"] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", format)
+            .text("copyright", "(c) 2026 Rush CMS");
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), 200, "{}", format);
+        let body = resp.bytes().await.unwrap();
+        let needle = b"(c) 2026 Rush CMS\0";
+        assert!(
+            body.windows(needle.len()).any(|w| w == needle),
+            "{} output lacks the copyright",
+            format
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_preset_sets_defaults_that_fields_override() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (speed, expected) in [(None, "8"), (Some("9"), "9")] {
+        let mut form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", "avif")
+            .text("preset", "thumbnail");
+        if let Some(speed) = speed {
+            form = form.text("speed", speed);
+        }
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get("x-avif-speed").unwrap(), expected);
+    }
+}
+
+// ── dry run ───────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_dry_run_returns_metadata_only() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("format", "png")
+        .text("width", "4")
+        .text("dry_run", "true");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "application/json"
+    );
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["format"], "png");
+    assert_eq!(body["width"], 4);
+    assert_eq!(body["height"], 4);
+    assert!(body["output_bytes"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_boolean_fields_accept_common_spellings_only() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (value, status) in [("YES", 200), ("1", 200), ("flase", 400)] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("dry_run", value);
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status, "dry_run={}", value);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        if status == 200 {
+            assert!(body["output_bytes"].as_u64().unwrap() > 0);
+        } else {
+            assert_eq!(body["error"]["code"], "invalid_parameter");
+        }
+    }
+}
+
+// ── several formats ───────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_formats_returns_each_variant_with_summary() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("formats", "webp,png");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().contains_key("x-request-id"));
+    let body: serde_json::Value = resp.json().await.unwrap();
+    let variants = body["variants"].as_array().unwrap();
+    assert_eq!(variants.len(), 2);
+    assert_eq!(variants[0]["format"], "webp");
+    assert_eq!(variants[1]["format"], "png");
+    for variant in variants {
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(variant["base64"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(variant["bytes"], bytes.len());
+        assert_eq!(image::load_from_memory(&bytes).unwrap().width(), 1);
+    }
+    assert_eq!(body["formats"].as_array().unwrap().len(), 2);
+    assert!(body["smallest"].is_string());
+}
+
+#[tokio::test]
+async fn test_formats_conflicts_with_format() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("format", "avif")
+        .text("formats", "webp,avif");
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_parameter");
+}
+
+#[tokio::test]
+async fn test_formats_with_jpeg_and_mask_need_background() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let send = |background: Option<&'static str>| {
+        let base = base.clone();
+        async move {
+            let mut form = reqwest::multipart::Form::new()
+                .part(
+                    "file",
+                    reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+                )
+                .text("formats", "webp,jpeg")
+                .text("mask", "circle");
+            if let Some(color) = background {
+                form = form.text("background", color);
+            }
+            Client::new()
+                .post(format!("{}/convert", base))
+                .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+                .multipart(form)
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    let resp = send(None).await;
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_parameter");
+
+    assert_eq!(send(Some("#ffffff")).await.status(), 200);
+}
+
+// ── srcset ────────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_srcset_returns_each_width() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("widths", "4, 2,4");
+
+    let resp = Client::new()
+        .post(format!("{}/srcset", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    let entries = body.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["width"], 2);
+    assert_eq!(entries[1]["width"], 4);
+    // base64 of "RIFF"
+    assert!(entries[0]["base64"].as_str().unwrap().starts_with("UklGR"));
+}
+
+#[tokio::test]
+async fn test_srcset_rejects_too_many_widths() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("widths", "1,2,3,4,5,6,7,8,9");
+
+    let resp = Client::new()
+        .post(format!("{}/srcset", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "invalid_parameter");
+}
+
+// ── transcode ─────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_transcode_keeps_dimensions_and_rejects_resize() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (width, status) in [(None, 200), (Some("10"), 400)] {
+        let mut form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", "webp")
+            .text("transcode", "true");
+        if let Some(width) = width {
+            form = form.text("width", width);
+        }
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status);
+        if status == 200 {
+            assert_eq!(resp.headers().get("content-type").unwrap(), "image/webp");
+            assert_eq!(resp.headers().get("x-image-width").unwrap(), "1");
+            assert_eq!(resp.headers().get("x-image-height").unwrap(), "1");
+        } else {
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert!(body["error"]["message"].as_str().unwrap().contains("width"));
+        }
+    }
+}
+
+// ── quality scan ──────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_quality_scan_returns_size_per_quality() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("format", "jpeg")
+        .text("qualities", "90, 30,90");
+
+    let resp = Client::new()
+        .post(format!("{}/quality-scan", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    let entries = body.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["quality"], 30);
+    assert_eq!(entries[1]["quality"], 90);
+    assert!(entries.iter().all(|e| e["bytes"].as_u64().unwrap() > 0));
+}
+
+// ── shrink ────────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_shrink_keeps_format_and_dimensions() {
+    use image::{codecs::jpeg::JpegEncoder, GenericImageView, Rgb, RgbImage};
+
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    // Smooth gradient at quality 100 leaves plenty to shave off
+    let img = RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 100)
+        .encode_image(&img)
+        .unwrap();
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(jpeg.clone()).file_name("photo.jpg"),
+    );
+    let resp = Client::new()
+        .post(format!("{}/shrink", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/jpeg");
+    assert_eq!(
+        resp.headers()["x-original-bytes"].to_str().unwrap(),
+        jpeg.len().to_string()
+    );
+    assert!(resp.headers().get("x-quality").is_some());
+    let image_bytes: usize = resp.headers()["x-image-bytes"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body = resp.bytes().await.unwrap();
+    assert_eq!(body.len(), image_bytes);
+    assert!(body.len() < jpeg.len());
+    assert_eq!(
+        image::load_from_memory(&body).unwrap().dimensions(),
+        (64, 64)
+    );
+}
+
+/// Posts `file` to `/shrink` with an optional `min_ssim`.
+async fn shrink(base: &str, file: Vec<u8>, min_ssim: Option<&str>) -> reqwest::Response {
+    let mut form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(file).file_name("upload"),
+    );
+    if let Some(min_ssim) = min_ssim {
+        form = form.text("min_ssim", min_ssim.to_string());
+    }
+    Client::new()
+        .post(format!("{}/shrink", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_shrink_recompresses_png_losslessly() {
+    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+    use image::{ImageEncoder, Rgb, RgbImage};
+
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let img = RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+    let mut png = Vec::new();
+    PngEncoder::new_with_quality(&mut png, CompressionType::Fast, FilterType::NoFilter)
+        .write_image(img.as_raw(), 64, 64, image::ExtendedColorType::Rgb8)
+        .unwrap();
+
+    let resp = shrink(&base, png.clone(), None).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/png");
+    assert!(resp.headers().get("x-quality").is_none());
+    assert!(resp.headers().get("x-warnings").is_none());
+    let body = resp.bytes().await.unwrap();
+    assert!(body.len() < png.len());
+    assert_eq!(image::load_from_memory(&body).unwrap().to_rgb8(), img);
+}
+
+#[tokio::test]
+async fn test_shrink_keeps_lossless_webp_lossless() {
+    use image::codecs::webp::WebPEncoder;
+    use image::{Rgba, RgbaImage};
+
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let img = RgbaImage::from_fn(48, 48, |x, y| {
+        Rgba([(x * 5) as u8, (y * 5) as u8, 40, (128 + x) as u8])
+    });
+    let mut webp = Vec::new();
+    WebPEncoder::new_lossless(&mut webp)
+        .encode(img.as_raw(), 48, 48, image::ExtendedColorType::Rgba8)
+        .unwrap();
+
+    let resp = shrink(&base, webp, None).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/webp");
+    assert!(resp.headers().get("x-quality").is_none());
+    // Smaller or returned as is, the pixels are untouched either way
+    let body = resp.bytes().await.unwrap();
+    assert_eq!(image::load_from_memory(&body).unwrap().to_rgba8(), img);
+}
+
+#[tokio::test]
+async fn test_shrink_returns_upload_when_nothing_is_saved() {
+    use image::{codecs::jpeg::JpegEncoder, Rgb, RgbImage};
+
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    // Already heavily compressed; matching it exactly needs quality 100
+    let img = RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 10)
+        .encode_image(&img)
+        .unwrap();
+
+    let resp = shrink(&base, jpeg.clone(), Some("1")).await;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/jpeg");
+    assert_eq!(resp.headers()["x-warnings"], "no_savings");
+    assert!(resp.headers().get("x-quality").is_none());
+    assert_eq!(resp.bytes().await.unwrap(), jpeg);
+}
+
+#[tokio::test]
+async fn test_shrink_rejects_formats_it_cannot_write_back() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(animated_gif()).file_name("anim.gif"),
+    );
+    let resp = Client::new()
+        .post(format!("{}/shrink", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "unsupported_format");
+}
+
+// ── content type ──────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_strict_content_type_rejects_mislabeled_file() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (strict, status) in [(false, 200), (true, 415)] {
+        let part = reqwest::multipart::Part::bytes(PNG_1X1.to_vec())
+            .file_name("test.jpg")
+            .mime_str("image/jpeg")
+            .unwrap();
+        let form = reqwest::multipart::Form::new()
+            .part("file", part)
+            .text("strict_content_type", strict.to_string());
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status);
+        if strict {
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "content_type_mismatch");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_input_allowlist_rejects_other_formats() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server_with(Config {
+        allowed_inputs: Some(vec!["image/png", "image/jpeg"]),
+        ..Config::from_env()
+    })
+    .await;
+
+    let mut webp = Vec::new();
+    image::load_from_memory(PNG_1X1)
+        .unwrap()
+        .write_to(
+            &mut std::io::Cursor::new(&mut webp),
+            image::ImageFormat::WebP,
+        )
+        .unwrap();
+
+    for (input, name, status) in [
+        (PNG_1X1.to_vec(), "test.png", 200),
+        (webp, "test.webp", 415),
+    ] {
+        let form = reqwest::multipart::Form::new().part(
+            "file",
+            reqwest::multipart::Part::bytes(input).file_name(name),
+        );
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status, "{}", name);
+        if status == 415 {
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "input_format_not_accepted");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_avif_cap_rejects_only_avif() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let config = Config::from_env();
+    let base = spawn_server_with(Config {
+        limits: Limits {
+            format_caps: FormatCaps {
+                avif: SizeCap {
+                    max_dimension: Some(32),
+                    max_pixels: None,
+                },
+                ..Default::default()
+            },
+            ..config.limits
+        },
+        ..config
+    })
+    .await;
+
+    // 64 is well within MAX_DIMENSION but over the AVIF cap
+    for (format, status) in [("webp", 200), ("avif", 422)] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", format)
+            .text("width", "64");
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status, "{}", format);
+        if status == 422 {
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "format_limit_exceeded");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_unknown_field_rejected_only_in_strict_mode() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let lenient = spawn_server().await;
+    let strict = spawn_server_with(Config {
+        strict_fields: true,
+        ..Config::from_env()
+    })
+    .await;
+
+    for (base, status) in [(lenient, 200), (strict, 400)] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("quailty", "80");
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), status);
+        if status == 400 {
+            let body: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "invalid_parameter");
+            assert_eq!(body["error"]["message"], "unknown field: quailty");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_file_under_alternate_field_name() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    // `image` is one of the default FILE_FIELD_NAMES
+    let form = reqwest::multipart::Form::new().part(
+        "image",
+        reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+    );
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/webp");
+}
+
+// ── options JSON ──────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_options_json_applies_and_fields_override() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    // `width` comes before `options` and still wins over the JSON value
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("width", "5")
+        .text("options", r#"{"format": "png", "width": 3, "height": 4}"#);
+
+    let resp = Client::new()
+        .post(format!("{}/convert", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    assert_eq!(resp.headers().get("x-image-width").unwrap(), "5");
+    assert_eq!(resp.headers().get("x-image-height").unwrap(), "4");
+}
+
+#[tokio::test]
+async fn test_options_json_errors_name_the_field() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    for (options, code) in [
+        (r#"{"quality": 0}"#, "quality_out_of_range"),
+        (r#"{"width": [1, 2]}"#, "invalid_parameter"),
+        ("not json", "invalid_parameter"),
+    ] {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("options", options);
+
+        let resp = Client::new()
+            .post(format!("{}/convert", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(resp.status(), 400, "{}", options);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(body["error"]["code"], code, "{}", options);
+    }
+}
+
+// ── raw body ──────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_raw_image_body_with_query_options() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let resp = Client::new()
+        .post(format!("{}/convert?format=png&width=3", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .header("Content-Type", "image/png")
+        .body(PNG_1X1.to_vec())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers().get("content-type").unwrap(), "image/png");
+    let bytes = resp.bytes().await.unwrap();
+    let decoded = image::load_from_memory(&bytes).unwrap();
+    assert_eq!(decoded.width(), 3);
+}
+
+#[tokio::test]
+async fn test_raw_image_body_rejects_invalid_query_option() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+
+    let resp = Client::new()
+        .post(format!("{}/convert?quality=0", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .header("Content-Type", "image/png")
+        .body(PNG_1X1.to_vec())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 400);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "quality_out_of_range");
+}
+
+// ── caching ───────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_cached_result_does_not_skip_require_photo() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server_with(Config {
+        cache_max_bytes: 1024 * 1024,
+        ..Config::from_env()
+    })
+    .await;
+
+    let convert = |require_photo: Option<&'static str>| {
+        let base = base.clone();
+        async move {
+            let mut form = reqwest::multipart::Form::new().part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            );
+            if let Some(value) = require_photo {
+                form = form.text("require_photo", value);
+            }
+            Client::new()
+                .post(format!("{}/convert", base))
+                .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+                .multipart(form)
+                .send()
+                .await
+                .unwrap()
+        }
+    };
+
+    // The flat pixel converts and is cached, but still isn't a photo
+    assert_eq!(convert(None).await.status(), 200);
+    let resp = convert(Some("true")).await;
+    assert_eq!(resp.status(), 422);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "not_photo");
+}
+
+// ── async jobs ────────────────────────────────────────────────────────────────
+
+#[tokio::test]
+async fn test_async_job_enqueue_poll_fetch() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+    let client = Client::new();
+
+    let form = reqwest::multipart::Form::new()
+        .part(
+            "file",
+            reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+        )
+        .text("format", "png")
+        .text("width", "4");
+
+    let resp = client
+        .post(format!("{}/convert?async=true", base))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .multipart(form)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(resp.status(), 202);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["status"], "pending");
+    let job_id = body["job_id"].as_str().unwrap().to_string();
+
+    let mut finished = None;
+    for _ in 0..100 {
+        let resp = client
+            .get(format!("{}/jobs/{}", base, job_id))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .send()
+            .await
+            .unwrap();
+        if resp.status() != 202 {
+            finished = Some(resp);
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    let resp = finished.expect("job should finish");
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "image/png");
+    let bytes = resp.bytes().await.unwrap();
+    let decoded = image::load_from_memory(&bytes).unwrap();
+    assert_eq!(decoded.width(), 4);
+
+    let resp = client
+        .get(format!("{}/jobs/{}", base, uuid::Uuid::new_v4()))
+        .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    let body: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(body["error"]["code"], "job_not_found");
+}
+
+#[tokio::test]
+async fn test_identical_async_submissions_share_a_job() {
+    unsafe { std::env::set_var("API_TOKEN", TEST_TOKEN) };
+    let base = spawn_server().await;
+    let client = Client::new();
+
+    // A slow AVIF encode keeps the first job pending while the second is submitted
+    let submit = || async {
+        let form = reqwest::multipart::Form::new()
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(PNG_1X1.to_vec()).file_name("test.png"),
+            )
+            .text("format", "avif")
+            .text("speed", "1")
+            .text("width", "1500")
+            .text("height", "1500");
+        let resp = client
+            .post(format!("{}/convert?async=true", base))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 202);
+        let body: serde_json::Value = resp.json().await.unwrap();
+        body["job_id"].as_str().unwrap().to_string()
+    };
+    let first = submit().await;
+    let second = submit().await;
+    assert_eq!(first, second);
+
+    let mut finished = false;
+    for _ in 0..600 {
+        let resp = client
+            .get(format!("{}/jobs/{}", base, first))
+            .header("Authorization", format!("Bearer {}", TEST_TOKEN))
+            .send()
+            .await
+            .unwrap();
+        if resp.status() != 202 {
+            assert_eq!(resp.status(), 200);
+            finished = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(finished, "job should finish");
+
+    // Encoded once
+    let health: serde_json::Value = client
+        .get(format!("{}/health", base))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(health["total_processed"], 1);
+}